@@ -0,0 +1,30 @@
+use rustygear::client::WorkerJob;
+use rustygear::worker::{run_worker, GearmanWorker, WorkError, WorkFuture};
+
+struct Reverser;
+
+impl GearmanWorker for Reverser {
+    fn functions(&self) -> Vec<Vec<u8>> {
+        vec![b"reverse".to_vec(), b"alwaysfail".to_vec()]
+    }
+
+    fn handle<'a>(&'a self, job: &'a mut WorkerJob) -> WorkFuture<'a> {
+        Box::pin(async move {
+            match job.function() {
+                b"reverse" => {
+                    job.work_status(0, 1).await?;
+                    let mut reversed = job.payload().to_vec();
+                    reversed.reverse();
+                    Ok(reversed)
+                }
+                _ => Err(WorkError::Exception(b"Always fails".to_vec())),
+            }
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
+    run_worker("127.0.0.1:4730", Reverser).await
+}
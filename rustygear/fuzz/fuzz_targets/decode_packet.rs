@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packets) = rustygear::codec::decode_packet(data) {
+        rustygear::client::handle_packets(packets);
+    }
+});
@@ -13,32 +13,48 @@
  * See the License for the specific language governing permissions and
  * limitations under the License.
 */
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
 use std::io;
-use std::net::ToSocketAddrs;
+use std::future::Future;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::str::{self, FromStr};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::sink::SinkExt;
-use futures::stream::StreamExt;
+use futures::stream::{self, Stream, StreamExt};
+use hash_ring::HashRing;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::runtime;
 use tokio::sync::mpsc::error::TryRecvError;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::time::{sleep, timeout};
 use tokio_util::codec::Decoder;
 
 use uuid::Uuid;
 
 use crate::codec::{Packet, PacketCodec};
 use crate::constants::*;
-use crate::util::{bytes2bool, new_req, new_res, next_field, no_response};
+use crate::job::JobHandle;
+use crate::util::{bytes2bool, fits_in_packet, new_req, new_res, next_field, no_response};
+use crate::worker::WorkError;
 
 type Hostname = String;
 
+const HASH_RING_REPLICAS: isize = 10;
+const KEEPALIVE_TOKEN: &[u8] = b"rustygear-keepalive";
+const SERVERS_ENV: &str = "GEARMAN_SERVERS";
+
 #[derive(Debug)]
 /// Used for passing job completion stats to clients
 pub struct JobStatus {
-    handle: Bytes,
+    handle: JobHandle,
     known: bool,
     running: bool,
     numerator: u32,
@@ -56,49 +72,168 @@ pub struct JobStatus {
 /// See examples/client.rs and examples/worker.rs for information on how to use it.
 pub struct Client {
     servers: Vec<Hostname>,
+    /// Routes jobs to servers by their unique id
+    ring: Arc<Mutex<ServerRing>>,
     conns: Arc<Mutex<Vec<Arc<Mutex<ClientHandler>>>>>,
     connected: Vec<bool>,
     client_id: Option<Bytes>,
-    senders_by_handle: Arc<Mutex<HashMap<Bytes, Sender<WorkUpdate>>>>,
+    exceptions: bool,
+    senders_by_handle: SendersByHandle,
     jobs_tx_by_func: Arc<Mutex<HashMap<Vec<u8>, Sender<WorkerJob>>>>,
     echo_tx: Sender<Bytes>,
     echo_rx: Receiver<Bytes>,
-    job_created_tx: Sender<Bytes>,
-    job_created_rx: Receiver<Bytes>,
-    status_res_tx: Sender<JobStatus>,
-    status_res_rx: Receiver<JobStatus>,
     error_tx: Sender<(Bytes, Bytes)>,
     error_rx: Receiver<(Bytes, Bytes)>,
     worker_job_tx: Sender<WorkerJob>,
     worker_job_rx: Receiver<WorkerJob>,
+    keepalive: Option<Keepalive>,
+    reconnect: Option<Reconnect>,
+    connect_retry: Option<ConnectRetry>,
+    outstanding: Arc<Mutex<HashMap<JobHandle, Outstanding>>>,
+}
+
+/// How [Client.connect] waits for servers that aren't up yet, see [Client.set_connect_retry]
+#[derive(Clone, Copy, Debug)]
+struct ConnectRetry {
+    initial: Duration,
+    deadline: Duration,
+}
+
+/// How often to check idle connections with ECHO_REQ, see [Client.set_keepalive]
+#[derive(Clone, Copy, Debug)]
+struct Keepalive {
+    interval: Duration,
+    timeout: Duration,
+}
+
+/// How a dropped connection is re-established, see [Client.set_reconnect]
+#[derive(Clone, Copy, Debug)]
+struct Reconnect {
+    interval: Duration,
+    resubmit_background: bool,
+}
+
+/// Servers jobs are routed to by unique, keyed by address rather than by
+/// position so clients listing the same servers in any order agree
+struct ServerRing {
+    ring: HashRing<Hostname>,
+    members: HashSet<Hostname>,
+}
+
+impl ServerRing {
+    fn new() -> ServerRing {
+        ServerRing {
+            ring: HashRing::new(Vec::new(), HASH_RING_REPLICAS),
+            members: HashSet::new(),
+        }
+    }
+
+    /// Routes to `server` again, nothing happens if it already is
+    fn add(&mut self, server: &Hostname) {
+        if self.members.insert(server.clone()) {
+            self.ring.add_node(server);
+        }
+    }
+
+    /// Stops routing to `server` until it is added back
+    fn remove(&mut self, server: &Hostname) {
+        if self.members.remove(server) {
+            self.ring.remove_node(server);
+        }
+    }
+
+    fn get(&mut self, unique: &str) -> Option<Hostname> {
+        self.ring.get_node(unique.to_string()).cloned()
+    }
+}
+
+/// A submitted job to send again if its connection is lost before it finishes
+#[derive(Clone)]
+struct Outstanding {
+    offset: usize,
+    unique: Bytes,
+    packet: Packet,
+    response_tx: Sender<WorkUpdate>,
+}
+
+/// Parses a decimal field of `req`, an error rather than a panic if the server sent something else
+fn parse_number<T: FromStr>(req: &Packet, field: &[u8]) -> Result<T, io::Error> {
+    str::from_utf8(field).ok().and_then(|s| s.parse().ok()).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} is not a number in {}", String::from_utf8_lossy(field), req.ptype_name()),
+        )
+    })
+}
+
+/// ERROR codes a server sends in place of JOB_CREATED when it refuses a submission
+const SUBMISSION_ERRORS: &[&[u8]] = &[
+    b"rate_limited",
+    b"draining",
+    b"no_workers",
+    b"data_too_large",
+    b"dependency_cycle",
+];
+
+/// Where each submitted job's updates go, along with the offset of the server it was submitted to
+type SendersByHandle = Arc<Mutex<HashMap<JobHandle, (usize, Sender<WorkUpdate>)>>>;
+
+/// What a connection needs from its [Client], so it can be (re)established on its own
+#[derive(Clone)]
+struct ConnectionContext {
+    client_id: Option<Bytes>,
+    exceptions: bool,
+    conns: Arc<Mutex<Vec<Arc<Mutex<ClientHandler>>>>>,
+    senders_by_handle: SendersByHandle,
+    jobs_tx_by_func: Arc<Mutex<HashMap<Vec<u8>, Sender<WorkerJob>>>>,
+    echo_tx: Sender<Bytes>,
+    error_tx: Sender<(Bytes, Bytes)>,
+    worker_job_tx: Sender<WorkerJob>,
+    keepalive: Option<Keepalive>,
+    reconnect: Option<Reconnect>,
+    outstanding: Arc<Mutex<HashMap<JobHandle, Outstanding>>>,
+    ring: Arc<Mutex<ServerRing>>,
 }
 
 /// Each individual connection has one of these for handling packets
 struct ClientHandler {
     client_id: Option<Bytes>,
-    senders_by_handle: Arc<Mutex<HashMap<Bytes, Sender<WorkUpdate>>>>,
+    senders_by_handle: SendersByHandle,
     jobs_tx_by_func: Arc<Mutex<HashMap<Vec<u8>, Sender<WorkerJob>>>>,
     sink_tx: Sender<Packet>,
     echo_tx: Sender<Bytes>,
-    job_created_tx: Sender<Bytes>,
-    status_res_tx: Sender<JobStatus>,
     error_tx: Sender<(Bytes, Bytes)>,
     worker_job_tx: Sender<WorkerJob>,
+    keepalive_tx: Sender<Bytes>,
+    outstanding: Arc<Mutex<HashMap<JobHandle, Outstanding>>>,
+    /// Jobs sent again on this connection, in order, awaiting their JOB_CREATED
+    resubmitted: VecDeque<Outstanding>,
+    /// Submissions on this connection, in order, awaiting their JOB_CREATED or ERROR
+    created: VecDeque<oneshot::Sender<Result<JobHandle, io::Error>>>,
+    /// GET_STATUS requests on this connection, in order, awaiting their STATUS_RES
+    statuses: VecDeque<oneshot::Sender<JobStatus>>,
 }
 
 /// Return object for submit_ functions.
 pub struct ClientJob {
-    handle: Bytes,
+    handle: JobHandle,
     response_rx: Receiver<WorkUpdate>,
 }
 
+/// Asks the server a background job went to for its status, see [Client::submit_background_with_status]
+pub struct StatusPoller {
+    conns: Arc<Mutex<Vec<Arc<Mutex<ClientHandler>>>>>,
+    offset: usize,
+    handle: JobHandle,
+}
+
 /// Passed to workers
 ///
 /// The sink_tx property of this structure can be used to send raw packets
 /// to the gearman server from workers, although this is not known to work
 /// generically as of this writing.
 pub struct WorkerJob {
-    handle: Bytes,
+    handle: JobHandle,
     function: Bytes,
     payload: Bytes,
     sink_tx: Sender<Packet>,
@@ -108,48 +243,319 @@ pub struct WorkerJob {
 /// Logical representation of the data workers may send back to clients
 pub enum WorkUpdate {
     Complete {
-        handle: Bytes,
+        handle: JobHandle,
         payload: Bytes,
     },
     Data {
-        handle: Bytes,
+        handle: JobHandle,
         payload: Bytes,
     },
     Warning {
-        handle: Bytes,
+        handle: JobHandle,
         payload: Bytes,
     },
     Exception {
-        handle: Bytes,
+        handle: JobHandle,
         payload: Bytes,
     },
     Status {
-        handle: Bytes,
+        handle: JobHandle,
         numerator: usize,
         denominator: usize,
     },
-    Fail(Bytes),
+    Fail(JobHandle),
+    /// Sent by [Client::forget], the server may still run the job
+    Cancelled(JobHandle),
+}
+
+/// One event from a job submitted with [Client::submit_stream]
+#[derive(Debug, PartialEq)]
+pub enum JobEvent {
+    Data(Vec<u8>),
+    Warning(Vec<u8>),
+    Status(u32, u32),
+    Complete(Vec<u8>),
+    Fail,
+    Exception(Vec<u8>),
+    Cancelled,
+}
+
+impl From<WorkUpdate> for JobEvent {
+    fn from(update: WorkUpdate) -> JobEvent {
+        match update {
+            WorkUpdate::Complete { payload, .. } => JobEvent::Complete(payload.to_vec()),
+            WorkUpdate::Data { payload, .. } => JobEvent::Data(payload.to_vec()),
+            WorkUpdate::Warning { payload, .. } => JobEvent::Warning(payload.to_vec()),
+            WorkUpdate::Exception { payload, .. } => JobEvent::Exception(payload.to_vec()),
+            WorkUpdate::Status {
+                numerator,
+                denominator,
+                ..
+            } => JobEvent::Status(numerator as u32, denominator as u32),
+            WorkUpdate::Fail(_) => JobEvent::Fail,
+            WorkUpdate::Cancelled(_) => JobEvent::Cancelled,
+        }
+    }
+}
+
+/// Builds a SUBMIT_JOB* packet, refusing jobs too large to send
+fn submit_packet(ptype: u32, function: &str, unique: &str, payload: &[u8]) -> Result<Packet, io::Error> {
+    let size = 2 + function.len() + unique.len() + payload.len(); // 2 for nulls
+    if !fits_in_packet(size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} byte {} job is too large for a packet", payload.len(), function),
+        ));
+    }
+    let mut data = BytesMut::with_capacity(size);
+    data.extend(function.bytes());
+    data.put_u8(b'\0');
+    data.extend(unique.bytes());
+    data.put_u8(b'\0');
+    data.extend(payload);
+    Ok(new_req(ptype, data.freeze()))
+}
+
+/// Connects to `addr`, retrying with exponential backoff if `retry` is set
+async fn connect_with_retry(addr: SocketAddr, retry: Option<ConnectRetry>) -> Result<TcpStream, io::Error> {
+    let retry = match retry {
+        None => return TcpStream::connect(addr).await,
+        Some(retry) => retry,
+    };
+    let started = Instant::now();
+    let mut delay = retry.initial;
+    loop {
+        let e = match TcpStream::connect(addr).await {
+            Ok(conn) => return Ok(conn),
+            Err(e) => e,
+        };
+        let left = retry.deadline.checked_sub(started.elapsed()).unwrap_or_default();
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Could not connect to {} within {:?}: {}", addr, retry.deadline, e),
+            ));
+        }
+        debug!("Connecting to {} failed, retrying in {:?}: {}", addr, delay.min(left), e);
+        sleep(delay.min(left)).await;
+        delay *= 2;
+    }
+}
+
+/// Asks `server` to drop the queued job `handle`, returning whether it had one
+async fn admin_cancel(server: &str, handle: &JobHandle) -> Result<bool, io::Error> {
+    let mut conn = BufReader::new(TcpStream::connect(server).await?);
+    let command = format!("cancel job {}\n", handle);
+    conn.get_mut().write_all(command.as_bytes()).await?;
+    let mut reply = String::new();
+    conn.read_line(&mut reply).await?;
+    debug!("cancel job {} on {}: {:?}", handle, server, reply);
+    Ok(reply.starts_with("OK"))
+}
+
+/// Sends GET_STATUS on `conn` and waits for the STATUS_RES, which comes back in request order
+async fn request_status(conn: Arc<Mutex<ClientHandler>>, handle: &JobHandle) -> Result<JobStatus, io::Error> {
+    let (status_tx, status_rx) = oneshot::channel();
+    conn.lock().unwrap().statuses.push_back(status_tx);
+    if let Err(e) = send_packet(conn.clone(), new_req(GET_STATUS, handle.to_bytes())).await {
+        conn.lock().unwrap().statuses.pop_back();
+        return Err(e);
+    }
+    status_rx
+        .await
+        .map_err(|_| io::Error::other("Connection closed before STATUS_RES"))
 }
 
 async fn send_packet(conn: Arc<Mutex<ClientHandler>>, packet: Packet) -> Result<(), io::Error> {
-    let mut sink_tx = conn.lock().unwrap().sink_tx.clone();
+    let sink_tx = conn.lock().unwrap().sink_tx.clone();
     if let Err(e) = sink_tx.send(packet).await {
         error!("Receiver dropped");
-        return Err(io::Error::new(io::ErrorKind::Other, format!("{}", e)));
+        return Err(io::Error::other(format!("{}", e)));
+    }
+    Ok(())
+}
+
+/// Connects to `server` and starts a connection on it in `offset`, see [start_connection]
+fn reconnect(
+    ctx: ConnectionContext,
+    offset: usize,
+    server: Hostname,
+) -> Pin<Box<dyn Future<Output = Result<(), io::Error>> + Send>> {
+    Box::pin(async move {
+        let addr = server
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::other(format!("No address for {}", server)))?;
+        let conn = TcpStream::connect(addr).await?;
+        start_connection(ctx, offset, server, conn).await
+    })
+}
+
+/// Retries [reconnect] every `interval` until it succeeds
+async fn recover(ctx: ConnectionContext, offset: usize, server: Hostname, interval: Duration) {
+    loop {
+        match reconnect(ctx.clone(), offset, server.clone()).await {
+            Ok(()) => break,
+            Err(e) => {
+                error!("Reconnecting to {} failed: {}", server, e);
+                sleep(interval).await;
+            }
+        }
+    }
+}
+
+/// Jobs previously submitted through `offset` that should be sent again
+///
+/// Jobs sharing a unique are only sent once, the server coalesces them anyway.
+fn resubmissions(ctx: &ConnectionContext, offset: usize) -> VecDeque<Outstanding> {
+    let outstanding = ctx.outstanding.lock().unwrap();
+    let mut seen = HashSet::new();
+    outstanding
+        .values()
+        .filter(|job| job.offset == offset && seen.insert(job.unique.clone()))
+        .cloned()
+        .collect()
+}
+
+/// Sets up the handler for a fresh connection and spawns its reader, writer and keepalive
+async fn start_connection(
+    ctx: ConnectionContext,
+    offset: usize,
+    server: Hostname,
+    conn: TcpStream,
+) -> Result<(), io::Error> {
+    let pc = PacketCodec::new();
+    let (mut sink, mut stream) = pc.framed(conn).split();
+    if let Some(ref client_id) = ctx.client_id {
+        let req = new_req(SET_CLIENT_ID, client_id.clone());
+        sink.send(req).await?;
+    }
+    if ctx.exceptions {
+        let req = new_req(OPTION_REQ, Bytes::from_static(b"exceptions"));
+        sink.send(req).await?;
+    }
+    let resubmitted = resubmissions(&ctx, offset);
+    for job in resubmitted.iter() {
+        info!("Resubmitting {:?} to {}", job.unique, server);
+        sink.send(job.packet.clone()).await?;
+    }
+    let (tx, mut rx) = channel(100); // XXX pick a good value or const
+    let (keepalive_tx, mut keepalive_rx) = channel(1);
+    let tx2 = tx.clone();
+    let handler = Arc::new(Mutex::new(ClientHandler::new(
+        &ctx.client_id,
+        ctx.senders_by_handle.clone(),
+        ctx.jobs_tx_by_func.clone(),
+        ctx.echo_tx.clone(),
+        tx2,
+        ctx.error_tx.clone(),
+        ctx.worker_job_tx.clone(),
+        keepalive_tx,
+        ctx.outstanding.clone(),
+    )));
+    handler.lock().unwrap().resubmitted = resubmitted;
+    {
+        let mut conns = ctx.conns.lock().unwrap();
+        if offset < conns.len() {
+            conns[offset] = handler.clone();
+        } else {
+            conns.insert(offset, handler.clone());
+        }
+    }
+    // Back in the ring in case a failed send took it out
+    ctx.ring.lock().unwrap().add(&server);
+    let keepalive_sink_tx = tx.clone();
+    // Set by whichever of the reader or keepalive notices the connection is gone first
+    let lost = Arc::new(AtomicBool::new(false));
+    let reader_lost = lost.clone();
+    let reader_ctx = ctx.clone();
+    let reader_server = server.clone();
+    let reader = async move {
+        let tx = tx.clone();
+        while let Some(frame) = stream.next().await {
+            trace!("Frame read: {:?}", frame);
+            // A server sending garbage is treated like one that went away
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    error!("Undecodable data from {}: {}", reader_server, e);
+                    break;
+                }
+            };
+            let response = {
+                let handler = handler.clone();
+                debug!("Locking handler");
+                let mut handler = handler.lock().unwrap();
+                debug!("Locked handler");
+                handler.call(frame)
+            };
+            if let Err(e) = response {
+                error!("conn dropped?: {}", e);
+                return;
+            }
+            if tx.send(response.unwrap()).await.is_err() {
+                error!("receiver dropped")
+            }
+        }
+        if let Some(reconnect) = reader_ctx.reconnect {
+            if !reader_lost.swap(true, Ordering::SeqCst) {
+                warn!("Connection to {} closed, reconnecting", reader_server);
+                runtime::Handle::current().spawn(recover(
+                    reader_ctx,
+                    offset,
+                    reader_server,
+                    reconnect.interval,
+                ));
+            }
+        }
+    };
+    let writer = async move {
+        while let Some(packet) = rx.recv().await {
+            trace!("Sending {:?}", &packet);
+            if let Err(e) = sink.send(packet).await {
+                // Dropping rx makes further sends fail, so this server is
+                // taken out of the ring until it is reconnected
+                error!("Connection ({}) dropped: {}", offset, e);
+                break;
+            }
+        }
+    };
+    let reader = runtime::Handle::current().spawn(reader);
+    let writer = runtime::Handle::current().spawn(writer);
+    if let Some(keepalive) = ctx.keepalive {
+        runtime::Handle::current().spawn(async move {
+            loop {
+                sleep(keepalive.interval).await;
+                let echo = new_req(ECHO_REQ, Bytes::from_static(KEEPALIVE_TOKEN));
+                if keepalive_sink_tx.send(echo).await.is_err() {
+                    break;
+                }
+                match timeout(keepalive.timeout, keepalive_rx.recv()).await {
+                    Ok(Some(_)) => trace!("Keepalive ok for {}", server),
+                    _ => break,
+                }
+            }
+            reader.abort();
+            writer.abort();
+            if !lost.swap(true, Ordering::SeqCst) {
+                warn!("Keepalive to {} failed, reconnecting", server);
+                recover(ctx, offset, server, keepalive.interval).await;
+            }
+        });
     }
     Ok(())
 }
 
 impl ClientJob {
-    fn new(handle: Bytes, response_rx: Receiver<WorkUpdate>) -> ClientJob {
+    fn new(handle: JobHandle, response_rx: Receiver<WorkUpdate>) -> ClientJob {
         ClientJob {
-            handle: handle,
-            response_rx: response_rx,
+            handle,
+            response_rx,
         }
     }
 
     /// returns the job handle
-    pub fn handle(&self) -> &Bytes {
+    pub fn handle(&self) -> &JobHandle {
         &self.handle
     }
 
@@ -158,13 +564,49 @@ impl ClientJob {
     /// Use this in clients to wait for a response on a job that was submitted. This will block
     /// forever or error if used on a background job.
     pub async fn response(&mut self) -> Result<WorkUpdate, io::Error> {
-        Ok(self.response_rx.recv().await.unwrap())
+        match self.response_rx.recv().await {
+            Some(update) => Ok(update),
+            None => Err(io::Error::other(format!("No more updates for {}", self.handle))),
+        }
+    }
+}
+
+impl StatusPoller {
+    /// Sends GET_STATUS for the job and returns the answer
+    ///
+    /// Goes over whichever connection to the job's server is current, so it
+    /// keeps working across reconnects.
+    pub async fn status(&self) -> Result<JobStatus, io::Error> {
+        let conn = self.conns.lock().unwrap().get(self.offset).cloned();
+        match conn {
+            Some(conn) => request_status(conn, &self.handle).await,
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "Not connected")),
+        }
+    }
+}
+
+impl JobStatus {
+    pub fn handle(&self) -> &JobHandle {
+        &self.handle
+    }
+    /// Whether the server knows of the job
+    pub fn known(&self) -> bool {
+        self.known
+    }
+    pub fn running(&self) -> bool {
+        self.running
+    }
+    pub fn numerator(&self) -> u32 {
+        self.numerator
+    }
+    pub fn denominator(&self) -> u32 {
+        self.denominator
     }
 }
 
 impl WorkerJob {
-    pub fn handle(&self) -> &[u8] {
-        self.handle.as_ref()
+    pub fn handle(&self) -> &JobHandle {
+        &self.handle
     }
     pub fn function(&self) -> &[u8] {
         self.function.as_ref()
@@ -184,9 +626,9 @@ impl WorkerJob {
         let numerator = format!("{}", numerator);
         let denominator = format!("{}", denominator);
         let mut payload = BytesMut::with_capacity(
-            2 + self.handle.len() + numerator.as_bytes().len() + denominator.as_bytes().len(),
+            2 + self.handle.as_bytes().len() + numerator.len() + denominator.len(),
         );
-        payload.extend(self.handle.clone());
+        payload.extend(self.handle.as_bytes());
         payload.put_u8(b'\0');
         payload.extend(numerator.as_bytes());
         payload.put_u8(b'\0');
@@ -197,7 +639,7 @@ impl WorkerJob {
 
     async fn send_packet(&mut self, packet: Packet) -> Result<(), io::Error> {
         match self.sink_tx.send(packet).await {
-            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Connection closed")),
+            Err(_) => Err(io::Error::other("Connection closed")),
             Ok(_) => Ok(()),
         }
     }
@@ -207,7 +649,7 @@ impl WorkerJob {
     /// This method is typically called by the [Client.work] method upon return
     /// of an error from the assigned closure.
     pub async fn work_fail(&mut self) -> Result<(), io::Error> {
-        let packet = new_res(WORK_FAIL, self.handle.clone());
+        let packet = new_res(WORK_FAIL, self.handle.to_bytes());
         self.send_packet(packet).await
     }
 
@@ -216,75 +658,238 @@ impl WorkerJob {
     /// This method is typically called by the [Client.work] method upon return of
     /// the assigned closure.
     pub async fn work_complete(&mut self, response: Vec<u8>) -> Result<(), io::Error> {
-        let mut payload = BytesMut::with_capacity(self.handle.len() + 1 + self.payload.len());
-        payload.extend(self.handle.clone());
+        let mut payload = BytesMut::with_capacity(self.handle.as_bytes().len() + 1 + self.payload.len());
+        payload.extend(self.handle.as_bytes());
         payload.put_u8(b'\0');
         payload.extend(response);
         let packet = new_res(WORK_COMPLETE, payload.freeze());
         self.send_packet(packet).await
     }
+
+    /// Sends a WORK_EXCEPTION
+    ///
+    /// Like [WorkerJob::work_fail] this ends the job, but carries `detail` to
+    /// clients that asked for exceptions.
+    pub async fn work_exception(&mut self, detail: Vec<u8>) -> Result<(), io::Error> {
+        let mut payload = BytesMut::with_capacity(self.handle.as_bytes().len() + 1 + detail.len());
+        payload.extend(self.handle.as_bytes());
+        payload.put_u8(b'\0');
+        payload.extend(detail);
+        let packet = new_res(WORK_EXCEPTION, payload.freeze());
+        self.send_packet(packet).await
+    }
+
+    /// Sends WORK_COMPLETE for an `Ok` result, or WORK_FAIL or WORK_EXCEPTION
+    /// as the error asks
+    pub(crate) async fn finish(&mut self, result: Result<Vec<u8>, WorkError>) {
+        match result {
+            Err(WorkError::Fail) => {
+                if let Err(e) = self.work_fail().await {
+                    warn!("Failed to send WORK_FAIL {}", e);
+                }
+            }
+            Err(WorkError::Exception(detail)) => {
+                if let Err(e) = self.work_exception(detail).await {
+                    warn!("Failed to send WORK_EXCEPTION {}", e);
+                }
+            }
+            Ok(response) => {
+                if let Err(e) = self.work_complete(response).await {
+                    warn!("Failed to send WORK_COMPLETE {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Client {
     pub fn new() -> Client {
         let (tx, rx) = channel(100); // XXX this is lame
-        let (txj, rxj) = channel(100); // XXX this is lame
-        let (txs, rxs) = channel(100); // XXX this is lame
         let (txe, rxe) = channel(100); // XXX this is lame
         let (txw, rxw) = channel(100);
         Client {
             servers: Vec::new(),
+            ring: Arc::new(Mutex::new(ServerRing::new())),
             conns: Arc::new(Mutex::new(Vec::new())),
             connected: Vec::new(),
             client_id: None,
+            exceptions: false,
             senders_by_handle: Arc::new(Mutex::new(HashMap::new())),
             jobs_tx_by_func: Arc::new(Mutex::new(HashMap::new())),
             echo_tx: tx,
             echo_rx: rx,
-            job_created_tx: txj,
-            job_created_rx: rxj,
-            status_res_tx: txs,
-            status_res_rx: rxs,
             error_tx: txe,
             error_rx: rxe,
             worker_job_tx: txw,
             worker_job_rx: rxw,
+            keepalive: None,
+            reconnect: None,
+            connect_retry: None,
+            outstanding: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
     /// Add a server to the client. This does not initiate anything, it just configures the client.
     ///
-    /// As of this writing, only one server at a time is supported.
+    /// Jobs are routed to servers by consistent hashing of their unique id,
+    /// so the same unique always reaches the same server and can be coalesced.
     pub fn add_server(mut self, server: &str) -> Self {
+        self.ring.lock().unwrap().add(&Hostname::from(server));
         self.servers.push(Hostname::from(server));
         self.connected.push(false);
         self
     }
 
+    /// A client for the servers listed in the `GEARMAN_SERVERS` environment variable
+    ///
+    /// The list is comma separated `host:port`, a host without a port gets
+    /// [DEFAULT_PORT]. If the variable is unset or empty the client uses
+    /// 127.0.0.1 on that port. Like
+    /// [Client.new] nothing happens until [Client.connect] is called.
+    pub fn from_env() -> Client {
+        let servers = env::var(SERVERS_ENV).unwrap_or_default();
+        let mut servers: Vec<String> = servers
+            .split(',')
+            .map(str::trim)
+            .filter(|server| !server.is_empty())
+            .map(|server| {
+                // A bare IPv6 address in brackets has colons but no port
+                if server.contains(':') && !server.ends_with(']') {
+                    server.to_string()
+                } else {
+                    format!("{}:{}", server, DEFAULT_PORT)
+                }
+            })
+            .collect();
+        if servers.is_empty() {
+            servers.push(format!("127.0.0.1:{}", DEFAULT_PORT));
+        }
+        servers
+            .iter()
+            .fold(Client::new(), |client, server| client.add_server(server))
+    }
+
+    /// Adds all of `servers` and connects to them, see [Client.add_server]
+    pub async fn connect_pool(servers: Vec<SocketAddr>) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut client = Client::new();
+        for server in servers {
+            client = client.add_server(&server.to_string());
+        }
+        client.connect().await
+    }
+
+    /// Returns the server that jobs with `unique` are routed to
+    pub fn server_for_unique(&mut self, unique: &str) -> Option<&str> {
+        let offset = self.offset_for_unique(unique)?;
+        Some(self.servers[offset].as_str())
+    }
+
+    /// Offset into `servers` of the server `unique` routes to
+    fn offset_for_unique(&self, unique: &str) -> Option<usize> {
+        let server = self.ring.lock().unwrap().get(unique)?;
+        self.servers.iter().position(|s| *s == server)
+    }
+
+    fn conn_for_unique(&mut self, unique: &str) -> Result<(usize, Arc<Mutex<ClientHandler>>), io::Error> {
+        let offset = self
+            .offset_for_unique(unique)
+            .ok_or_else(|| io::Error::other("No connections for submitting jobs."))?;
+        let conn = self.conns.lock().unwrap().get(offset).cloned();
+        conn.map(|conn| (offset, conn))
+            .ok_or_else(|| io::Error::other(format!("Not connected to {}", self.servers[offset])))
+    }
+
     /// Configures the client ID for this client
     pub fn set_client_id(mut self, client_id: &'static str) -> Self {
         self.client_id = Some(Bytes::from(client_id));
         self
     }
 
+    /// Asks the servers to forward WORK_EXCEPTION instead of turning it into WORK_FAIL
+    ///
+    /// Exceptions then arrive as [WorkUpdate::Exception] with the worker's detail.
+    pub fn set_exceptions(mut self) -> Self {
+        self.exceptions = true;
+        self
+    }
+
+    /// Checks connections with an ECHO_REQ every `interval`
+    ///
+    /// If the matching ECHO_RES doesn't arrive within `timeout`, the connection is
+    /// torn down and re-established. This keeps long lived worker connections from
+    /// silently dying behind NAT and firewalls.
+    pub fn set_keepalive(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.keepalive = Some(Keepalive {
+            interval,
+            timeout,
+        });
+        self
+    }
+
+    /// Retries failed connects in [Client.connect] for up to `deadline`
+    ///
+    /// The first retry is after `initial`, each one after that waits twice as
+    /// long as the last. Useful when clients and servers start at the same time.
+    pub fn set_connect_retry(mut self, initial: Duration, deadline: Duration) -> Self {
+        self.connect_retry = Some(ConnectRetry {
+            initial,
+            deadline,
+        });
+        self
+    }
+
+    /// Reconnects to a server every `interval` until it succeeds when its connection drops
+    ///
+    /// Foreground jobs that haven't completed are submitted again with the same
+    /// unique once reconnected, so their [ClientJob] still gets a response.
+    /// Background jobs are never waited on once created. With `resubmit_background`
+    /// set, one whose connection drops before its JOB_CREATED is sent again, at
+    /// the risk of running twice if the server had already queued it.
+    pub fn set_reconnect(mut self, interval: Duration, resubmit_background: bool) -> Self {
+        self.reconnect = Some(Reconnect {
+            interval,
+            resubmit_background,
+        });
+        self
+    }
+
     /// Attempts to connect to all servers added via [Client.add_server]
     pub async fn connect(mut self) -> Result<Self, Box<dyn std::error::Error>> {
         /* Returns the client after having attempted to connect to all servers. */
         trace!("connecting");
-        let mut i = 0;
         let mut connects = Vec::new();
-        for is_conn in self.connected.iter() {
+        for (i, is_conn) in self.connected.iter().enumerate() {
             if !is_conn {
                 let server: &str = self.servers.get(i).unwrap();
                 let addr = server.to_socket_addrs().unwrap().next().unwrap();
                 trace!("really connecting: i={} addr={:?}", i, addr);
+                let retry = self.connect_retry;
                 connects.push(
                     runtime::Handle::current()
-                        .spawn(async move { (i, TcpStream::connect(addr).await) }),
+                        .spawn(async move { (i, connect_with_retry(addr, retry).await) }),
                 );
             }
-            i = i + 1;
         }
+        let ctx = ConnectionContext {
+            client_id: self.client_id.clone(),
+            exceptions: self.exceptions,
+            conns: self.conns.clone(),
+            senders_by_handle: self.senders_by_handle.clone(),
+            jobs_tx_by_func: self.jobs_tx_by_func.clone(),
+            echo_tx: self.echo_tx.clone(),
+            error_tx: self.error_tx.clone(),
+            worker_job_tx: self.worker_job_tx.clone(),
+            keepalive: self.keepalive,
+            reconnect: self.reconnect,
+            outstanding: self.outstanding.clone(),
+            ring: self.ring.clone(),
+        };
         for connect in connects.iter_mut() {
             let connect = connect.await?;
             let offset = connect.0;
@@ -294,58 +899,8 @@ impl Client {
                 offset,
                 self.servers[offset]
             );
-            let pc = PacketCodec {};
-            let (mut sink, mut stream) = pc.framed(conn).split();
-            if let Some(ref client_id) = self.client_id {
-                let req = new_req(SET_CLIENT_ID, client_id.clone());
-                sink.send(req).await?;
-            }
-            let (tx, mut rx) = channel(100); // XXX pick a good value or const
-            let tx = tx.clone();
-            let tx2 = tx.clone();
-            let handler = Arc::new(Mutex::new(ClientHandler::new(
-                &self.client_id,
-                self.senders_by_handle.clone(),
-                self.jobs_tx_by_func.clone(),
-                self.echo_tx.clone(),
-                tx2,
-                self.job_created_tx.clone(),
-                self.status_res_tx.clone(),
-                self.error_tx.clone(),
-                self.worker_job_tx.clone(),
-            )));
+            start_connection(ctx.clone(), offset, self.servers[offset].clone(), conn).await?;
             self.connected[offset] = true;
-            self.conns.lock().unwrap().insert(offset, handler.clone());
-            let reader = async move {
-                let mut tx = tx.clone();
-                while let Some(frame) = stream.next().await {
-                    trace!("Frame read: {:?}", frame);
-                    let response = {
-                        let handler = handler.clone();
-                        debug!("Locking handler");
-                        let mut handler = handler.lock().unwrap();
-                        debug!("Locked handler");
-                        handler.call(frame.unwrap())
-                    };
-                    if let Err(e) = response {
-                        error!("conn dropped?: {}", e);
-                        return;
-                    }
-                    if let Err(_) = tx.send(response.unwrap()).await {
-                        error!("receiver dropped")
-                    }
-                }
-            };
-            let writer = async move {
-                while let Some(packet) = rx.recv().await {
-                    trace!("Sending {:?}", &packet);
-                    if let Err(_) = sink.send(packet).await {
-                        error!("Connection ({}) dropped", offset);
-                    }
-                }
-            };
-            runtime::Handle::current().spawn(reader);
-            runtime::Handle::current().spawn(writer);
         }
         trace!("connected all");
         Ok(self)
@@ -355,16 +910,12 @@ impl Client {
     ///
     /// Returns an error if there aren't any connected servers, or no ECHO_RES comes back
     pub async fn echo(&mut self, payload: &[u8]) -> Result<(), io::Error> {
-        let payload = payload.clone();
         let packet = new_req(ECHO_REQ, Bytes::copy_from_slice(payload));
         let conn: Arc<Mutex<ClientHandler>> = {
             if let Some(conn) = self.conns.lock().unwrap().get_mut(0) {
                 conn.clone()
             } else {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "No connections for echo!",
-                ));
+                return Err(io::Error::other("No connections for echo!"));
             }
         };
         send_packet(conn, packet).await?;
@@ -379,7 +930,72 @@ impl Client {
     /// Submits a foreground job. The see [ClientJob.response] for how to see the response from the
     /// worker.
     pub async fn submit(&mut self, function: &str, payload: &[u8]) -> Result<ClientJob, io::Error> {
-        self.direct_submit(SUBMIT_JOB, function, payload).await
+        let unique = format!("{}", Uuid::new_v4());
+        self.direct_submit(SUBMIT_JOB, function, &unique, payload).await
+    }
+
+    /// Submits a foreground job and waits at most `limit` for it to finish
+    ///
+    /// Returns the final Complete, Fail or Exception update, or a TimedOut
+    /// error. A job that times out is left to run on the server.
+    pub async fn submit_with_timeout(
+        &mut self,
+        function: &str,
+        payload: &[u8],
+        limit: Duration,
+    ) -> Result<WorkUpdate, io::Error> {
+        let finished = async {
+            let mut job = self.submit(function, payload).await?;
+            loop {
+                match job.response().await? {
+                    update @ WorkUpdate::Complete { .. }
+                    | update @ WorkUpdate::Fail(_)
+                    | update @ WorkUpdate::Cancelled(_)
+                    | update @ WorkUpdate::Exception { .. } => return Ok(update),
+                    _ => {}
+                }
+            }
+        };
+        match timeout(limit, finished).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} job did not finish within {:?}", function, limit),
+            )),
+        }
+    }
+
+    /// Submits a foreground job and returns every update the worker sends for it
+    ///
+    /// The stream ends after [JobEvent::Complete], [JobEvent::Fail] or [JobEvent::Exception].
+    pub async fn submit_stream(
+        &mut self,
+        function: &str,
+        payload: &[u8],
+    ) -> Result<impl Stream<Item = JobEvent>, io::Error> {
+        let job = self.submit(function, payload).await?;
+        Ok(stream::unfold(Some(job.response_rx), |response_rx| async move {
+            let mut response_rx = response_rx?;
+            let event = JobEvent::from(response_rx.recv().await?);
+            let done = matches!(
+                event,
+                JobEvent::Complete(_) | JobEvent::Fail | JobEvent::Exception(_) | JobEvent::Cancelled
+            );
+            Some((event, if done { None } else { Some(response_rx) }))
+        }))
+    }
+
+    /// Submits a foreground job with a caller chosen unique id
+    ///
+    /// Jobs with the same unique are sent to the same server, which will coalesce
+    /// them if one is already queued or running.
+    pub async fn submit_unique(
+        &mut self,
+        function: &str,
+        unique: &str,
+        payload: &[u8],
+    ) -> Result<ClientJob, io::Error> {
+        self.direct_submit(SUBMIT_JOB, function, unique, payload).await
     }
 
     /// Submits a background job. The [ClientJob] returned won't be able to use the
@@ -389,66 +1005,219 @@ impl Client {
         function: &str,
         payload: &[u8],
     ) -> Result<ClientJob, io::Error> {
-        self.direct_submit(SUBMIT_JOB_BG, function, payload).await
+        let unique = format!("{}", Uuid::new_v4());
+        self.direct_submit(SUBMIT_JOB_BG, function, &unique, payload)
+            .await
+    }
+
+    /// Submits a background job, returning its handle and a way to poll its status
+    ///
+    /// The [StatusPoller] asks the server the job was routed to, so the caller
+    /// needn't know which one that was.
+    pub async fn submit_background_with_status(
+        &mut self,
+        function: &str,
+        payload: &[u8],
+    ) -> Result<(JobHandle, StatusPoller), io::Error> {
+        let unique = format!("{}", Uuid::new_v4());
+        let (offset, job) = self
+            .routed_submit(SUBMIT_JOB_BG, function, &unique, payload)
+            .await?;
+        let poller = StatusPoller {
+            conns: self.conns.clone(),
+            offset,
+            handle: job.handle.clone(),
+        };
+        Ok((job.handle, poller))
+    }
+
+    /// Submits many background jobs, sending them all before waiting for any JOB_CREATED
+    ///
+    /// The server answers each connection's submissions in order, so the
+    /// handles are matched up without a round-trip per job. They are returned
+    /// in the same order as `jobs`.
+    pub async fn submit_background_batch(
+        &mut self,
+        jobs: &[(&str, &[u8])],
+    ) -> Result<Vec<JobHandle>, io::Error> {
+        let mut pending = Vec::with_capacity(jobs.len());
+        for (function, payload) in jobs {
+            let unique = format!("{}", Uuid::new_v4());
+            let packet = submit_packet(SUBMIT_JOB_BG, function, &unique, payload)?;
+            let (offset, created_rx) = self.send_submit(&unique, &packet).await?;
+            pending.push((offset, unique, packet, created_rx));
+        }
+        let mut handles = Vec::with_capacity(pending.len());
+        for (offset, unique, packet, created_rx) in pending {
+            let (_, handle) = self.submit_created(offset, created_rx, &unique, &packet).await?;
+            handles.push(handle);
+        }
+        Ok(handles)
     }
 
     async fn direct_submit(
         &mut self,
         ptype: u32,
         function: &str,
+        unique: &str,
         payload: &[u8],
     ) -> Result<ClientJob, io::Error> {
-        let conn = {
-            let mut conns = self.conns.lock().unwrap();
-            if let Some(conn) = conns.get_mut(0) {
-                conn.clone()
-            } else {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "No connections for submitting jobs.",
-                ));
-            }
-        };
-        /* Pick the conn later */
-        let conn = conn.clone();
-        let unique = format!("{}", Uuid::new_v4());
-        let mut data = BytesMut::with_capacity(2 + function.len() + unique.len() + payload.len()); // 2 for nulls
-        data.extend(function.bytes());
-        data.put_u8(b'\0');
-        data.extend(unique.bytes());
-        data.put_u8(b'\0');
-        data.extend(payload);
-        let packet = new_req(ptype, data.freeze());
+        let (_, job) = self.routed_submit(ptype, function, unique, payload).await?;
+        Ok(job)
+    }
+
+    /// Like [Client::direct_submit], also returning the offset of the server the job went to
+    async fn routed_submit(
+        &mut self,
+        ptype: u32,
+        function: &str,
+        unique: &str,
+        payload: &[u8],
+    ) -> Result<(usize, ClientJob), io::Error> {
+        let packet = submit_packet(ptype, function, unique, payload)?;
+        let (offset, created_rx) = self.send_submit(unique, &packet).await?;
+        let (offset, handle) = self.submit_created(offset, created_rx, unique, &packet).await?;
         {
-            let conn = conn.clone();
-            send_packet(conn, packet).await?;
-            /* Really important that conn be unlocked here to unblock res processing */
-        }
-        if let Some(handle) = self.job_created_rx.recv().await {
             let (tx, rx) = channel(100); // XXX lamer
             let mut response_by_handle = self.senders_by_handle.lock().unwrap();
-            response_by_handle.insert(handle.clone(), tx.clone());
-            Ok(ClientJob::new(handle, rx))
-        } else {
-            Err(io::Error::new(io::ErrorKind::Other, "No job created!"))
+            response_by_handle.insert(handle.clone(), (offset, tx.clone()));
+            // Background jobs never hear back, so there's nothing to wait for once created
+            if self.reconnect.is_some() && ptype != SUBMIT_JOB_BG {
+                self.outstanding.lock().unwrap().insert(
+                    handle.clone(),
+                    Outstanding {
+                        offset,
+                        unique: Bytes::copy_from_slice(unique.as_bytes()),
+                        packet,
+                        response_tx: tx,
+                    },
+                );
+            }
+            Ok((offset, ClientJob::new(handle, rx)))
         }
     }
 
+    /// Sends a submission to the server `unique` routes to, returning that
+    /// server's offset and where its JOB_CREATED will be delivered
+    ///
+    /// Servers answer each connection's submissions in order, so the answer
+    /// is matched to the submission by queueing a promise for it on the
+    /// connection. This holds as long as nothing else submits on the
+    /// connection behind the client's back, e.g. with raw packets sent
+    /// through a [WorkerJob]'s sink.
+    async fn send_submit(
+        &mut self,
+        unique: &str,
+        packet: &Packet,
+    ) -> Result<(usize, oneshot::Receiver<Result<JobHandle, io::Error>>), io::Error> {
+        loop {
+            let (offset, conn) = self.conn_for_unique(unique)?;
+            let (created_tx, created_rx) = oneshot::channel();
+            conn.lock().unwrap().created.push_back(created_tx);
+            match send_packet(conn.clone(), packet.clone()).await {
+                /* Really important that conn be unlocked here to unblock res processing */
+                Ok(()) => return Ok((offset, created_rx)),
+                Err(e) => {
+                    conn.lock().unwrap().created.pop_back();
+                    warn!("Removing {} from the ring: {}", self.servers[offset], e);
+                    self.ring.lock().unwrap().remove(&self.servers[offset]);
+                }
+            }
+        }
+    }
+
+    /// Waits for the handle the server at `offset` gave a submission, see [Client::send_submit]
+    async fn created(
+        &self,
+        offset: usize,
+        created_rx: oneshot::Receiver<Result<JobHandle, io::Error>>,
+    ) -> Result<JobHandle, io::Error> {
+        match created_rx.await {
+            Ok(created) => created,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("Connection to {} closed before JOB_CREATED", self.servers[offset]),
+            )),
+        }
+    }
+
+    /// Like [Client::created], but sends a background job again if its
+    /// connection went away first and `resubmit_background` is set
+    async fn submit_created(
+        &mut self,
+        mut offset: usize,
+        mut created_rx: oneshot::Receiver<Result<JobHandle, io::Error>>,
+        unique: &str,
+        packet: &Packet,
+    ) -> Result<(usize, JobHandle), io::Error> {
+        loop {
+            match self.created(offset, created_rx).await {
+                Ok(handle) => return Ok((offset, handle)),
+                Err(e) => {
+                    let resubmit = match self.reconnect {
+                        Some(reconnect) => reconnect.resubmit_background,
+                        None => false,
+                    };
+                    if packet.ptype != SUBMIT_JOB_BG || !resubmit || e.kind() != io::ErrorKind::ConnectionAborted {
+                        return Err(e);
+                    }
+                    warn!("Submitting background job {} again: {}", unique, e);
+                    let (next_offset, next_rx) = self.send_submit(unique, packet).await?;
+                    offset = next_offset;
+                    created_rx = next_rx;
+                }
+            }
+        }
+    }
+
+    /// Stops waiting for a submitted job, whose [ClientJob::response] then returns
+    /// [WorkUpdate::Cancelled]
+    ///
+    /// With `cancel` the job is also removed from the server it was submitted
+    /// to with the admin `cancel job` command, over a separate connection.
+    /// Returns whether a server cancelled it, a job that already finished can't be.
+    ///
+    /// A job this client isn't waiting on, e.g. one already forgotten, is looked
+    /// for on every connected server, and those that can't be reached are skipped.
+    pub async fn forget(&mut self, handle: &JobHandle, cancel: bool) -> Result<bool, io::Error> {
+        let sender = self.senders_by_handle.lock().unwrap().remove(handle);
+        self.outstanding.lock().unwrap().remove(handle);
+        let offset = match sender {
+            Some((offset, tx)) => {
+                let _ = tx.send(WorkUpdate::Cancelled(handle.clone())).await;
+                Some(offset)
+            }
+            None => None,
+        };
+        if !cancel {
+            return Ok(false);
+        }
+        if let Some(offset) = offset {
+            return admin_cancel(&self.servers[offset], handle).await;
+        }
+        for (server, connected) in self.servers.iter().zip(self.connected.iter()) {
+            if !*connected {
+                continue;
+            }
+            match admin_cancel(server, handle).await {
+                Ok(true) => return Ok(true),
+                Ok(false) => (),
+                Err(e) => warn!("Couldn't ask {} to cancel {}: {}", server, handle, e),
+            }
+        }
+        Ok(false)
+    }
+
     /// Sends a GET_STATUS packet and then returns the STATUS_RES in a [JobStatus]
-    pub async fn get_status(&mut self, handle: &[u8]) -> Result<JobStatus, io::Error> {
+    ///
+    /// This asks the first server, use [Client::submit_background_with_status]
+    /// to ask the one a job was routed to.
+    pub async fn get_status(&mut self, handle: &JobHandle) -> Result<JobStatus, io::Error> {
         let conn: Arc<Mutex<ClientHandler>> = {
             let mut conns = self.conns.lock().unwrap();
             conns.get_mut(0).unwrap().clone()
         };
-        let mut payload = BytesMut::with_capacity(handle.len());
-        payload.extend(handle);
-        let status_req = new_req(GET_STATUS, payload.freeze());
-        send_packet(conn, status_req).await?;
-        if let Some(status_res) = self.status_res_rx.recv().await {
-            Ok(status_res)
-        } else {
-            Err(io::Error::new(io::ErrorKind::Other, "No status to report!"))
-        }
+        request_status(conn, handle).await
     }
 
     /// Sends a CAN_DO on every connection and registers a callback for it
@@ -465,43 +1234,33 @@ impl Client {
     where
         F: FnMut(&mut WorkerJob) -> Result<Vec<u8>, io::Error> + Send + 'static,
     {
-        let (tx, mut rx) = channel(100); // Some day we'll use this param right
-        {
-            let mut conns = self.conns.lock().unwrap();
-            for conn in conns.iter_mut() {
-                {
-                    let conn = conn.lock().unwrap();
-                    let mut jobs_tx_by_func = conn.jobs_tx_by_func.lock().unwrap();
-                    let mut k = Vec::with_capacity(function.len());
-                    k.extend_from_slice(function.as_bytes());
-                    // Same tx for all jobs, the jobs themselves will have a response conn ref
-                    jobs_tx_by_func.entry(k).or_insert(tx.clone());
-                }
-                let mut payload = BytesMut::with_capacity(function.len());
-                payload.extend(function.bytes());
-                let can_do = new_req(CAN_DO, payload.freeze());
-                send_packet(conn.clone(), can_do).await?;
-            }
-        }
+        let mut rx = self.register(function.as_bytes()).await?;
         runtime::Handle::current().spawn(async move {
             while let Some(mut job) = rx.recv().await {
-                match func(&mut job) {
-                    Err(_) => {
-                        if let Err(e) = job.work_fail().await {
-                            warn!("Failed to send WORK_FAIL {}", e);
-                        }
-                    }
-                    Ok(response) => {
-                        if let Err(e) = job.work_complete(response).await {
-                            warn!("Failed to send WORK_COMPLETE {}", e);
-                        }
-                    }
-                }
+                let result = func(&mut job).map_err(WorkError::from);
+                job.finish(result).await;
             }
         });
         Ok(self)
     }
 
+    /// Sends a CAN_DO for `function` on every connection, returning where its jobs arrive
+    pub(crate) async fn register(&self, function: &[u8]) -> Result<Receiver<WorkerJob>, io::Error> {
+        let (tx, rx) = channel(100); // Some day we'll use this param right
+        let conns = self.conns.lock().unwrap().clone();
+        for conn in conns.iter() {
+            {
+                let conn = conn.lock().unwrap();
+                let mut jobs_tx_by_func = conn.jobs_tx_by_func.lock().unwrap();
+                // Same tx for all jobs, the jobs themselves will have a response conn ref
+                jobs_tx_by_func.entry(function.to_vec()).or_insert(tx.clone());
+            }
+            let can_do = new_req(CAN_DO, Bytes::copy_from_slice(function));
+            send_packet(conn.clone(), can_do).await?;
+        }
+        Ok(rx)
+    }
+
     /// Run the assigned jobs through can_do functions until an error happens
     ///
     /// After you have set up all functions your worker can do via the
@@ -514,43 +1273,32 @@ impl Client {
             let job = self.worker_job_rx.try_recv();
             let job = match job {
                 Err(TryRecvError::Empty) => {
-                    let conns = self.conns.lock().unwrap();
+                    let conns = self.conns.lock().unwrap().clone();
                     for conn in conns.iter() {
                         let packet = new_req(GRAB_JOB, Bytes::new());
                         send_packet(conn.clone(), packet).await?;
                     }
                     match self.worker_job_rx.recv().await {
                         Some(job) => job,
-                        None => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::Other,
-                                "Worker job tx are all dropped",
-                            ))
-                        }
+                        None => return Err(io::Error::other("Worker job tx are all dropped")),
                     }
                 }
                 Err(TryRecvError::Disconnected) => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "Worker job tx are all dropped",
-                    ))
+                    return Err(io::Error::other("Worker job tx are all dropped"))
                 }
                 Ok(job) => job,
             };
-            let mut jobs_tx_by_func = self.jobs_tx_by_func.lock().unwrap();
-            let tx = match jobs_tx_by_func.get_mut(job.function()) {
+            let tx = self.jobs_tx_by_func.lock().unwrap().get(job.function()).cloned();
+            let tx = match tx {
                 None => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!(
-                            "Received job for unregistered function: {:?}",
-                            job.function()
-                        ),
-                    ))
+                    return Err(io::Error::other(format!(
+                        "Received job for unregistered function: {:?}",
+                        job.function()
+                    )))
                 }
                 Some(tx) => tx,
             };
-            if let Err(_) = tx.send(job).await {
+            if tx.send(job).await.is_err() {
                 warn!("Ignored a job for an unregistered function"); // XXX We can do much, much better
             }
         }
@@ -563,28 +1311,90 @@ impl Client {
     }
 }
 
+/// Blocking wrapper around [Client] for code that isn't async
+///
+/// A `SyncClient` owns a private tokio runtime and blocks the calling thread
+/// on it for every call. Because of that it must not be used from within
+/// another tokio runtime; async code should use [Client] directly.
+pub struct SyncClient {
+    runtime: runtime::Runtime,
+    client: Client,
+}
+
+impl SyncClient {
+    /// Starts a runtime and connects a [Client] to each of `servers`
+    pub fn connect(servers: &[&str]) -> Result<SyncClient, Box<dyn std::error::Error>> {
+        let runtime = runtime::Runtime::new()?;
+        let mut client = Client::new();
+        for server in servers {
+            client = client.add_server(server);
+        }
+        let client = runtime.block_on(client.connect())?;
+        Ok(SyncClient { runtime, client })
+    }
+
+    /// Submits a foreground job and blocks until it completes, returning its payload
+    ///
+    /// WORK_DATA, WORK_WARNING and WORK_STATUS updates are skipped. WORK_FAIL and
+    /// WORK_EXCEPTION are returned as errors.
+    pub fn submit(&mut self, function: &str, payload: &[u8]) -> Result<Vec<u8>, io::Error> {
+        let client = &mut self.client;
+        self.runtime.block_on(async move {
+            let mut job = client.submit(function, payload).await?;
+            loop {
+                match job.response().await? {
+                    WorkUpdate::Complete { payload, .. } => return Ok(payload.to_vec()),
+                    WorkUpdate::Fail(handle) => {
+                        return Err(io::Error::other(format!("Job failed: {:?}", handle)))
+                    }
+                    WorkUpdate::Exception { payload, .. } => {
+                        return Err(io::Error::other(format!(
+                            "Job exception: {}",
+                            String::from_utf8_lossy(&payload)
+                        )))
+                    }
+                    update => trace!("Ignoring {:?}", update),
+                }
+            }
+        })
+    }
+
+    /// Submits a background job and blocks until the server returns its handle
+    pub fn submit_background(&mut self, function: &str, payload: &[u8]) -> Result<JobHandle, io::Error> {
+        let client = &mut self.client;
+        self.runtime.block_on(async move {
+            let job = client.submit_background(function, payload).await?;
+            Ok(job.handle().clone())
+        })
+    }
+}
+
 impl ClientHandler {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         client_id: &Option<Bytes>,
-        senders_by_handle: Arc<Mutex<HashMap<Bytes, Sender<WorkUpdate>>>>,
+        senders_by_handle: SendersByHandle,
         jobs_tx_by_func: Arc<Mutex<HashMap<Vec<u8>, Sender<WorkerJob>>>>,
         echo_tx: Sender<Bytes>,
         sink_tx: Sender<Packet>,
-        job_created_tx: Sender<Bytes>,
-        status_res_tx: Sender<JobStatus>,
         error_tx: Sender<(Bytes, Bytes)>,
         worker_job_tx: Sender<WorkerJob>,
+        keepalive_tx: Sender<Bytes>,
+        outstanding: Arc<Mutex<HashMap<JobHandle, Outstanding>>>,
     ) -> ClientHandler {
         ClientHandler {
             client_id: client_id.clone(),
-            senders_by_handle: senders_by_handle,
-            jobs_tx_by_func: jobs_tx_by_func,
-            echo_tx: echo_tx,
-            sink_tx: sink_tx,
-            job_created_tx: job_created_tx,
-            status_res_tx: status_res_tx,
-            error_tx: error_tx,
-            worker_job_tx: worker_job_tx,
+            senders_by_handle,
+            jobs_tx_by_func,
+            echo_tx,
+            sink_tx,
+            error_tx,
+            worker_job_tx,
+            keepalive_tx,
+            outstanding,
+            resubmitted: VecDeque::new(),
+            created: VecDeque::new(),
+            statuses: VecDeque::new(),
         }
     }
 
@@ -606,25 +1416,31 @@ impl ClientHandler {
             //JOB_ASSIGN_ALL => self.handle_job_assign_all(&req),
             _ => {
                 error!("Unimplemented: {:?} processing packet", req);
-                Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Invalid packet type {}", req.ptype),
-                ))
+                Err(io::Error::other(format!("Invalid packet type {}", req.ptype)))
             }
         }
     }
 
+    /// A sleeping worker was woken because a job it can do was queued, so grab it
     fn handle_noop(&self) -> Result<Packet, io::Error> {
         Ok(new_req(GRAB_JOB, Bytes::new()))
     }
 
+    /// Nothing to grab, so sleep until the server sends NOOP
+    ///
+    /// Together with [ClientHandler::handle_noop] this keeps an idle worker
+    /// cycling PRE_SLEEP, NOOP, GRAB_JOB, then NO_JOB or JOB_ASSIGN.
     fn handle_no_job(&self) -> Result<Packet, io::Error> {
         Ok(new_req(PRE_SLEEP, Bytes::new()))
     }
 
     fn handle_echo_res(&mut self, req: &Packet) -> Result<Packet, io::Error> {
         info!("Echo response received: {:?}", req.data);
-        let mut tx = self.echo_tx.clone();
+        let tx = if &req.data[..] == KEEPALIVE_TOKEN {
+            self.keepalive_tx.clone()
+        } else {
+            self.echo_tx.clone()
+        };
         let data = req.data.clone();
         runtime::Handle::current().spawn(async move { tx.send(data).await });
         Ok(no_response())
@@ -632,45 +1448,91 @@ impl ClientHandler {
 
     fn handle_job_created(&mut self, req: &Packet) -> Result<Packet, io::Error> {
         info!("Job Created: {:?}", req);
-        let mut tx = self.job_created_tx.clone();
-        let handle = req.data.clone();
-        runtime::Handle::current().spawn(async move { tx.send(handle).await });
+        if let Some(job) = self.resubmitted.pop_front() {
+            return self.handle_resubmit_created(job, JobHandle::from(req.data.clone()));
+        }
+        match self.created.pop_front() {
+            // A submitter that gave up has dropped its receiver, the handle goes nowhere
+            Some(tx) => {
+                let _ = tx.send(Ok(JobHandle::from(req.data.clone())));
+            }
+            None => warn!("JOB_CREATED for no submission: {:?}", req),
+        }
         Ok(no_response())
     }
 
+    /// Moves a resubmitted job over to the handle the server gave it this time
+    fn handle_resubmit_created(&mut self, job: Outstanding, handle: JobHandle) -> Result<Packet, io::Error> {
+        let mut outstanding = self.outstanding.lock().unwrap();
+        let replaced: Vec<JobHandle> = outstanding
+            .iter()
+            .filter(|(_, other)| other.offset == job.offset && other.unique == job.unique)
+            .map(|(old, _)| old.clone())
+            .collect();
+        let mut senders_by_handle = self.senders_by_handle.lock().unwrap();
+        // Updates for the old handle, should its server come back, no longer reach the caller
+        for old in replaced {
+            outstanding.remove(&old);
+            senders_by_handle.remove(&old);
+        }
+        senders_by_handle.insert(handle.clone(), (job.offset, job.response_tx.clone()));
+        outstanding.insert(handle, job);
+        Ok(no_response())
+    }
+
+    /// Fails the oldest submission still waiting for JOB_CREATED if the
+    /// server refused it, or else passes the error on to [Client::error]
+    ///
+    /// Only the codes in [SUBMISSION_ERRORS] answer a submission, others such
+    /// as a refused OPTION_REQ answer something else sent on the connection.
     fn handle_error(&mut self, req: &Packet) -> Result<Packet, io::Error> {
         let mut data = req.data.clone();
         let code = next_field(&mut data);
         let text = next_field(&mut data);
-        let mut tx = self.error_tx.clone();
+        if SUBMISSION_ERRORS.contains(&&code[..]) {
+            if let Some(job) = self.resubmitted.pop_front() {
+                warn!(
+                    "Resubmission of {:?} refused: {}",
+                    job.unique,
+                    String::from_utf8_lossy(&code)
+                );
+                return Ok(no_response());
+            }
+            if let Some(created_tx) = self.created.pop_front() {
+                let _ = created_tx.send(Err(io::Error::other(format!(
+                    "Submission refused: {} {}",
+                    String::from_utf8_lossy(&code),
+                    String::from_utf8_lossy(&text)
+                ))));
+                return Ok(no_response());
+            }
+        }
+        let tx = self.error_tx.clone();
         runtime::Handle::current().spawn(async move { tx.send((code, text)).await });
         Ok(no_response())
     }
 
     fn handle_status_res(&mut self, req: &Packet) -> Result<Packet, io::Error> {
-        let mut req = req.clone();
+        // Answered even if the reply is garbage, so later ones go to the right request
+        let status_tx = self.statuses.pop_front();
+        let mut data = req.data.clone();
         let mut js = JobStatus {
-            handle: next_field(&mut req.data),
-            known: bytes2bool(&next_field(&mut req.data)),
-            running: bytes2bool(&next_field(&mut req.data)),
-            numerator: String::from_utf8(next_field(&mut req.data).to_vec())
-                .unwrap()
-                .parse()
-                .unwrap(), // XXX we can do better
-            denominator: String::from_utf8(next_field(&mut req.data).to_vec())
-                .unwrap()
-                .parse()
-                .unwrap(),
+            handle: JobHandle::from(next_field(&mut data)),
+            known: bytes2bool(&next_field(&mut data)),
+            running: bytes2bool(&next_field(&mut data)),
+            numerator: parse_number(req, &next_field(&mut data))?,
+            denominator: parse_number(req, &next_field(&mut data))?,
             waiting: 0,
         };
         if req.ptype == STATUS_RES_UNIQUE {
-            js.waiting = String::from_utf8(next_field(&mut req.data).to_vec())
-                .unwrap()
-                .parse()
-                .unwrap();
+            js.waiting = parse_number(req, &next_field(&mut data))?;
+        }
+        match status_tx {
+            Some(tx) => {
+                let _ = tx.send(js);
+            }
+            None => warn!("STATUS_RES for no request: {:?}", js),
         }
-        let mut tx = self.status_res_tx.clone();
-        runtime::Handle::current().spawn(async move { tx.send(js).await });
         Ok(no_response())
     }
 
@@ -685,49 +1547,51 @@ impl ClientHandler {
 
     fn handle_work_update(&mut self, req: &Packet) -> Result<Packet, io::Error> {
         let mut data = req.data.clone();
-        let handle = next_field(&mut data);
+        let handle = JobHandle::from(next_field(&mut data));
         let payload = next_field(&mut data);
         let work_update = {
             let handle = handle.clone();
             match req.ptype {
                 WORK_DATA => WorkUpdate::Data {
-                    handle: handle,
-                    payload: payload,
+                    handle,
+                    payload,
                 },
                 WORK_COMPLETE => WorkUpdate::Complete {
-                    handle: handle,
-                    payload: payload,
+                    handle,
+                    payload,
                 },
                 WORK_WARNING => WorkUpdate::Warning {
-                    handle: handle,
-                    payload: payload,
+                    handle,
+                    payload,
                 },
                 WORK_EXCEPTION => WorkUpdate::Exception {
-                    handle: handle,
-                    payload: payload,
+                    handle,
+                    payload,
                 },
                 WORK_FAIL => WorkUpdate::Fail(handle),
                 WORK_STATUS => {
-                    let numerator: usize = String::from_utf8((&payload).to_vec())
-                        .unwrap()
-                        .parse()
-                        .unwrap();
-                    let denominator: usize = String::from_utf8(next_field(&mut data).to_vec())
-                        .unwrap()
-                        .parse()
-                        .unwrap();
+                    let numerator = parse_number(req, &payload)?;
+                    let denominator = parse_number(req, &next_field(&mut data))?;
                     WorkUpdate::Status {
-                        handle: handle,
-                        numerator: numerator,
-                        denominator: denominator,
+                        handle,
+                        numerator,
+                        denominator,
                     }
                 }
                 _ => unreachable!("handle_work_status called with wrong ptype: {:?}", req),
             }
         };
-        let senders_by_handle = self.senders_by_handle.lock().unwrap();
-        if let Some(tx) = senders_by_handle.get(&handle) {
-            let mut tx = tx.clone();
+        let finished = matches!(req.ptype, WORK_COMPLETE | WORK_FAIL | WORK_EXCEPTION);
+        if finished {
+            self.outstanding.lock().unwrap().remove(&handle);
+        }
+        let mut senders_by_handle = self.senders_by_handle.lock().unwrap();
+        // Nothing follows a job's last update, so its sender goes with it
+        let tx = match finished {
+            true => senders_by_handle.remove(&handle),
+            false => senders_by_handle.get(&handle).cloned(),
+        };
+        if let Some((_, tx)) = tx {
             runtime::Handle::current().spawn(async move { tx.send(work_update).await });
         } else {
             error!("Received work for unknown job: {:?}", handle);
@@ -737,17 +1601,55 @@ impl ClientHandler {
 
     fn handle_job_assign(&mut self, req: &Packet) -> Result<Packet, io::Error> {
         let mut data = req.data.clone();
-        let handle = next_field(&mut data);
+        let handle = JobHandle::from(next_field(&mut data));
         let function = next_field(&mut data);
         let payload = next_field(&mut data);
         let job = WorkerJob {
-            handle: handle,
-            function: function,
-            payload: payload,
+            handle,
+            function,
+            payload,
             sink_tx: self.sink_tx.clone(),
         };
-        let mut tx = self.worker_job_tx.clone();
+        let tx = self.worker_job_tx.clone();
         runtime::Handle::current().spawn(async move { tx.send(job).await });
         Ok(no_response())
     }
 }
+
+/// Fuzzing entry point: feeds `packets` to a fresh connection's handler as if
+/// a server had sent them, with a submission and a GET_STATUS waiting for replies
+///
+/// Arbitrary packets must only ever produce an error, never a panic. The
+/// handler stops at the first error, as a connection would.
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub fn handle_packets(packets: Vec<Packet>) {
+    static RUNTIME: std::sync::OnceLock<runtime::Runtime> = std::sync::OnceLock::new();
+    let runtime = RUNTIME.get_or_init(|| {
+        runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap()
+    });
+    let _guard = runtime.enter();
+    // Receivers are dropped, so whatever the handler passes on is discarded
+    let mut handler = ClientHandler::new(
+        &None,
+        Arc::new(Mutex::new(HashMap::new())),
+        Arc::new(Mutex::new(HashMap::new())),
+        channel(1).0,
+        channel(1).0,
+        channel(1).0,
+        channel(1).0,
+        channel(1).0,
+        Arc::new(Mutex::new(HashMap::new())),
+    );
+    handler.created.push_back(oneshot::channel().0);
+    handler.statuses.push_back(oneshot::channel().0);
+    for packet in packets {
+        if handler.call(packet).is_err() {
+            break;
+        }
+    }
+}
@@ -21,6 +21,66 @@ use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
 
 use crate::constants::*;
+#[cfg(feature = "fuzz")]
+use crate::util::decode_metadata;
+use crate::util::psize;
+
+/// Length of the REQ and RES magic that starts every binary packet
+const MAGIC_LEN: usize = REQ.len();
+
+// Both magics must be the same length for the header to have one layout
+const _: () = assert!(REQ.len() == RES.len());
+
+/// Magic, then the type and the data size as big endian u32s
+pub const HEADER_LEN: usize = MAGIC_LEN + 4 + 4;
+
+/// Read buffers left with more spare capacity than this after a packet are released
+const MAX_IDLE_READ_CAPACITY: usize = 64 * 1024;
+
+/// Default cap on an admin command line, see [PacketCodec::with_max_admin_line]
+pub const DEFAULT_MAX_ADMIN_LINE: usize = 4096;
+
+/// Default cap on a binary packet's data, see [PacketCodec::with_max_packet_size]
+pub const DEFAULT_MAX_PACKET_SIZE: usize = 256 * 1024 * 1024;
+
+/// Fields longer than this are cut short in a packet's Debug output
+const DEBUG_FIELD_PREVIEW: usize = 32;
+
+/// Names a binary packet type for messages, e.g. `SUBMIT_JOB (7)`, or just the number if unknown
+pub fn describe_ptype(ptype: u32) -> String {
+    match PTYPES.get(ptype as usize) {
+        Some(p) if p.ptype == ptype => format!("{} ({})", p.name, ptype),
+        _ => format!("ptype {}", ptype),
+    }
+}
+
+/// A packet's data has fewer null terminated arguments than its type declares
+#[derive(Debug, PartialEq)]
+pub struct TooFewFields {
+    pub ptype: u32,
+    pub expected: usize,
+    pub found: usize,
+}
+
+impl fmt::Display for TooFewFields {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} needs {} fields, found {}",
+            describe_ptype(self.ptype),
+            self.expected,
+            self.found
+        )
+    }
+}
+
+impl std::error::Error for TooFewFields {}
+
+impl From<TooFewFields> for io::Error {
+    fn from(e: TooFewFields) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum PacketMagic {
@@ -50,32 +110,143 @@ impl Clone for Packet {
 
 impl fmt::Debug for Packet {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let unimpl = format!("__UNIMPLEMENTED__({})", self.ptype);
-        let ptype_str = match self.ptype {
+        write!(
+            f,
+            "Packet {{ magic: {:?}, ptype: {}, size: {}",
+            self.magic_name(),
+            self.ptype_name(),
+            self.psize
+        )?;
+        for (i, field) in self.fields().iter().enumerate() {
+            let preview = &field[..field.len().min(DEBUG_FIELD_PREVIEW)];
+            write!(f, ", field[{}]={:?}", i, String::from_utf8_lossy(preview))?;
+            if field.len() > DEBUG_FIELD_PREVIEW {
+                write!(f, "...(+{})", field.len() - DEBUG_FIELD_PREVIEW)?;
+            }
+        }
+        write!(f, " }}")
+    }
+}
+
+/// Read-only view of a decoded packet for logs and test fixtures
+///
+/// This is not the wire format. `fields` holds each NUL separated argument
+/// the packet type declares, followed by the remaining data.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PacketView {
+    pub magic: String,
+    pub ptype: String,
+    #[cfg_attr(feature = "serde", serde(with = "base64_fields"))]
+    pub fields: Vec<Vec<u8>>,
+}
+
+/// Fields as base64 strings, so binary data survives JSON
+#[cfg(feature = "serde")]
+mod base64_fields {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(fields: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(fields.iter().map(|field| STANDARD.encode(field)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|field| STANDARD.decode(field).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+/// Serializes as its [PacketView], there is no way back to a packet
+#[cfg(feature = "serde")]
+impl serde::Serialize for Packet {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.view().serialize(serializer)
+    }
+}
+
+impl Packet {
+    fn magic_name(&self) -> &'static str {
+        match self.magic {
+            PacketMagic::REQ => "REQ",
+            PacketMagic::RES => "RES",
+            PacketMagic::TEXT => "TEXT",
+            _ => "UNKNOWN",
+        }
+    }
+
+    /// The protocol name of this packet's type, e.g. "SUBMIT_JOB"
+    pub fn ptype_name(&self) -> String {
+        let name = match self.ptype {
             p @ 0..=42 => PTYPES[p as usize].name,
             _p @ ADMIN_STATUS => "ADMIN_STATUS",
             _p @ ADMIN_VERSION => "ADMIN_VERSION",
             _p @ ADMIN_UNKNOWN => "ADMIN_UNKNOWN",
             _p @ ADMIN_RESPONSE => "ADMIN_RESPONSE",
             _p @ ADMIN_WORKERS => "ADMIN_WORKERS",
-            _ => &unimpl,
+            _p @ ADMIN_SHUTDOWN => "ADMIN_SHUTDOWN",
+            _p @ ADMIN_SHUTDOWN_GRACEFUL => "ADMIN_SHUTDOWN_GRACEFUL",
+            _p @ ADMIN_DRAIN => "ADMIN_DRAIN",
+            _p @ ADMIN_PING => "ADMIN_PING",
+            _p @ ADMIN_CANCEL_JOB => "ADMIN_CANCEL_JOB",
+            _p @ ADMIN_FUNCTION_STATS => "ADMIN_FUNCTION_STATS",
+            _p @ ADMIN_GETPID => "ADMIN_GETPID",
+            _p @ ADMIN_ID => "ADMIN_ID",
+            _p @ ADMIN_SHOW_JOBS => "ADMIN_SHOW_JOBS",
+            _p @ ADMIN_KILL_IDLE => "ADMIN_KILL_IDLE",
+            _p @ ADMIN_DROP_FUNCTION => "ADMIN_DROP_FUNCTION",
+            _p @ ADMIN_SHOW_UNIQUE => "ADMIN_SHOW_UNIQUE",
+            _ => return format!("__UNIMPLEMENTED__({})", self.ptype),
         };
-        write!(
-            f,
-            "Packet {{ magic: {:?}, ptype: {}, size: {} }}",
-            match self.magic {
-                PacketMagic::REQ => "REQ",
-                PacketMagic::RES => "RES",
-                PacketMagic::TEXT => "TEXT",
-                _ => "UNKNOWN",
-            },
-            ptype_str,
-            self.psize
-        )
+        name.to_string()
+    }
+
+    /// Null terminated arguments the packet type declares ahead of the rest of the data
+    fn nargs(&self) -> usize {
+        match self.ptype {
+            p @ 0..=42 => PTYPES[p as usize].nargs.max(0) as usize,
+            _ => 0,
+        }
+    }
+
+    /// Slices of `data` for each argument its packet type declares, plus the rest
+    ///
+    /// Data with fewer nulls than that just gives fewer slices, see
+    /// [Packet::checked_fields] to have that be an error.
+    pub fn fields(&self) -> Vec<&[u8]> {
+        if self.data.is_empty() {
+            return Vec::new();
+        }
+        self.data.splitn(self.nargs() + 1, |b| *b == b'\0').collect()
+    }
+
+    /// Like [Packet::fields], but fails unless every declared argument is there
+    pub fn checked_fields(&self) -> Result<Vec<&[u8]>, TooFewFields> {
+        let fields = self.fields();
+        let expected = self.nargs();
+        let found = fields.len().saturating_sub(1);
+        if found < expected {
+            return Err(TooFewFields {
+                ptype: self.ptype,
+                expected,
+                found,
+            });
+        }
+        Ok(fields)
+    }
+
+    pub fn view(&self) -> PacketView {
+        PacketView {
+            magic: self.magic_name().to_string(),
+            ptype: self.ptype_name(),
+            fields: self.fields().iter().map(|field| field.to_vec()).collect(),
+        }
     }
-}
 
-impl Packet {
     pub fn admin_decode(buf: &mut BytesMut) -> Result<Option<Packet>, io::Error> {
         let newline = buf[..].iter().position(|b| *b == b'\n');
         if let Some(n) = newline {
@@ -83,21 +254,42 @@ impl Packet {
             let _ = buf.split_to(1); // drop the newline itself
             let data_str = match str::from_utf8(&line[..]) {
                 Ok(s) => s,
-                Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "invalid string")),
+                Err(_) => return Err(io::Error::other("invalid string, admin command is not UTF-8")),
             };
             let trimmed = data_str.trim();
             info!("admin command data: {:?}", trimmed);
-            let command = match data_str.trim() {
-                "version" => ADMIN_VERSION,
-                "status" => ADMIN_STATUS,
-                "workers" => ADMIN_WORKERS,
+            // The verb is case-insensitive, its arguments are passed on as data
+            let mut words = trimmed.split_whitespace();
+            let verb = words.next().unwrap_or("").to_lowercase();
+            let args: Vec<&str> = words.collect();
+            let command = match (verb.as_str(), &args[..]) {
+                ("version", _) => ADMIN_VERSION,
+                ("status", _) => ADMIN_STATUS,
+                ("workers", _) => ADMIN_WORKERS,
+                ("shutdown", []) => ADMIN_SHUTDOWN,
+                ("shutdown", ["graceful"]) => ADMIN_SHUTDOWN_GRACEFUL,
+                ("drain", []) | ("drain", ["on"]) | ("drain", ["off"]) => ADMIN_DRAIN,
+                ("ping", []) => ADMIN_PING,
+                ("cancel", ["job", _]) => ADMIN_CANCEL_JOB,
+                ("functionstats", []) => ADMIN_FUNCTION_STATS,
+                ("getpid", []) => ADMIN_GETPID,
+                ("id", []) => ADMIN_ID,
+                ("showjobs", [_]) => ADMIN_SHOW_JOBS,
+                ("killidle", [_]) => ADMIN_KILL_IDLE,
+                ("dropfunction", [_]) => ADMIN_DROP_FUNCTION,
+                ("showunique", []) | ("show", ["unique", "jobs"]) => ADMIN_SHOW_UNIQUE,
                 _ => ADMIN_UNKNOWN,
             };
+            let data = match command {
+                // Only the handle, so it can be looked up as is
+                ADMIN_CANCEL_JOB => Bytes::from(args[1].to_string()),
+                _ => Bytes::from(args.join(" ")),
+            };
             return Ok(Some(Packet {
                 magic: PacketMagic::TEXT,
                 ptype: command,
-                psize: 0,
-                data: Bytes::new(),
+                psize: psize(data.len()),
+                data,
             }));
         }
         Ok(None) // Wait for more data
@@ -110,24 +302,78 @@ impl Packet {
             PacketMagic::RES => RES,
             PacketMagic::TEXT => return (Bytes::from_static(b""), self.data),
         };
-        let mut buf = BytesMut::with_capacity(12);
+        let mut buf = BytesMut::with_capacity(HEADER_LEN);
         buf.extend(magic.iter());
         buf.put_u32(self.ptype);
         buf.put_u32(self.psize);
         (buf.freeze(), self.data)
     }
 
+    /// Builds a REQ packet from its fields, the inverse of [Packet::fields]
+    ///
+    /// Mostly useful for building fixtures in tests.
+    pub fn from_fields(ptype: u32, fields: &[&[u8]]) -> Packet {
+        let data = Bytes::from(fields.join(&b'\0'));
+        Packet {
+            magic: PacketMagic::REQ,
+            ptype,
+            psize: psize(data.len()),
+            data,
+        }
+    }
+
     pub fn new_text_res(body: Bytes) -> Packet {
         Packet {
             magic: PacketMagic::TEXT,
             ptype: ADMIN_RESPONSE,
-            psize: body.len() as u32,
+            psize: psize(body.len()),
             data: body,
         }
     }
 }
 
-pub struct PacketCodec;
+/// Frames the gearman binary and admin text protocols
+///
+/// Binary packets are only emitted once the header and the entire data
+/// section are buffered, so a body that arrives over several reads is
+/// reassembled here and consumers always see complete `data`.
+///
+/// Data sections are always passed through as they are. Compression isn't
+/// supported, and rustygeard refuses the `compress=gzip` option.
+pub struct PacketCodec {
+    max_admin_line: usize,
+    max_packet_size: usize,
+}
+
+impl Default for PacketCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PacketCodec {
+    pub fn new() -> PacketCodec {
+        PacketCodec {
+            max_admin_line: DEFAULT_MAX_ADMIN_LINE,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+        }
+    }
+
+    /// Fails decoding once this many bytes of an admin command arrive without a newline
+    pub fn with_max_admin_line(mut self, max_admin_line: usize) -> PacketCodec {
+        self.max_admin_line = max_admin_line;
+        self
+    }
+
+    /// Fails decoding a binary packet whose header declares more data than this
+    ///
+    /// The buffer is grown to a packet's full size as soon as its header
+    /// arrives, so without a cap any peer can make us allocate 4GiB.
+    pub fn with_max_packet_size(mut self, max_packet_size: usize) -> PacketCodec {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+}
 
 impl Decoder for PacketCodec {
     type Item = Packet;
@@ -135,55 +381,137 @@ impl Decoder for PacketCodec {
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
         debug!("Decoding {:?}", src);
-        // Peek at first 4
-        // Is this a req/res
-        if src.len() < 4 {
+        // Nothing is consumed until a whole packet is buffered, so returning
+        // Ok(None) at any point below is safe to retry after the next read.
+        match src.first() {
+            None => return Ok(None),
+            // Binary magic always starts with a NUL, admin commands never do
+            Some(0) => {}
+            Some(_) => {
+                debug!("admin protocol detected");
+                let decoded = Packet::admin_decode(src)?;
+                if decoded.is_none() && src.len() > self.max_admin_line {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("admin line exceeds {} bytes", self.max_admin_line),
+                    ));
+                }
+                return Ok(decoded);
+            }
+        }
+        if src.len() < HEADER_LEN {
             return Ok(None);
         }
-        let mut magic_buf: [u8; 4] = [0; 4];
-        magic_buf.clone_from_slice(&src[0..4]);
-        let magic = match magic_buf {
-            REQ => PacketMagic::REQ,
-            RES => PacketMagic::RES,
-            // TEXT/ADMIN protocol
-            _ => PacketMagic::TEXT,
+        trace!("Buf is >= {} bytes ({}) -- check header", HEADER_LEN, src.len());
+        // The type is read first so errors about the rest of the header can name it
+        let ptype = (&src[MAGIC_LEN..MAGIC_LEN + 4]).get_u32();
+        let magic = match &src[..MAGIC_LEN] {
+            m if m == REQ => PacketMagic::REQ,
+            m if m == RES => PacketMagic::RES,
+            m => {
+                return Err(io::Error::other(format!(
+                    "invalid packet magic {:?} in {} header",
+                    m,
+                    describe_ptype(ptype)
+                )))
+            }
         };
         debug!("Magic is {:?}", magic);
-        if magic == PacketMagic::TEXT {
-            debug!("admin protocol detected");
-            return Packet::admin_decode(src);
-        }
-        if src.len() < 12 {
-            return Ok(None);
-        }
-        trace!("Buf is >= 12 bytes ({}) -- check header", src.len());
-        let header = &src.clone()[0..12];
-        // Now get the type
-        let ptype = (&header[4..8]).get_u32();
-        debug!("We got a {}", &PTYPES[ptype as usize].name);
+        debug!("We got a {}", describe_ptype(ptype));
         // Now the length
-        let psize = (&header[8..12]).get_u32();
+        let psize = (&src[MAGIC_LEN + 4..HEADER_LEN]).get_u32();
         debug!("Data section is {} bytes", psize);
-        let packet_len = 12 + psize as usize;
+        if psize as usize > self.max_packet_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} declares {} bytes of data, more than the {} allowed",
+                    describe_ptype(ptype),
+                    psize,
+                    self.max_packet_size
+                ),
+            ));
+        }
+        // Packets without data, e.g. NOOP or GRAB_JOB, are complete with the header
+        let packet_len = HEADER_LEN + psize as usize;
         if src.len() < packet_len {
+            // Grow once to exactly the size of this packet
+            src.reserve(packet_len - src.len());
             return Ok(None);
         }
-        let _ = src.split_to(12);
+        let _ = src.split_to(HEADER_LEN);
+        let data = src.split_to(psize as usize).freeze();
+        // The spare capacity shares an allocation with data, so after a big
+        // packet let it go rather than pin it for the life of the connection
+        if src.is_empty() && src.capacity() > MAX_IDLE_READ_CAPACITY {
+            *src = BytesMut::new();
+        }
         Ok(Some(Packet {
-            magic: magic,
-            ptype: ptype,
-            psize: psize,
-            data: src.split_to(psize as usize).freeze(),
+            magic,
+            ptype,
+            psize,
+            data,
         }))
     }
+
+    /// Ends the stream quietly when the peer closes partway through a packet
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, io::Error> {
+        match self.decode(src)? {
+            Some(packet) => Ok(Some(packet)),
+            None => {
+                if !src.is_empty() {
+                    warn!("Connection closed with {} bytes of a packet unread", src.len());
+                    src.clear();
+                }
+                Ok(None)
+            }
+        }
+    }
 }
 
 impl Encoder<Packet> for PacketCodec {
     type Error = io::Error;
     fn encode(&mut self, item: Packet, dst: &mut BytesMut) -> Result<(), io::Error> {
+        if item.magic != PacketMagic::TEXT && item.psize as usize != item.data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "psize {} does not match {} bytes of data",
+                    item.psize,
+                    item.data.len()
+                ),
+            ));
+        }
         let allbytes = item.into_bytes();
         dst.extend(allbytes.0);
         dst.extend(allbytes.1);
         Ok(())
     }
 }
+
+/// Fuzzing entry point: decodes `data` as if read from a peer, then takes
+/// apart each packet the way the client, worker and server do
+///
+/// Arbitrary input must only ever produce an error, never a panic. Returns
+/// the packets decoded before the stream ended or failed.
+#[cfg(feature = "fuzz")]
+#[doc(hidden)]
+pub fn decode_packet(data: &[u8]) -> Result<Vec<Packet>, io::Error> {
+    // Small enough that a fuzzed header can't exhaust memory
+    let mut codec = PacketCodec::new().with_max_packet_size(64 * 1024);
+    let mut src = BytesMut::from(data);
+    let mut packets = Vec::new();
+    while let Some(packet) = codec.decode_eof(&mut src)? {
+        let _ = packet.ptype_name();
+        let _ = packet.view();
+        if let Ok(fields) = packet.checked_fields() {
+            let _ = Packet::from_fields(packet.ptype, &fields);
+        }
+        let _ = decode_metadata(packet.data.clone());
+        if packet.magic != PacketMagic::TEXT {
+            codec.encode(packet.clone(), &mut BytesMut::new())?;
+        }
+        packets.push(packet);
+    }
+    Ok(packets)
+}
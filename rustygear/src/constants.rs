@@ -61,9 +61,24 @@ pub const ADMIN_STATUS: u32 = 10001;
 pub const ADMIN_VERSION: u32 = 10002;
 pub const ADMIN_RESPONSE: u32 = 10003;
 pub const ADMIN_WORKERS: u32 = 10004;
+pub const ADMIN_SHUTDOWN: u32 = 10005;
+pub const ADMIN_SHUTDOWN_GRACEFUL: u32 = 10006;
+pub const ADMIN_DRAIN: u32 = 10007;
+pub const ADMIN_PING: u32 = 10008;
+pub const ADMIN_CANCEL_JOB: u32 = 10009;
+pub const ADMIN_FUNCTION_STATS: u32 = 10010;
+pub const ADMIN_GETPID: u32 = 10011;
+pub const ADMIN_ID: u32 = 10012;
+pub const ADMIN_SHOW_JOBS: u32 = 10013;
+pub const ADMIN_KILL_IDLE: u32 = 10014;
+pub const ADMIN_DROP_FUNCTION: u32 = 10015;
+pub const ADMIN_SHOW_UNIQUE: u32 = 10016;
 
-pub const REQ: [u8; 4] = [0x00u8, 'R' as u8, 'E' as u8, 'Q' as u8];
-pub const RES: [u8; 4] = [0x00u8, 'R' as u8, 'E' as u8, 'S' as u8];
+pub const REQ: [u8; 4] = [0x00u8, b'R', b'E', b'Q'];
+pub const RES: [u8; 4] = [0x00u8, b'R', b'E', b'S'];
+
+/// The port gearman servers conventionally listen on
+pub const DEFAULT_PORT: u16 = 4730;
 
 pub const PRIORITY_HIGH: usize = 0;
 pub const PRIORITY_NORMAL: usize = 1;
@@ -15,23 +15,114 @@
  * limitations under the License.
 */
 use std::fmt;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Mutex;
+use std::time::Instant;
 
 use bytes::Bytes;
 
+/// The handle a server gave a job
+///
+/// Kept apart from the function name, unique and data, which are all bytes
+/// too, so they can't be passed in each other's place.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct JobHandle(Bytes);
+
+impl JobHandle {
+    pub fn new(handle: Bytes) -> JobHandle {
+        JobHandle(handle)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The handle as it goes on the wire
+    pub fn to_bytes(&self) -> Bytes {
+        self.0.clone()
+    }
+}
+
+impl From<Bytes> for JobHandle {
+    fn from(handle: Bytes) -> JobHandle {
+        JobHandle(handle)
+    }
+}
+
+impl From<&'static str> for JobHandle {
+    fn from(handle: &'static str) -> JobHandle {
+        JobHandle(Bytes::from(handle))
+    }
+}
+
+impl AsRef<[u8]> for JobHandle {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl PartialEq<Bytes> for JobHandle {
+    fn eq(&self, other: &Bytes) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<JobHandle> for Bytes {
+    fn eq(&self, other: &JobHandle) -> bool {
+        self == &other.0
+    }
+}
+
+impl fmt::Display for JobHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl fmt::Debug for JobHandle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "JobHandle({:?})", String::from_utf8_lossy(&self.0))
+    }
+}
+
 pub struct Job {
-    pub handle: Bytes,
+    pub handle: JobHandle,
     pub fname: Bytes,
     pub unique: Bytes,
     pub data: Bytes,
+    /// Number of times this job has been failed or abandoned by a worker
+    pub attempts: AtomicUsize,
+    /// Function that reduces this job's output, set by SUBMIT_REDUCE_JOB
+    pub reducer: Option<Bytes>,
+    /// Map output collected from WORK_DATA until the reducer is queued
+    pub partials: Mutex<Vec<Bytes>>,
+    /// When the job was submitted
+    pub created_at: Instant,
+    /// A job still queued at this time is dropped, set by the `ttl` option
+    pub expires_at: Option<Instant>,
+    /// Key/value pairs sent ahead of the data by clients with the `metadata` option
+    pub metadata: Vec<(Bytes, Bytes)>,
+    /// Numerator and denominator of the last WORK_STATUS, numerator at most denominator
+    pub status: Mutex<Option<(u64, u64)>>,
+    /// Number of workers currently assigned this job by the server
+    pub assigned: AtomicUsize,
 }
 
 impl Job {
-    pub fn new(fname: Bytes, unique: Bytes, data: Bytes, handle: Bytes) -> Job {
+    pub fn new(fname: Bytes, unique: Bytes, data: Bytes, handle: JobHandle) -> Job {
         Job {
-            handle: handle,
-            fname: fname,
-            unique: unique,
-            data: data,
+            handle,
+            fname,
+            unique,
+            data,
+            attempts: AtomicUsize::new(0),
+            reducer: None,
+            partials: Mutex::new(Vec::new()),
+            created_at: Instant::now(),
+            expires_at: None,
+            metadata: Vec::new(),
+            status: Mutex::new(None),
+            assigned: AtomicUsize::new(0),
         }
     }
 }
@@ -41,7 +132,7 @@ impl fmt::Debug for Job {
         write!(
             f,
             "Job {{ handle: {}, fname: {}, unique: {}, +{} data }}",
-            String::from_utf8_lossy(&self.handle),
+            self.handle,
             String::from_utf8_lossy(&self.fname),
             String::from_utf8_lossy(&self.unique),
             self.data.len()
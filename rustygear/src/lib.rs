@@ -5,3 +5,4 @@ pub mod codec;
 pub mod constants;
 pub mod job;
 pub mod util;
+pub mod worker;
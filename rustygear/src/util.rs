@@ -15,33 +15,44 @@
 */
 use crate::codec::{Packet, PacketMagic};
 use crate::constants::*;
-use bytes::{Buf, Bytes};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::convert::TryFrom;
 
 pub fn bytes2bool(input: &Bytes) -> bool {
-    if input.len() != 1 {
-        false
-    } else if input[0] == b'1' {
-        true
-    } else {
-        false
+    input.len() == 1 && input[0] == b'1'
+}
+
+/// Narrows a data length to a packet psize
+///
+/// Panics rather than truncating when `len` can't fit in one packet, use
+/// [fits_in_packet] first for sizes that come from callers.
+pub fn psize(len: usize) -> u32 {
+    match u32::try_from(len) {
+        Ok(psize) => psize,
+        Err(_) => panic!("{} bytes is too large for a packet", len),
     }
 }
 
+/// Whether `len` bytes of data fit in a single packet
+pub fn fits_in_packet(len: usize) -> bool {
+    u32::try_from(len).is_ok()
+}
+
 pub fn new_res(ptype: u32, data: Bytes) -> Packet {
     Packet {
         magic: PacketMagic::RES,
-        ptype: ptype,
-        psize: data.len() as u32,
-        data: data,
+        ptype,
+        psize: psize(data.len()),
+        data,
     }
 }
 
 pub fn new_req(ptype: u32, data: Bytes) -> Packet {
     Packet {
         magic: PacketMagic::REQ,
-        ptype: ptype,
-        psize: data.len() as u32,
-        data: data,
+        ptype,
+        psize: psize(data.len()),
+        data,
     }
 }
 
@@ -59,6 +70,53 @@ pub fn next_field(buf: &mut Bytes) -> Bytes {
     }
 }
 
+/// Ends the metadata prefix of a job's data, see [encode_metadata]
+pub const METADATA_END: u8 = 0x1e;
+/// Separates metadata pairs, see [encode_metadata]
+pub const METADATA_SEP: u8 = 0x1f;
+
+/// Prefixes job data with `key=value` metadata pairs
+///
+/// Used for submissions and JOB_ASSIGN data on connections that set the
+/// `metadata` option. Pairs are joined by [METADATA_SEP] and the prefix is
+/// closed by [METADATA_END], which is there even when there are no pairs.
+pub fn encode_metadata(metadata: &[(Bytes, Bytes)], data: &[u8]) -> Bytes {
+    let mut encoded = BytesMut::new();
+    for (i, (key, value)) in metadata.iter().enumerate() {
+        if i > 0 {
+            encoded.put_u8(METADATA_SEP);
+        }
+        encoded.extend_from_slice(key);
+        encoded.put_u8(b'=');
+        encoded.extend_from_slice(value);
+    }
+    encoded.put_u8(METADATA_END);
+    encoded.extend_from_slice(data);
+    encoded.freeze()
+}
+
+/// Splits what [encode_metadata] made back into pairs and data
+///
+/// Data without a [METADATA_END] is taken to carry no metadata and is
+/// returned as is. Pairs without an `=` get an empty value.
+pub fn decode_metadata(mut data: Bytes) -> (Vec<(Bytes, Bytes)>, Bytes) {
+    let end = match data.iter().position(|b| *b == METADATA_END) {
+        Some(end) => end,
+        None => return (Vec::new(), data),
+    };
+    let prefix = data.split_to(end);
+    data.advance(1);
+    let metadata = prefix
+        .split(|b| *b == METADATA_SEP)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.iter().position(|b| *b == b'=') {
+            Some(eq) => (prefix.slice_ref(&pair[..eq]), prefix.slice_ref(&pair[eq + 1..])),
+            None => (prefix.slice_ref(pair), Bytes::new()),
+        })
+        .collect();
+    (metadata, data)
+}
+
 pub fn no_response() -> Packet {
     Packet {
         magic: PacketMagic::TEXT,
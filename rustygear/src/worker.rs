@@ -0,0 +1,77 @@
+/*
+ * Copyright 2020 Clint Byrum
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *    http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+*/
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::runtime;
+
+use crate::client::{Client, WorkerJob};
+
+/// How a job went wrong, deciding which packet ends it
+#[derive(Debug, PartialEq)]
+pub enum WorkError {
+    /// Sent as WORK_FAIL, without any detail
+    Fail,
+    /// Sent as WORK_EXCEPTION with the detail, clients that didn't set the
+    /// `exceptions` option get WORK_FAIL from the server instead
+    Exception(Vec<u8>),
+}
+
+/// Errors from the connection, e.g. sending WORK_STATUS, fail the job
+impl From<io::Error> for WorkError {
+    fn from(_: io::Error) -> WorkError {
+        WorkError::Fail
+    }
+}
+
+/// What [GearmanWorker::handle] returns, boxed so the trait stays object safe
+pub type WorkFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>, WorkError>> + Send + 'a>>;
+
+/// A worker described by the functions it can do and how it does them
+///
+/// Hand one to [run_worker], which registers every function and sends
+/// WORK_COMPLETE, WORK_FAIL or WORK_EXCEPTION for each job. See examples/trait_worker.rs.
+pub trait GearmanWorker: Send + Sync + 'static {
+    /// Names sent to the server with CAN_DO
+    fn functions(&self) -> Vec<Vec<u8>>;
+
+    /// Does one job, the response is sent with WORK_COMPLETE, an error as
+    /// WORK_FAIL or WORK_EXCEPTION, see [WorkError]
+    ///
+    /// `job` may be used to send WORK_STATUS while the job runs.
+    fn handle<'a>(&'a self, job: &'a mut WorkerJob) -> WorkFuture<'a>;
+}
+
+/// Connects to the server at `addr` and works jobs with `worker` until an error happens
+pub async fn run_worker<W: GearmanWorker>(addr: &str, worker: W) -> Result<(), Box<dyn Error>> {
+    let client = Client::new().add_server(addr).connect().await?;
+    let worker = Arc::new(worker);
+    for function in worker.functions() {
+        let mut rx = client.register(&function).await?;
+        let worker = worker.clone();
+        runtime::Handle::current().spawn(async move {
+            while let Some(mut job) = rx.recv().await {
+                let result = worker.handle(&mut job).await;
+                job.finish(result).await;
+            }
+        });
+    }
+    client.work().await?;
+    Ok(())
+}
@@ -0,0 +1,640 @@
+extern crate rustygear;
+
+use std::env;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::time::{sleep, timeout};
+use tokio_util::codec::Decoder;
+
+use rustygear::client::{Client, JobEvent, SyncClient, WorkUpdate};
+use rustygear::codec::PacketCodec;
+use rustygear::constants::*;
+use rustygear::job::JobHandle;
+use rustygear::util::new_res;
+
+#[test]
+fn same_unique_routes_to_same_server() {
+    let mut client = Client::new()
+        .add_server("127.0.0.1:4730")
+        .add_server("127.0.0.1:4731")
+        .add_server("127.0.0.1:4732");
+    let first = client.server_for_unique("unique-1").unwrap().to_string();
+    assert_eq!(first, client.server_for_unique("unique-1").unwrap());
+    let routed_elsewhere = (0..100)
+        .map(|i| format!("unique-{}", i))
+        .any(|unique| client.server_for_unique(&unique).unwrap() != first);
+    assert!(routed_elsewhere);
+}
+
+#[test]
+fn routing_does_not_depend_on_server_order() {
+    let servers = ["127.0.0.1:4730", "127.0.0.1:4731", "127.0.0.1:4732"];
+    let mut forward = servers.iter().fold(Client::new(), |client, s| client.add_server(s));
+    let mut backward = servers.iter().rev().fold(Client::new(), |client, s| client.add_server(s));
+    for i in 0..100 {
+        let unique = format!("unique-{}", i);
+        assert_eq!(
+            forward.server_for_unique(&unique).unwrap(),
+            backward.server_for_unique(&unique).unwrap()
+        );
+    }
+}
+
+#[test]
+fn no_servers_routes_nowhere() {
+    assert!(Client::new().server_for_unique("unique-1").is_none());
+}
+
+/// Every server `client` routes some unique to, sorted
+fn routed_servers(client: &mut Client) -> Vec<String> {
+    let mut servers: Vec<String> = (0..100)
+        .map(|i| client.server_for_unique(&format!("unique-{}", i)).unwrap().to_string())
+        .collect();
+    servers.sort();
+    servers.dedup();
+    servers
+}
+
+#[test]
+fn servers_from_env() {
+    // One test, so nothing else sees the variable change under it
+    env::set_var("GEARMAN_SERVERS", "10.0.0.1:4730, gearman2");
+    assert_eq!(
+        vec!["10.0.0.1:4730", "gearman2:4730"],
+        routed_servers(&mut Client::from_env())
+    );
+    env::remove_var("GEARMAN_SERVERS");
+    assert_eq!(vec!["127.0.0.1:4730"], routed_servers(&mut Client::from_env()));
+}
+
+#[tokio::test]
+async fn keepalive_reconnects_stalled_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let _client = Client::new()
+        .add_server(&addr.to_string())
+        .set_keepalive(Duration::from_millis(20), Duration::from_millis(20))
+        .connect()
+        .await
+        .unwrap();
+    // Never answer ECHO_REQ, so the client must give up on this connection
+    let (_stalled, _) = listener.accept().await.unwrap();
+    let reconnect = timeout(Duration::from_secs(5), listener.accept()).await;
+    assert!(reconnect.expect("client did not reconnect").is_ok());
+}
+
+#[tokio::test]
+async fn submit_stream_yields_each_update() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut framed = PacketCodec::new().framed(sock);
+        while let Some(packet) = framed.next().await {
+            if packet.unwrap().ptype == SUBMIT_JOB {
+                break;
+            }
+        }
+        framed.send(new_res(JOB_CREATED, Bytes::from("H:1"))).await.unwrap();
+        // Give the client time to register the handle before work arrives
+        sleep(Duration::from_millis(50)).await;
+        framed.send(new_res(WORK_DATA, Bytes::from("H:1\0a"))).await.unwrap();
+        framed.send(new_res(WORK_DATA, Bytes::from("H:1\0b"))).await.unwrap();
+        framed.send(new_res(WORK_COMPLETE, Bytes::from("H:1\0done"))).await.unwrap();
+        framed
+    });
+    let mut client = Client::new()
+        .add_server(&addr.to_string())
+        .connect()
+        .await
+        .unwrap();
+    let events: Vec<JobEvent> = client
+        .submit_stream("f", b"x")
+        .await
+        .unwrap()
+        .collect()
+        .await;
+    assert_eq!(
+        vec![
+            JobEvent::Data(b"a".to_vec()),
+            JobEvent::Data(b"b".to_vec()),
+            JobEvent::Complete(b"done".to_vec()),
+        ],
+        events
+    );
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn submit_stream_ends_on_exception() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut framed = PacketCodec::new().framed(sock);
+        while let Some(packet) = framed.next().await {
+            if packet.unwrap().ptype == SUBMIT_JOB {
+                break;
+            }
+        }
+        framed.send(new_res(JOB_CREATED, Bytes::from("H:1"))).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        framed.send(new_res(WORK_DATA, Bytes::from("H:1\0a"))).await.unwrap();
+        framed.send(new_res(WORK_EXCEPTION, Bytes::from("H:1\0boom"))).await.unwrap();
+        // Stays connected, so only the exception can end the stream
+        framed
+    });
+    let mut client = Client::new()
+        .add_server(&addr.to_string())
+        .set_exceptions()
+        .connect()
+        .await
+        .unwrap();
+    let stream = client.submit_stream("f", b"x").await.unwrap();
+    let events: Vec<JobEvent> = timeout(Duration::from_secs(5), stream.collect())
+        .await
+        .expect("stream did not end after the exception");
+    assert_eq!(
+        vec![JobEvent::Data(b"a".to_vec()), JobEvent::Exception(b"boom".to_vec())],
+        events
+    );
+    let _framed = server.await.unwrap();
+}
+
+#[tokio::test]
+async fn submit_with_timeout_gives_up() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut framed = PacketCodec::new().framed(sock);
+        while let Some(packet) = framed.next().await {
+            if packet.unwrap().ptype == SUBMIT_JOB {
+                break;
+            }
+        }
+        // No worker will ever pick this up
+        framed.send(new_res(JOB_CREATED, Bytes::from("H:1"))).await.unwrap();
+        framed
+    });
+    let mut client = Client::new()
+        .add_server(&addr.to_string())
+        .connect()
+        .await
+        .unwrap();
+    let result = timeout(
+        Duration::from_secs(5),
+        client.submit_with_timeout("nobody", b"x", Duration::from_millis(100)),
+    )
+    .await
+    .expect("submit_with_timeout did not time out");
+    assert_eq!(std::io::ErrorKind::TimedOut, result.unwrap_err().kind());
+    let _ = server.await.unwrap();
+}
+
+#[tokio::test]
+async fn job_created_goes_to_the_submission_it_answers() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut framed = PacketCodec::new().framed(sock);
+        let mut submits = 0;
+        while let Some(packet) = framed.next().await {
+            if packet.unwrap().ptype != SUBMIT_JOB {
+                continue;
+            }
+            submits += 1;
+            match submits {
+                // The first is only answered along with the second
+                1 => {}
+                2 => {
+                    framed.send(new_res(JOB_CREATED, Bytes::from("H:1"))).await.unwrap();
+                    framed.send(new_res(JOB_CREATED, Bytes::from("H:2"))).await.unwrap();
+                }
+                _ => {
+                    let refusal = Bytes::from("rate_limited\0Too many job submissions");
+                    framed.send(new_res(ERROR, refusal)).await.unwrap();
+                    break;
+                }
+            }
+        }
+        framed
+    });
+    let mut client = Client::new()
+        .add_server(&addr.to_string())
+        .connect()
+        .await
+        .unwrap();
+    // Gives up before its JOB_CREATED arrives, so H:1 must not go to the next one
+    assert!(timeout(Duration::from_millis(100), client.submit("f", b"first")).await.is_err());
+    let second = timeout(Duration::from_secs(5), client.submit("f", b"second"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!("H:2", second.handle().to_string());
+    let refused = timeout(Duration::from_secs(5), client.submit("f", b"third"))
+        .await
+        .unwrap();
+    assert!(refused.err().unwrap().to_string().contains("rate_limited"));
+    let _ = server.await.unwrap();
+}
+
+#[tokio::test]
+async fn option_error_is_not_taken_for_a_submission_reply() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut framed = PacketCodec::new().framed(sock);
+        let mut submits = 0;
+        while let Some(packet) = framed.next().await {
+            if packet.unwrap().ptype != SUBMIT_JOB {
+                continue;
+            }
+            submits += 1;
+            if submits == 1 {
+                // The answer to the exceptions option, held back until a submission is waiting
+                let refusal = Bytes::from("unknown_option\0Server does not support this option");
+                framed.send(new_res(ERROR, refusal)).await.unwrap();
+                framed.send(new_res(JOB_CREATED, Bytes::from("H:1"))).await.unwrap();
+            } else {
+                framed.send(new_res(JOB_CREATED, Bytes::from("H:2"))).await.unwrap();
+                break;
+            }
+        }
+        framed
+    });
+    let mut client = Client::new()
+        .add_server(&addr.to_string())
+        .set_exceptions()
+        .connect()
+        .await
+        .unwrap();
+    let first = timeout(Duration::from_secs(5), client.submit("f", b"first"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!("H:1", first.handle().to_string());
+    let second = timeout(Duration::from_secs(5), client.submit("f", b"second"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!("H:2", second.handle().to_string());
+    let (code, _) = client.error().await.unwrap().unwrap();
+    assert_eq!(Bytes::from("unknown_option"), code);
+    let _ = server.await.unwrap();
+}
+
+#[tokio::test]
+async fn reconnect_resubmits_unfinished_job() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let mut uniques = Vec::new();
+        for (handle, finish) in [("H:1", false), ("H:2", true)].iter() {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut framed = PacketCodec::new().framed(sock);
+            let submit = loop {
+                let packet = framed.next().await.unwrap().unwrap();
+                if packet.ptype == SUBMIT_JOB {
+                    break packet;
+                }
+            };
+            uniques.push(submit.data.split(|b| *b == b'\0').nth(1).unwrap().to_vec());
+            framed.send(new_res(JOB_CREATED, Bytes::from(*handle))).await.unwrap();
+            if *finish {
+                sleep(Duration::from_millis(50)).await;
+                let complete = format!("{}\0done", handle);
+                framed.send(new_res(WORK_COMPLETE, Bytes::from(complete))).await.unwrap();
+                return (uniques, framed);
+            }
+            // Simulate the server going away before the job finishes
+            sleep(Duration::from_millis(50)).await;
+        }
+        unreachable!()
+    });
+    let mut client = Client::new()
+        .add_server(&addr.to_string())
+        .set_reconnect(Duration::from_millis(20), false)
+        .connect()
+        .await
+        .unwrap();
+    let mut job = client.submit("f", b"x").await.unwrap();
+    let response = timeout(Duration::from_secs(5), job.response())
+        .await
+        .expect("job was not resubmitted")
+        .unwrap();
+    match response {
+        WorkUpdate::Complete { payload, .. } => assert_eq!(Bytes::from("done"), payload),
+        other => panic!("unexpected {:?}", other),
+    }
+    let (uniques, _framed) = server.await.unwrap();
+    assert_eq!(uniques[0], uniques[1]);
+}
+
+#[tokio::test]
+async fn resubmitted_job_ignores_its_old_handle() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        for handle in ["H:1", "H:2"].iter() {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut framed = PacketCodec::new().framed(sock);
+            loop {
+                let packet = framed.next().await.unwrap().unwrap();
+                if packet.ptype == SUBMIT_JOB {
+                    break;
+                }
+            }
+            framed.send(new_res(JOB_CREATED, Bytes::from(*handle))).await.unwrap();
+            sleep(Duration::from_millis(50)).await;
+            if *handle == "H:2" {
+                // A late result for the handle the job had before the reconnect
+                framed.send(new_res(WORK_COMPLETE, Bytes::from("H:1\0stale"))).await.unwrap();
+                framed.send(new_res(WORK_COMPLETE, Bytes::from("H:2\0done"))).await.unwrap();
+                return framed;
+            }
+        }
+        unreachable!()
+    });
+    let mut client = Client::new()
+        .add_server(&addr.to_string())
+        .set_reconnect(Duration::from_millis(20), false)
+        .connect()
+        .await
+        .unwrap();
+    let mut job = client.submit("f", b"x").await.unwrap();
+    let response = timeout(Duration::from_secs(5), job.response())
+        .await
+        .expect("job was not resubmitted")
+        .unwrap();
+    match response {
+        WorkUpdate::Complete { payload, .. } => assert_eq!(Bytes::from("done"), payload),
+        other => panic!("unexpected {:?}", other),
+    }
+    let _framed = server.await.unwrap();
+}
+
+#[tokio::test]
+async fn job_ended_by_exception_is_not_resubmitted() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut framed = PacketCodec::new().framed(sock);
+        loop {
+            let packet = framed.next().await.unwrap().unwrap();
+            if packet.ptype == SUBMIT_JOB {
+                break;
+            }
+        }
+        framed.send(new_res(JOB_CREATED, Bytes::from("H:1"))).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        framed.send(new_res(WORK_EXCEPTION, Bytes::from("H:1\0boom"))).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        drop(framed);
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut framed = PacketCodec::new().framed(sock);
+        loop {
+            match timeout(Duration::from_millis(300), framed.next()).await {
+                Ok(Some(Ok(packet))) if packet.ptype == SUBMIT_JOB => return true,
+                Ok(Some(Ok(_))) => continue,
+                _ => return false,
+            }
+        }
+    });
+    let mut client = Client::new()
+        .add_server(&addr.to_string())
+        .set_exceptions()
+        .set_reconnect(Duration::from_millis(20), false)
+        .connect()
+        .await
+        .unwrap();
+    let mut job = client.submit("f", b"x").await.unwrap();
+    match timeout(Duration::from_secs(5), job.response()).await.unwrap().unwrap() {
+        WorkUpdate::Exception { .. } => (),
+        other => panic!("unexpected {:?}", other),
+    }
+    let resubmitted = timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+    assert!(!resubmitted, "finished job was submitted again");
+}
+
+#[tokio::test]
+async fn background_job_is_resubmitted_only_until_created() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let mut submits = 0;
+        // The last connection only watches for anything sent again
+        let connections = [(None, 5000), (Some("H:2"), 5000), (None, 300)];
+        for (answer, wait_ms) in connections.iter() {
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut framed = PacketCodec::new().framed(sock);
+            let wait = Duration::from_millis(*wait_ms);
+            loop {
+                match timeout(wait, framed.next()).await {
+                    Ok(Some(Ok(packet))) if packet.ptype == SUBMIT_JOB_BG => {
+                        submits += 1;
+                        break;
+                    }
+                    Ok(Some(Ok(_))) => continue,
+                    _ => return submits,
+                }
+            }
+            if let Some(handle) = answer {
+                framed.send(new_res(JOB_CREATED, Bytes::from(*handle))).await.unwrap();
+                sleep(Duration::from_millis(50)).await;
+            }
+            // Hang up, before JOB_CREATED the first time
+        }
+        submits
+    });
+    let mut client = Client::new()
+        .add_server(&addr.to_string())
+        .set_reconnect(Duration::from_millis(20), true)
+        .connect()
+        .await
+        .unwrap();
+    let job = timeout(Duration::from_secs(5), client.submit_background("f", b"x"))
+        .await
+        .expect("background job was not resubmitted")
+        .unwrap();
+    assert_eq!(&JobHandle::from(Bytes::from("H:2")), job.handle());
+    assert_eq!(2, timeout(Duration::from_secs(10), server).await.unwrap().unwrap());
+}
+
+#[tokio::test]
+async fn connect_retries_until_server_is_up() {
+    let addr = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        sleep(Duration::from_millis(300)).await;
+        let listener = TcpListener::bind(addr).await.unwrap();
+        listener.accept().await.unwrap()
+    });
+    let refused = Client::new()
+        .add_server(&addr.to_string())
+        .set_connect_retry(Duration::from_millis(10), Duration::from_millis(50))
+        .connect()
+        .await;
+    let error = refused.err().expect("connected before the server was up").to_string();
+    assert!(error.contains("within"), "{}", error);
+    Client::new()
+        .add_server(&addr.to_string())
+        .set_connect_retry(Duration::from_millis(10), Duration::from_secs(5))
+        .connect()
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(1), server).await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn lost_server_rejoins_the_ring_once_reconnected() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut client = Client::new()
+        .add_server(&addr.to_string())
+        .set_reconnect(Duration::from_millis(50), false)
+        .connect()
+        .await
+        .unwrap();
+    // Hang up and stop listening, so reconnecting fails for now
+    let (first, _) = listener.accept().await.unwrap();
+    drop(first);
+    drop(listener);
+    // Once the writer fails the server leaves the ring and nothing is routable
+    let mut unroutable = None;
+    for _ in 0..50 {
+        match timeout(Duration::from_millis(100), client.submit_background("f", b"x")).await {
+            Ok(Err(e)) if e.to_string().contains("No connections") => {
+                unroutable = Some(e);
+                break;
+            }
+            _ => sleep(Duration::from_millis(10)).await,
+        }
+    }
+    assert!(unroutable.is_some(), "server never left the ring");
+    let listener = TcpListener::bind(addr).await.unwrap();
+    let server = tokio::spawn(async move {
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut framed = PacketCodec::new().framed(sock);
+        loop {
+            let packet = framed.next().await.unwrap().unwrap();
+            if packet.ptype == SUBMIT_JOB_BG {
+                framed.send(new_res(JOB_CREATED, Bytes::from("H:1"))).await.unwrap();
+                return framed;
+            }
+        }
+    });
+    let mut handle = None;
+    for _ in 0..50 {
+        if let Ok(Ok(created)) = timeout(Duration::from_millis(100), client.submit_background("f", b"x")).await {
+            handle = Some(created.handle().clone());
+            break;
+        }
+        sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(Some(JobHandle::from("H:1")), handle);
+    let _framed = server.await.unwrap();
+}
+
+#[test]
+fn sync_client_submits_and_waits_for_completion() {
+    // SyncClient runs its own runtime, so the server gets one on another thread
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let server = std::thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async move {
+            let listener = TcpListener::from_std(listener).unwrap();
+            let (sock, _) = listener.accept().await.unwrap();
+            let mut framed = PacketCodec::new().framed(sock);
+            while let Some(packet) = framed.next().await {
+                if packet.unwrap().ptype == SUBMIT_JOB {
+                    break;
+                }
+            }
+            framed.send(new_res(JOB_CREATED, Bytes::from("H:1"))).await.unwrap();
+            sleep(Duration::from_millis(50)).await;
+            framed.send(new_res(WORK_DATA, Bytes::from("H:1\0a"))).await.unwrap();
+            framed.send(new_res(WORK_COMPLETE, Bytes::from("H:1\0done"))).await.unwrap();
+            // Stay connected until the client has read the result
+            while framed.next().await.is_some() {}
+        })
+    });
+    let mut client = SyncClient::connect(&[&addr.to_string()]).unwrap();
+    assert_eq!(b"done".to_vec(), client.submit("f", b"x").unwrap());
+    drop(client);
+    server.join().unwrap();
+}
+
+#[tokio::test]
+async fn forget_cancels_only_on_the_jobs_server() {
+    let home = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let home_addr = home.local_addr().unwrap().to_string();
+    let other = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let other_addr = other.local_addr().unwrap().to_string();
+    let home_server = tokio::spawn(async move {
+        let (sock, _) = home.accept().await.unwrap();
+        let mut framed = PacketCodec::new().framed(sock);
+        while let Some(packet) = framed.next().await {
+            if packet.unwrap().ptype == SUBMIT_JOB {
+                break;
+            }
+        }
+        framed.send(new_res(JOB_CREATED, Bytes::from("H:1"))).await.unwrap();
+        let (admin, _) = home.accept().await.unwrap();
+        let mut admin = BufReader::new(admin);
+        let mut command = String::new();
+        admin.read_line(&mut command).await.unwrap();
+        admin.get_mut().write_all(b"OK\n").await.unwrap();
+        (command, framed)
+    });
+    // The other server is asked first, and can't be reached for admin commands
+    let mut client = Client::new().add_server(&other_addr).add_server(&home_addr);
+    let unique = (0..100)
+        .map(|i| format!("unique-{}", i))
+        .find(|unique| client.server_for_unique(unique) == Some(home_addr.as_str()))
+        .unwrap();
+    let mut client = client.connect().await.unwrap();
+    let (other_conn, _) = other.accept().await.unwrap();
+    drop(other);
+    let job = client.submit_unique("f", &unique, b"x").await.unwrap();
+    assert!(client.forget(job.handle(), true).await.unwrap());
+    let (command, _framed) = home_server.await.unwrap();
+    assert_eq!("cancel job H:1\n", command);
+    drop(other_conn);
+}
+
+#[tokio::test]
+async fn garbage_status_res_is_an_error_not_a_panic() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (sock, _) = listener.accept().await.unwrap();
+        let mut framed = PacketCodec::new().framed(sock);
+        while let Some(packet) = framed.next().await {
+            if packet.unwrap().ptype == GET_STATUS {
+                break;
+            }
+        }
+        let garbage = Bytes::from_static(b"H:1\x001\x001\x00lots\x00\xff");
+        framed.send(new_res(STATUS_RES, garbage)).await.unwrap();
+        framed
+    });
+    let mut client = Client::new()
+        .add_server(&addr.to_string())
+        .connect()
+        .await
+        .unwrap();
+    let status = timeout(Duration::from_secs(5), client.get_status(&JobHandle::from("H:1")))
+        .await
+        .expect("status request was never answered");
+    assert!(status.is_err());
+    let _framed = server.await.unwrap();
+}
@@ -0,0 +1,334 @@
+extern crate bytes;
+extern crate rustygear;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+use tokio_util::codec::{Decoder, Encoder, FramedRead};
+
+#[cfg(feature = "serde")]
+use rustygear::codec::PacketView;
+#[cfg(feature = "fuzz")]
+use rustygear::client::handle_packets;
+#[cfg(feature = "fuzz")]
+use rustygear::codec::decode_packet;
+use rustygear::codec::{Packet, PacketCodec, PacketMagic, TooFewFields, HEADER_LEN};
+use rustygear::constants::*;
+use rustygear::util::{decode_metadata, encode_metadata, fits_in_packet, new_req, psize};
+
+#[test]
+fn decode_multi_chunk_body() {
+    let body = b"reverse\0unique\0some payload";
+    let mut codec = PacketCodec::new();
+    let mut buf = BytesMut::new();
+    buf.extend(REQ.iter());
+    buf.put_u32(SUBMIT_JOB);
+    buf.put_u32(body.len() as u32);
+    for chunk in body[..body.len() - 1].chunks(5) {
+        buf.extend(chunk);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+    }
+    buf.extend(&body[body.len() - 1..]);
+    let packet = codec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(PacketMagic::REQ, packet.magic);
+    assert_eq!(SUBMIT_JOB, packet.ptype);
+    assert_eq!(&body[..], &packet.data[..]);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn decode_admin_shutdown() {
+    let mut codec = PacketCodec::new();
+    let mut buf = BytesMut::from(&b"shutdown\nshutdown graceful\n"[..]);
+    let packet = codec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(ADMIN_SHUTDOWN, packet.ptype);
+    let packet = codec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(ADMIN_SHUTDOWN_GRACEFUL, packet.ptype);
+}
+
+#[test]
+fn decode_admin_case_and_whitespace() {
+    let mut codec = PacketCodec::new();
+    let mut buf = BytesMut::from(&b"VERSION\n  status \nmaxqueue foo 10\n"[..]);
+    assert_eq!(ADMIN_VERSION, codec.decode(&mut buf).unwrap().unwrap().ptype);
+    assert_eq!(ADMIN_STATUS, codec.decode(&mut buf).unwrap().unwrap().ptype);
+    let unknown = codec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(ADMIN_UNKNOWN, unknown.ptype);
+    assert_eq!(&b"foo 10"[..], &unknown.data[..]);
+}
+
+#[test]
+fn decode_admin_cancel_job() {
+    let mut codec = PacketCodec::new();
+    let mut buf = BytesMut::from(&b"cancel job H:host:1
+cancel job
+"[..]);
+    let cancel = codec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(ADMIN_CANCEL_JOB, cancel.ptype);
+    assert_eq!(&b"H:host:1"[..], &cancel.data[..]);
+    assert_eq!(ADMIN_UNKNOWN, codec.decode(&mut buf).unwrap().unwrap().ptype);
+}
+
+#[test]
+fn decode_sizes_buffer_to_packet() {
+    let body = vec![b'x'; 1024 * 1024];
+    let mut codec = PacketCodec::new();
+    let mut buf = BytesMut::new();
+    buf.extend(REQ.iter());
+    buf.put_u32(SUBMIT_JOB);
+    buf.put_u32(body.len() as u32);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    assert!(buf.capacity() >= 12 + body.len());
+    buf.extend(&body[..]);
+    let packet = codec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(body.len(), packet.data.len());
+    assert!(buf.capacity() < body.len());
+}
+
+#[test]
+fn packet_view_splits_fields() {
+    let packet = new_req(SUBMIT_JOB, Bytes::from_static(b"f\0u\0a\0b"));
+    let view = packet.view();
+    assert_eq!("REQ", view.magic);
+    assert_eq!("SUBMIT_JOB", view.ptype);
+    assert_eq!(vec![b"f".to_vec(), b"u".to_vec(), b"a\0b".to_vec()], view.fields);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn packet_serializes_as_its_view() {
+    let packet = new_req(SUBMIT_JOB, Bytes::from_static(b"f\0u\0\xff"));
+    let json = serde_json::to_string(&packet).unwrap();
+    assert_eq!(r#"{"magic":"REQ","ptype":"SUBMIT_JOB","fields":["Zg==","dQ==","/w=="]}"#, json);
+    let view: PacketView = serde_json::from_str(&json).unwrap();
+    assert_eq!(packet.view(), view);
+}
+
+#[test]
+fn decode_header_one_byte_at_a_time() {
+    let mut packet = BytesMut::new();
+    packet.extend(RES.iter());
+    packet.put_u32(NOOP);
+    packet.put_u32(0);
+    let mut codec = PacketCodec::new();
+    let mut buf = BytesMut::new();
+    for (i, b) in packet[..11].iter().enumerate() {
+        buf.put_u8(*b);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(i + 1, buf.len());
+    }
+    buf.put_u8(packet[11]);
+    let decoded = codec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(PacketMagic::RES, decoded.magic);
+    assert_eq!(NOOP, decoded.ptype);
+    assert!(buf.is_empty());
+}
+
+#[tokio::test]
+async fn close_mid_header_ends_stream() {
+    let (mut writer, reader) = tokio::io::duplex(64);
+    let mut frames = FramedRead::new(reader, PacketCodec::new());
+    let mut packet = BytesMut::new();
+    packet.extend(REQ.iter());
+    packet.put_u32(ECHO_REQ);
+    packet.put_u32(0);
+    writer.write_all(&packet[..]).await.unwrap();
+    writer.write_all(&packet[..6]).await.unwrap();
+    drop(writer);
+    assert_eq!(ECHO_REQ, frames.next().await.unwrap().unwrap().ptype);
+    assert!(frames.next().await.is_none());
+}
+
+#[test]
+fn decode_packets_without_data() {
+    // Every packet type that carries no data section
+    let empty = [
+        RESET_ABILITIES,
+        PRE_SLEEP,
+        NOOP,
+        GRAB_JOB,
+        NO_JOB,
+        ALL_YOURS,
+        GRAB_JOB_UNIQ,
+        GRAB_JOB_ALL,
+    ];
+    let mut codec = PacketCodec::new();
+    let mut buf = BytesMut::new();
+    for ptype in empty.iter() {
+        codec.encode(new_req(*ptype, Bytes::new()), &mut buf).unwrap();
+    }
+    for ptype in empty.iter() {
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(*ptype, decoded.ptype);
+        assert_eq!(0, decoded.psize);
+        assert!(decoded.data.is_empty());
+    }
+    assert!(buf.is_empty());
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+}
+
+#[test]
+fn magic_lengths_match_header() {
+    assert_eq!(4, REQ.len());
+    assert_eq!(REQ.len(), RES.len());
+    assert_eq!(12, HEADER_LEN);
+    let mut buf = BytesMut::new();
+    PacketCodec::new().encode(new_req(NOOP, Bytes::new()), &mut buf).unwrap();
+    assert_eq!(HEADER_LEN, buf.len());
+}
+
+#[test]
+fn decode_rejects_bad_magic() {
+    let mut codec = PacketCodec::new();
+    let mut buf = BytesMut::from(&b"\0BAD\0\0\0\x06\0\0\0\0"[..]);
+    assert!(codec.decode(&mut buf).is_err());
+}
+
+#[test]
+fn encode_rejects_mismatched_psize() {
+    let packet = Packet {
+        magic: PacketMagic::REQ,
+        ptype: SUBMIT_JOB,
+        psize: 1,
+        data: Bytes::from_static(b"f\0u\0x"),
+    };
+    let mut buf = BytesMut::new();
+    assert!(PacketCodec::new().encode(packet, &mut buf).is_err());
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn packet_size_limit() {
+    assert!(fits_in_packet(u32::MAX as usize));
+    assert!(!fits_in_packet(u32::MAX as usize + 1));
+}
+
+#[test]
+fn psize_at_limit() {
+    assert_eq!(u32::MAX, psize(u32::MAX as usize));
+}
+
+#[test]
+#[should_panic(expected = "too large for a packet")]
+fn psize_past_limit_panics() {
+    psize(u32::MAX as usize + 1);
+}
+
+#[test]
+fn debug_shows_field_previews() {
+    let packet = new_req(SUBMIT_JOB, Bytes::from(format!("f\0u\0{}", "x".repeat(40))));
+    let debug = format!("{:?}", packet);
+    assert!(debug.contains("ptype: SUBMIT_JOB"));
+    assert!(debug.contains(r#"field[0]="f", field[1]="u", field[2]="xxxx"#));
+    assert!(debug.ends_with("...(+8) }"));
+}
+
+#[test]
+fn fields_can_be_read_twice() {
+    let packet = new_req(WORK_COMPLETE, Bytes::from_static(b"H:1\0a\0b"));
+    let expected: Vec<&[u8]> = vec![b"H:1", b"a\0b"];
+    assert_eq!(expected, packet.fields());
+    assert_eq!(expected, packet.fields());
+    assert!(new_req(NOOP, Bytes::new()).fields().is_empty());
+}
+
+#[test]
+fn admin_line_without_newline_is_capped() {
+    let mut codec = PacketCodec::new().with_max_admin_line(16);
+    let mut buf = BytesMut::from(&b"status"[..]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    buf.extend_from_slice(&[b'x'; 16]);
+    let err = codec.decode(&mut buf).unwrap_err();
+    assert_eq!(std::io::ErrorKind::InvalidData, err.kind());
+}
+
+#[test]
+fn from_fields_is_inverse_of_fields() {
+    let packet = Packet::from_fields(SUBMIT_JOB, &[b"f", b"u", b"x"]);
+    assert_eq!(PacketMagic::REQ, packet.magic);
+    assert_eq!(5, packet.psize);
+    assert_eq!(&b"f\0u\0x"[..], &packet.data[..]);
+    let expected: Vec<&[u8]> = vec![b"f", b"u", b"x"];
+    assert_eq!(expected, packet.fields());
+}
+
+#[test]
+fn metadata_round_trips_and_plain_data_passes_through() {
+    let metadata = vec![
+        (Bytes::from("trace_id"), Bytes::from("abc")),
+        (Bytes::from("span"), Bytes::from("a=b")),
+    ];
+    let encoded = encode_metadata(&metadata, b"data\x1fwith\x1eseparators");
+    let (decoded, data) = decode_metadata(encoded);
+    assert_eq!(metadata, decoded);
+    assert_eq!(&b"data\x1fwith\x1eseparators"[..], data);
+    let (decoded, data) = decode_metadata(encode_metadata(&[], b"x"));
+    assert!(decoded.is_empty());
+    assert_eq!(&b"x"[..], data);
+    let (decoded, data) = decode_metadata(Bytes::from("plain"));
+    assert!(decoded.is_empty());
+    assert_eq!(&b"plain"[..], data);
+}
+
+#[test]
+fn checked_fields_reports_missing_fields() {
+    let short = new_req(SUBMIT_JOB, Bytes::from("f\0x"));
+    assert_eq!(vec![&b"f"[..], b"x"], short.fields());
+    assert_eq!(
+        TooFewFields {
+            ptype: SUBMIT_JOB,
+            expected: 2,
+            found: 1,
+        },
+        short.checked_fields().unwrap_err()
+    );
+    let full = new_req(SUBMIT_JOB, Bytes::from("f\0u\0x"));
+    assert_eq!(vec![&b"f"[..], b"u", b"x"], full.checked_fields().unwrap());
+    assert!(new_req(NOOP, Bytes::new()).checked_fields().unwrap().is_empty());
+}
+
+#[test]
+fn decode_errors_name_the_packet_type() {
+    let mut codec = PacketCodec::new();
+    let mut buf = BytesMut::from(&b"\0REX\0\0\0\x07\0\0\0\0"[..]);
+    let err = codec.decode(&mut buf).unwrap_err();
+    assert!(err.to_string().contains("SUBMIT_JOB (7)"), "{}", err);
+    let mut buf = BytesMut::from(&b"\0REX\0\0\x01\0\0\0\0\0"[..]);
+    let err = codec.decode(&mut buf).unwrap_err();
+    assert!(err.to_string().contains("ptype 256"), "{}", err);
+}
+
+#[test]
+fn oversized_packet_is_an_error_before_buffering() {
+    let mut codec = PacketCodec::new().with_max_packet_size(16);
+    let mut buf = BytesMut::new();
+    buf.extend(REQ.iter());
+    buf.put_u32(SUBMIT_JOB);
+    buf.put_u32(u32::MAX);
+    let err = codec.decode(&mut buf).unwrap_err();
+    assert!(err.to_string().contains("SUBMIT_JOB (7)"), "{}", err);
+    assert!(buf.capacity() < 1024);
+}
+
+#[cfg(feature = "fuzz")]
+#[test]
+fn seed_corpus_decodes_and_its_mangled_forms_do_not_panic() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/decode_packet");
+    let mut seeds = 0;
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let seed = std::fs::read(entry.unwrap().path()).unwrap();
+        let packets = decode_packet(&seed).unwrap();
+        assert_eq!(1, packets.len());
+        handle_packets(packets);
+        for cut in 0..seed.len() {
+            let _ = decode_packet(&seed[..cut]);
+            let mut flipped = seed.clone();
+            flipped[cut] ^= 0xff;
+            if let Ok(packets) = decode_packet(&flipped) {
+                handle_packets(packets);
+            }
+        }
+        seeds += 1;
+    }
+    assert!(seeds > 40);
+}
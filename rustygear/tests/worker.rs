@@ -0,0 +1,75 @@
+extern crate rustygear;
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_util::codec::{Decoder, Framed};
+
+use rustygear::client::WorkerJob;
+use rustygear::codec::{Packet, PacketCodec};
+use rustygear::constants::*;
+use rustygear::worker::{run_worker, GearmanWorker, WorkError, WorkFuture};
+
+struct Upper;
+
+impl GearmanWorker for Upper {
+    fn functions(&self) -> Vec<Vec<u8>> {
+        vec![b"upper".to_vec()]
+    }
+
+    fn handle<'a>(&'a self, job: &'a mut WorkerJob) -> WorkFuture<'a> {
+        Box::pin(async move {
+            match job.payload() {
+                b"" => Err(WorkError::Fail),
+                b"boom" => Err(WorkError::Exception(b"boom".to_vec())),
+                payload => Ok(payload.to_ascii_uppercase()),
+            }
+        })
+    }
+}
+
+/// The next packet from the worker that isn't another GRAB_JOB
+/// A payload to assign, then the type and fields of the reply it should get
+type Expected = (&'static [u8], u32, &'static [&'static [u8]]);
+
+async fn next_reply(server: &mut Framed<TcpStream, PacketCodec>) -> Packet {
+    loop {
+        let packet = timeout(Duration::from_secs(5), server.next())
+            .await
+            .expect("worker went quiet")
+            .unwrap()
+            .unwrap();
+        if packet.ptype != GRAB_JOB {
+            return packet;
+        }
+    }
+}
+
+#[tokio::test]
+async fn run_worker_completes_and_fails_jobs() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let _ = run_worker(&addr, Upper).await;
+    });
+    let (sock, _) = listener.accept().await.unwrap();
+    let mut server = PacketCodec::new().framed(sock);
+    let can_do = next_reply(&mut server).await;
+    assert_eq!(CAN_DO, can_do.ptype);
+    assert_eq!(Bytes::from("upper"), can_do.data);
+    let expected: [Expected; 3] = [
+        (b"abc", WORK_COMPLETE, &[b"H:1", b"ABC"]),
+        (b"", WORK_FAIL, &[b"H:2"]),
+        (b"boom", WORK_EXCEPTION, &[b"H:3", b"boom"]),
+    ];
+    for (payload, ptype, fields) in expected.iter() {
+        let assign = Packet::from_fields(JOB_ASSIGN, &[fields[0], b"upper", payload]);
+        server.send(assign).await.unwrap();
+        let reply = next_reply(&mut server).await;
+        assert_eq!(*ptype, reply.ptype);
+        assert_eq!(fields.to_vec(), reply.fields());
+    }
+}
@@ -1,16 +1,17 @@
-use bytes::{BufMut, BytesMut};
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
 
 use rustygear::codec::Packet;
 
-use crate::queues::SharedJobStorage;
+use crate::queues::{JobState, SharedJobStorage};
 use crate::worker::{SharedWorkers, Wake};
 use crate::service::WorkersByConnId;
 
 pub fn admin_command_status(storage: SharedJobStorage, workers: SharedWorkers) -> Packet {
     let mut response = BytesMut::with_capacity(1024 * 1024); // XXX Wild guess.
-    let storage = storage.lock().unwrap();
-    let queues = storage.queues();
-    for (func, fqueues) in queues.iter() {
+    let shards = storage.lock_all();
+    for (func, fqueues) in shards.iter().flat_map(|shard| shard.queues().iter()) {
         let mut qtot = 0;
         for q in fqueues {
             qtot += q.len();
@@ -32,6 +33,102 @@ pub fn admin_command_status(storage: SharedJobStorage, workers: SharedWorkers) -
     Packet::new_text_res(response)
 }
 
+/// One line per function: name, completed, failed, then average and p95 runtime in ms
+pub fn admin_command_function_stats(storage: SharedJobStorage) -> Packet {
+    let mut response = BytesMut::new();
+    let shards = storage.lock_all();
+    let millis = |runtime: Option<Duration>| runtime.map_or(0, |r| r.as_millis());
+    for (func, stats) in shards.iter().flat_map(|shard| shard.function_stats().iter()) {
+        response.extend(func);
+        response.extend(
+            format!(
+                "\t{}\t{}\t{}\t{}\n",
+                stats.completed,
+                stats.failed,
+                millis(stats.avg_runtime()),
+                millis(stats.percentile_runtime(95.0))
+            )
+            .into_bytes(),
+        );
+    }
+    response.extend(b".\n");
+    Packet::new_text_res(response.freeze())
+}
+
+/// One line per job queued for `fname`, in the order they'll be handed out:
+/// handle, unique, age in ms, priority and any space separated `key=value`
+/// metadata, at most `max_rows` of them
+pub fn admin_command_show_jobs(storage: SharedJobStorage, fname: &Bytes, max_rows: usize) -> Packet {
+    let mut response = BytesMut::new();
+    let storage = storage.shard(fname);
+    if let Some(prios) = storage.queues().get(fname) {
+        let queued = prios
+            .iter()
+            .zip(["high", "normal", "low"].iter())
+            .flat_map(|(q, priority)| q.iter().filter_map(move |job| Some((job.upgrade()?, priority))))
+            .take(max_rows);
+        for (job, priority) in queued {
+            response.extend(job.handle.as_bytes());
+            response.put_u8(b'\t');
+            response.extend(&job.unique);
+            response.extend(
+                format!("\t{}\t{}", job.created_at.elapsed().as_millis(), priority).into_bytes(),
+            );
+            for (i, (key, value)) in job.metadata.iter().enumerate() {
+                response.put_u8(if i == 0 { b'\t' } else { b' ' });
+                response.extend(key);
+                response.put_u8(b'=');
+                response.extend(value);
+            }
+            response.put_u8(b'\n');
+        }
+    }
+    response.extend(b".\n");
+    Packet::new_text_res(response.freeze())
+}
+
+/// Closes every worker that has sent nothing for longer than `threshold`
+/// while holding no jobs, replying with how many there were
+pub fn admin_command_kill_idle(workers: WorkersByConnId, threshold: Duration) -> Packet {
+    let mut killed = 0;
+    let workers = workers.lock().unwrap();
+    for (conn_id, worker) in workers.iter() {
+        let worker = worker.lock().unwrap();
+        match worker.idle_for() {
+            Some(idle) if idle > threshold => {
+                info!("Closing worker {} idle for {:?}", conn_id, idle);
+                worker.close();
+                killed += 1;
+            }
+            _ => {}
+        }
+    }
+    Packet::new_text_res(Bytes::from(format!("OK {}\n", killed)))
+}
+
+/// One line per stored job, oldest first: unique, handle, state and how
+/// many connections wait on it, at most `max_rows` of them
+pub fn admin_command_show_unique(storage: SharedJobStorage, max_rows: usize) -> Packet {
+    let mut response = BytesMut::new();
+    for job in storage.snapshot(max_rows) {
+        let state = match job.state {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Blocked => "blocked",
+        };
+        let waiters = storage
+            .shard(&job.fname)
+            .remotes_by_unique(&job.unique)
+            .map_or(0, |remotes| remotes.len());
+        response.extend(&job.unique);
+        response.put_u8(b'\t');
+        response.extend(job.handle.as_bytes());
+        response.extend(format!("\t{}\t{}\n", state, waiters).into_bytes());
+    }
+    response.extend(b".\n");
+    Packet::new_text_res(response.freeze())
+}
+
 pub fn admin_command_workers(workers: WorkersByConnId) -> Packet {
     let mut response = BytesMut::with_capacity(1024 * 1024); // XXX Wild guess.
     let workers = workers.lock().unwrap();
@@ -41,8 +138,10 @@ pub fn admin_command_workers(workers: WorkersByConnId) -> Packet {
         // actually makes the workers command more useful as it lets us see
         // where in the roundrobin each worker is
         let mut worker = worker.lock().unwrap();
-        let client_id = String::from_utf8(worker.client_id.to_vec()).unwrap();
-        response.extend(format!("{} {} {} :", conn_id, worker.peer_addr, client_id).bytes());
+        // Client ids are arbitrary bytes, pass them through rather than requiring UTF-8
+        response.extend(format!("{} {} ", conn_id, worker.peer_addr).bytes());
+        response.extend(&worker.client_id);
+        response.extend(b" :");
         for func in worker.functions.iter() {
             response.put_u8(b' ');
             response.extend(func);
@@ -0,0 +1,35 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+
+/// Decides whether a connection may use the server
+///
+/// Set one with [ServerConfig::authenticator](crate::config::ServerConfig::authenticator).
+/// Connections then get ERROR `auth_required` for every packet until they
+/// send OPTION_REQ `auth=<token>` with a token this accepts.
+pub trait Authenticator: fmt::Debug + Send + Sync {
+    /// Whether the peer at `peer_addr` presenting `token` is let in
+    fn authenticate(&self, peer_addr: SocketAddr, token: &[u8]) -> bool;
+}
+
+/// Accepts any of a fixed set of tokens, from any address
+#[derive(Debug, Default)]
+pub struct StaticTokens {
+    tokens: HashSet<Bytes>,
+}
+
+impl StaticTokens {
+    pub fn new<T: Into<Bytes>>(tokens: Vec<T>) -> StaticTokens {
+        StaticTokens {
+            tokens: tokens.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl Authenticator for StaticTokens {
+    fn authenticate(&self, _peer_addr: SocketAddr, token: &[u8]) -> bool {
+        self.tokens.contains(token)
+    }
+}
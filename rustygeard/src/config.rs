@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use rustygear::codec::{DEFAULT_MAX_ADMIN_LINE, DEFAULT_MAX_PACKET_SIZE};
+use rustygear::constants::DEFAULT_PORT;
+
+use crate::auth::Authenticator;
+use crate::handles::HandleGenerator;
+use crate::replication::Replicator;
+use crate::scheduler::Scheduler;
+
+/// Options for a [GearmanServer](crate::server::GearmanServer)
+///
+/// Start from `ServerConfig::default()` and override what you need.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Address the listener binds to, every interface on [DEFAULT_PORT] by default
+    pub listen: SocketAddr,
+    /// Depth of each connection's outbound packet channel
+    pub max_unhandled_out_frames: usize,
+    /// A warning is logged when this many packets are queued for one
+    /// connection, e.g. a worker or client that stopped reading
+    pub outbound_high_water: Option<usize>,
+    /// Connections that send no complete packet for this long are closed,
+    /// unless they are workers sleeping after PRE_SLEEP
+    pub idle_timeout: Option<Duration>,
+    /// Accept the admin `shutdown` command from non-loopback peers
+    pub allow_remote_shutdown: bool,
+    /// Maximum job submissions per second from a single connection
+    pub max_submits_per_sec: Option<u32>,
+    /// Set SO_REUSEADDR on the listener so restarts don't wait out TIME_WAIT
+    pub reuseaddr: bool,
+    /// Set TCP_NODELAY on accepted connections
+    pub nodelay: bool,
+    /// Length of the listener's accept queue
+    pub backlog: u32,
+    /// Connections beyond this many are sent an ERROR and closed
+    pub max_connections: Option<usize>,
+    /// Queued low and normal priority jobs older than this are promoted a level
+    pub aging_threshold: Option<Duration>,
+    /// Submissions with more data than this for a function are refused with ERROR data_too_large
+    pub max_data_sizes: HashMap<Bytes, usize>,
+    /// Admin connections sending a longer line than this are sent an ERROR and closed
+    pub max_admin_line: usize,
+    /// Connections sending a packet with more data than this are closed
+    pub max_packet_size: usize,
+    /// Threads serving connections, one per core if `None`
+    ///
+    /// Every thread shares the same job queues and worker lists, each behind
+    /// a single mutex, so past a few threads they rather than the CPU tend to
+    /// become the limit.
+    pub threads: Option<usize>,
+    /// Makes handles for new jobs, `H:<counter>` handles if `None`
+    pub handles: Option<Arc<dyn HandleGenerator>>,
+    /// Picks which sleeping worker to wake for a job, least recently assigned if `None`
+    pub scheduler: Option<Arc<dyn Scheduler>>,
+    /// Packets that take longer than this to process are logged at warn level
+    pub slow_packet_threshold: Option<Duration>,
+    /// Told about every job submitted, completed or failed, nothing if `None`
+    pub replicator: Option<Arc<dyn Replicator>>,
+    /// Most jobs the admin `showjobs` and `showunique` commands list
+    pub max_admin_rows: usize,
+    /// Submissions for a function no connected worker can do get an ERROR
+    /// rather than being queued
+    pub reject_unknown_functions: bool,
+    /// Checks the token connections must send before anything else, every
+    /// connection is trusted if `None`
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            listen: SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT)),
+            max_unhandled_out_frames: 1024,
+            outbound_high_water: Some(768),
+            idle_timeout: None,
+            allow_remote_shutdown: false,
+            max_submits_per_sec: None,
+            reuseaddr: true,
+            nodelay: true,
+            backlog: 1024,
+            max_connections: None,
+            aging_threshold: None,
+            max_data_sizes: HashMap::new(),
+            max_admin_line: DEFAULT_MAX_ADMIN_LINE,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            threads: None,
+            handles: None,
+            scheduler: None,
+            slow_packet_threshold: None,
+            replicator: None,
+            max_admin_rows: 1000,
+            reject_unknown_functions: false,
+            authenticator: None,
+        }
+    }
+}
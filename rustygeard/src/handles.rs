@@ -0,0 +1,80 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use rustygear::job::JobHandle;
+use uuid::Uuid;
+
+/// Makes the handles given to newly submitted jobs
+///
+/// Set one with [ServerConfig::handles](crate::config::ServerConfig::handles),
+/// every connection to the server shares it.
+pub trait HandleGenerator: fmt::Debug + Send + Sync {
+    /// A handle no other job of this server has had
+    fn next_handle(&self) -> JobHandle;
+
+    /// Names this server in its handles, reported by the admin `id` command
+    fn server_id(&self) -> Option<&str> {
+        None
+    }
+}
+
+/// `H:<counter>` or `H:<host>:<counter>` handles, the counter zero padded to ten digits
+#[derive(Debug)]
+pub struct CounterHandles {
+    host: Option<String>,
+    prefix: String,
+    count: Arc<AtomicUsize>,
+}
+
+impl CounterHandles {
+    pub fn new() -> CounterHandles {
+        CounterHandles::with_counter(Arc::new(AtomicUsize::new(0)))
+    }
+
+    /// Counts with `count`, which may be shared with something else
+    pub fn with_counter(count: Arc<AtomicUsize>) -> CounterHandles {
+        CounterHandles {
+            host: None,
+            prefix: String::from("H:"),
+            count: count,
+        }
+    }
+
+    /// Puts `host` in every handle, so handles from several servers don't collide
+    pub fn with_host(host: &str) -> CounterHandles {
+        CounterHandles {
+            host: Some(host.to_string()),
+            prefix: format!("H:{}:", host),
+            count: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl Default for CounterHandles {
+    fn default() -> CounterHandles {
+        CounterHandles::new()
+    }
+}
+
+impl HandleGenerator for CounterHandles {
+    fn next_handle(&self) -> JobHandle {
+        let job_num = self.count.fetch_add(1, Ordering::Relaxed);
+        JobHandle::from(Bytes::from(format!("{}{:010}", self.prefix, job_num)))
+    }
+
+    fn server_id(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+}
+
+/// `H:<uuid>` handles, unique across servers and restarts
+#[derive(Debug, Default)]
+pub struct UuidHandles;
+
+impl HandleGenerator for UuidHandles {
+    fn next_handle(&self) -> JobHandle {
+        JobHandle::from(Bytes::from(format!("H:{}", Uuid::new_v4())))
+    }
+}
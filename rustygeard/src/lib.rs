@@ -1,7 +1,15 @@
 #[macro_use]
 extern crate log;
 pub mod admin;
+pub mod auth;
+pub mod config;
+pub mod handles;
+pub mod log_context;
 pub mod queues;
+pub mod ratelimit;
+pub mod replication;
+pub mod scheduler;
 pub mod server;
 pub mod service;
+pub mod stats;
 pub mod worker;
@@ -0,0 +1,35 @@
+use std::fmt;
+
+/// Prefix for log lines so they can be grepped by connection and job
+///
+/// Formats as `[conn=N]` or `[conn=N][handle=H:...]`.
+pub struct LogContext<'a> {
+    conn_id: usize,
+    handle: Option<&'a [u8]>,
+}
+
+impl<'a> LogContext<'a> {
+    pub fn conn(conn_id: usize) -> LogContext<'a> {
+        LogContext {
+            conn_id: conn_id,
+            handle: None,
+        }
+    }
+
+    pub fn handle(conn_id: usize, handle: &'a [u8]) -> LogContext<'a> {
+        LogContext {
+            conn_id: conn_id,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl<'a> fmt::Display for LogContext<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[conn={}]", self.conn_id)?;
+        if let Some(handle) = self.handle {
+            write!(f, "[handle={}]", String::from_utf8_lossy(handle))?;
+        }
+        Ok(())
+    }
+}
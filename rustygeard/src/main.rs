@@ -5,6 +5,7 @@ extern crate env_logger;
 extern crate rustygear;
 extern crate rustygeard;
 
+use rustygear::constants::DEFAULT_PORT;
 use rustygeard::server::GearmanServer;
 use clap::{Arg, App};
 
@@ -22,7 +23,10 @@ fn main() {
             .takes_value(true))
         .get_matches();
 
-    let listen = matches.value_of("listen").unwrap_or("0.0.0.0:4730");
+    let listen = match matches.value_of("listen") {
+        Some(listen) => listen.to_string(),
+        None => format!("0.0.0.0:{}", DEFAULT_PORT),
+    };
     env_logger::init();
 
     info!("Binding to {}", listen);
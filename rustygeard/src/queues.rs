@@ -1,44 +1,181 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::sync::{Arc, Mutex, Weak};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::{Duration, Instant};
 
 use bytes::Bytes;
 
-use rustygear::job::Job;
+use rustygear::constants::{PRIORITY_LOW, PRIORITY_NORMAL};
+use rustygear::job::{Job, JobHandle};
 
+use crate::replication::{NoReplication, ReplicationEvent, Replicator};
+use crate::stats::FunctionStats;
 use crate::worker::Worker;
 
 pub type JobQueue = VecDeque<Weak<Job>>;
 pub type JobQueues = HashMap<Bytes, [JobQueue; 3]>;
 
+/// One shard of the job storage, with the jobs and queues of the functions that hash to it
 pub struct JobStorage {
+    shard: usize,
+    index: Arc<Mutex<ShardIndex>>, // Shared by every shard
     jobs: HashMap<Bytes, Arc<Job>>, // Owns the job objects forever
+    uniques_by_handle: HashMap<JobHandle, Bytes>, // Index into jobs, for lookups by handle
     queues: JobQueues,
+    nonempty: HashSet<Bytes>, // Functions whose queues may hold jobs, pruned by get_job
     remotes_by_unique: HashMap<Bytes, HashSet<usize>>,
-    remotes_by_handle: HashMap<Bytes, Vec<usize>>,
+    remotes_by_handle: HashMap<JobHandle, Vec<usize>>,
+    max_data_sizes: HashMap<Bytes, usize>,
+    max_retries: HashMap<Bytes, usize>,
+    last_waits: HashMap<Bytes, Duration>,
+    stats: HashMap<Bytes, FunctionStats>,
+    aging_threshold: Option<Duration>,
+    replicator: Arc<dyn Replicator>,
 }
 
-pub type SharedJobStorage = Arc<Mutex<JobStorage>>;
+/// Job storage split into shards by function name, each behind its own lock,
+/// so work on functions in different shards doesn't wait on one another
+///
+/// Locks are taken in the order dependencies, shard, index, and only one
+/// shard at a time except through [ShardedJobStorage::lock_all].
+pub struct ShardedJobStorage {
+    shards: Vec<Mutex<JobStorage>>,
+    index: Arc<Mutex<ShardIndex>>,
+    dependencies: Mutex<Dependencies>,
+    draining: AtomicBool,
+}
+
+pub type SharedJobStorage = Arc<ShardedJobStorage>;
+
+/// Which shard holds each stored job
+#[derive(Default)]
+struct ShardIndex {
+    by_handle: HashMap<JobHandle, usize>,
+    by_unique: HashMap<Bytes, usize>,
+}
+
+impl ShardIndex {
+    fn insert(&mut self, job: &Job, shard: usize) {
+        self.by_handle.insert(job.handle.clone(), shard);
+        self.by_unique.insert(job.unique.clone(), shard);
+    }
+
+    /// Forgets `job` unless another shard stored a job with its handle or unique since
+    fn remove(&mut self, job: &Job, shard: usize) {
+        if self.by_handle.get(&job.handle) == Some(&shard) {
+            self.by_handle.remove(&job.handle);
+        }
+        if self.by_unique.get(&job.unique) == Some(&shard) {
+            self.by_unique.remove(&job.unique);
+        }
+    }
+}
+
+/// Jobs held back until another job completes, which may be in any shard
+#[derive(Default)]
+struct Dependencies {
+    blocked: HashMap<Bytes, Vec<(Arc<Job>, JobQueuePriority)>>, // By the unique they wait on
+    waiting_on: HashMap<Bytes, Bytes>,                          // Blocked unique -> unique it waits on
+}
+
+/// Where a stored job is, see [JobSnapshot]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JobState {
+    Queued,
+    /// Handed to a worker and not yet finished
+    Running,
+    /// Held back until the job it depends on completes
+    Blocked,
+}
+
+/// One job as it was when [JobStorage::snapshot] was taken
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobSnapshot {
+    pub fname: Bytes,
+    pub handle: JobHandle,
+    pub unique: Bytes,
+    /// `None` once running, the storage forgets it then
+    pub priority: Option<JobQueuePriority>,
+    pub state: JobState,
+    pub age: Duration,
+}
+
+/// Reasons a job may be refused by [HandleJobStorage::add_job]
+#[derive(Debug)]
+pub enum EnqueueError {
+    DataTooLarge { size: usize, limit: usize },
+    DependencyCycle,
+}
+
+impl EnqueueError {
+    /// The code sent to clients in the ERROR packet
+    pub fn code(&self) -> &'static str {
+        match self {
+            EnqueueError::DataTooLarge { .. } => "data_too_large",
+            EnqueueError::DependencyCycle => "dependency_cycle",
+        }
+    }
+}
+
+impl fmt::Display for EnqueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EnqueueError::DataTooLarge { size, limit } => {
+                write!(f, "Job data is {} bytes, limit is {}", size, limit)
+            }
+            EnqueueError::DependencyCycle => write!(f, "Job would depend on itself"),
+        }
+    }
+}
 
 pub trait HandleJobStorage {
     fn new_job_storage() -> SharedJobStorage;
-    fn coalesce_unique(&mut self, unique: &Bytes, remote: Option<usize>) -> Option<Bytes>;
-    fn add_job(&mut self, job: Arc<Job>, priority: JobQueuePriority, remote: Option<usize>);
+    fn coalesce_unique(&mut self, unique: &Bytes, remote: Option<usize>) -> Option<JobHandle>;
+    fn add_job(
+        &mut self,
+        job: Arc<Job>,
+        priority: JobQueuePriority,
+        remote: Option<usize>,
+    ) -> Result<(), EnqueueError>;
+    fn add_dependent_job(
+        &mut self,
+        job: Arc<Job>,
+        priority: JobQueuePriority,
+        remote: Option<usize>,
+        dependency: Bytes,
+    ) -> Result<(), EnqueueError>;
     fn get_job(&mut self, worker: &mut Worker) -> Option<Arc<Job>>;
+    fn functions_with_queued(&self) -> Vec<Vec<u8>>;
 }
 
 pub type JobQueuePriority = usize;
 
-const INIT_JOB_STORAGE_CAPACITY: usize = 10000000; // XXX This should be configurable
 const INIT_JOB_FUNCTIONS_CAPACITY: usize = 4096; // XXX This should be configurable
 const INIT_JOB_REMOTES_CAPACITY: usize = 8;
+const JOB_STORAGE_SHARDS: usize = 16; // XXX This should be configurable
 
 impl JobStorage {
-    fn new() -> JobStorage {
+    fn new(shard: usize, index: Arc<Mutex<ShardIndex>>) -> JobStorage {
+        // Job maps grow as jobs arrive, so an idle server doesn't hold memory for millions of them
+        let functions_capacity = INIT_JOB_FUNCTIONS_CAPACITY / JOB_STORAGE_SHARDS;
         JobStorage {
-            jobs: HashMap::with_capacity(INIT_JOB_STORAGE_CAPACITY),
-            queues: HashMap::with_capacity(INIT_JOB_FUNCTIONS_CAPACITY),
-            remotes_by_unique: HashMap::with_capacity(INIT_JOB_STORAGE_CAPACITY),
-            remotes_by_handle: HashMap::with_capacity(INIT_JOB_STORAGE_CAPACITY),
+            shard: shard,
+            index: index,
+            jobs: HashMap::new(),
+            uniques_by_handle: HashMap::new(),
+            queues: HashMap::with_capacity(functions_capacity),
+            nonempty: HashSet::with_capacity(functions_capacity),
+            remotes_by_unique: HashMap::new(),
+            remotes_by_handle: HashMap::new(),
+            max_data_sizes: HashMap::new(),
+            max_retries: HashMap::new(),
+            last_waits: HashMap::new(),
+            stats: HashMap::new(),
+            aging_threshold: None,
+            replicator: Arc::new(NoReplication),
         }
     }
 
@@ -46,11 +183,321 @@ impl JobStorage {
         &self.queues
     }
 
+    /// Limits the data size of jobs submitted for `fname`, `None` removes the limit
+    pub fn set_max_data_size(&mut self, fname: Bytes, limit: Option<usize>) {
+        match limit {
+            Some(limit) => {
+                self.max_data_sizes.insert(fname, limit);
+            }
+            None => {
+                self.max_data_sizes.remove(&fname);
+            }
+        }
+    }
+
+    pub fn max_data_size(&self, fname: &Bytes) -> Option<usize> {
+        self.max_data_sizes.get(fname).cloned()
+    }
+
+    /// Sets how many times a failed job for `fname` is requeued before the failure is reported
+    pub fn set_max_retries(&mut self, fname: Bytes, retries: usize) {
+        self.max_retries.insert(fname, retries);
+    }
+
+    pub fn max_retries(&self, fname: &Bytes) -> usize {
+        self.max_retries.get(fname).cloned().unwrap_or(0)
+    }
+
+    /// Replaces the [NoReplication] default told about submitted, completed and failed jobs
+    pub fn set_replicator(&mut self, replicator: Arc<dyn Replicator>) {
+        self.replicator = replicator;
+    }
+
+    /// Passes `event` on to the replicator
+    pub fn replicate(&self, event: ReplicationEvent) {
+        self.replicator.replicate(event);
+    }
+
+    /// Promotes queued low and normal jobs that have waited longer than `threshold`
+    ///
+    /// Jobs are checked when a worker asks for their function, and move up one
+    /// level each time, so high priority work can't starve them forever.
+    pub fn set_aging_threshold(&mut self, threshold: Option<Duration>) {
+        self.aging_threshold = threshold;
+    }
+
+    /// Number of jobs waiting in this shard's queues
+    pub fn queued_count(&self) -> usize {
+        self.queues
+            .values()
+            .flat_map(|prios| prios.iter())
+            .flat_map(|q| q.iter())
+            .filter(|job| job.upgrade().is_some())
+            .count()
+    }
+
+    /// Adds every job stored in this shard to `jobs`, `blocked` being the
+    /// priorities of jobs held back by a dependency
+    fn snapshot_into(&self, blocked: &HashMap<Bytes, JobQueuePriority>, now: Instant, jobs: &mut Vec<JobSnapshot>) {
+        let snapshot = |job: &Job, priority, state| JobSnapshot {
+            fname: job.fname.clone(),
+            handle: job.handle.clone(),
+            unique: job.unique.clone(),
+            priority: priority,
+            state: state,
+            age: now.saturating_duration_since(job.created_at),
+        };
+        let mut seen = HashSet::new();
+        for prios in self.queues.values() {
+            for (priority, q) in prios.iter().enumerate() {
+                for job in q.iter().filter_map(|job| job.upgrade()) {
+                    if self.is_stored(&job) && seen.insert(job.unique.clone()) {
+                        jobs.push(snapshot(&job, Some(priority), JobState::Queued));
+                    }
+                }
+            }
+        }
+        for (unique, job) in self.jobs.iter() {
+            if seen.contains(unique) {
+                continue;
+            }
+            match blocked.get(unique) {
+                Some(priority) => jobs.push(snapshot(job, Some(*priority), JobState::Blocked)),
+                None => jobs.push(snapshot(job, None, JobState::Running)),
+            }
+        }
+    }
+
+    /// Number of jobs waiting in `fname`'s queues
+    pub fn queued_for(&self, fname: &Bytes) -> usize {
+        match self.queues.get(fname) {
+            None => 0,
+            Some(prios) => prios
+                .iter()
+                .flat_map(|q| q.iter())
+                .filter(|job| job.upgrade().is_some())
+                .count(),
+        }
+    }
+
+    /// How long the most recently assigned `fname` job waited in the queue
+    pub fn last_wait(&self, fname: &Bytes) -> Option<Duration> {
+        self.last_waits.get(fname).cloned()
+    }
+
+    /// Age of the oldest job still queued for `fname`
+    pub fn oldest_queued_age(&self, fname: &Bytes) -> Option<Duration> {
+        self.queues.get(fname).and_then(|prios| {
+            prios
+                .iter()
+                .flat_map(|q| q.iter())
+                .filter_map(|job| job.upgrade())
+                .map(|job| job.created_at.elapsed())
+                .max()
+        })
+    }
+
+    /// Completion statistics by function, for functions that finished or failed a job
+    pub fn function_stats(&self) -> &HashMap<Bytes, FunctionStats> {
+        &self.stats
+    }
+
+    pub fn record_complete(&mut self, fname: &Bytes, runtime: Duration) {
+        self.stats.entry(fname.clone()).or_default().record_complete(runtime);
+    }
+
+    pub fn record_failure(&mut self, fname: &Bytes) {
+        self.stats.entry(fname.clone()).or_default().record_failure();
+    }
+
+    /// Puts a job that is already stored back on the queue for its function
+    pub fn requeue_job(&mut self, job: &Arc<Job>, priority: JobQueuePriority) {
+        match self.queues.get_mut(&job.fname) {
+            None => warn!("Requeue of {:?} for a function with no queues", job),
+            Some(func_queues) => {
+                func_queues[priority].push_back(Arc::downgrade(job));
+                self.nonempty.insert(job.fname.clone());
+            }
+        }
+    }
+
+    /// Whether `job` is still stored, rather than cancelled or replaced by
+    /// another job with the same unique
+    pub fn is_stored(&self, job: &Arc<Job>) -> bool {
+        match self.jobs.get(&job.unique) {
+            Some(stored) => Arc::ptr_eq(stored, job),
+            None => false,
+        }
+    }
+
+    /// Removes `job` if it is still waiting in a queue, returning whether it was
+    ///
+    /// Running jobs and jobs blocked on a dependency are left alone.
+    pub fn expire_job(&mut self, job: &Arc<Job>) -> bool {
+        if !self.is_stored(job) {
+            return false;
+        }
+        let queued = match self.queues.get_mut(&job.fname) {
+            None => false,
+            Some(prios) => prios.iter_mut().any(|q| {
+                match q.iter().position(|queued| queued.as_ptr() == Arc::as_ptr(job)) {
+                    Some(at) => {
+                        q.remove(at);
+                        true
+                    }
+                    None => false,
+                }
+            }),
+        };
+        if queued {
+            self.remove_job(&job.unique);
+        }
+        queued
+    }
+
+    /// Removes every job queued for `fname` and returns them
+    ///
+    /// Running jobs and jobs blocked on a dependency are left alone.
+    pub fn drop_function(&mut self, fname: &Bytes) -> Vec<Arc<Job>> {
+        let dropped: Vec<Arc<Job>> = match self.queues.get_mut(fname) {
+            None => Vec::new(),
+            Some(prios) => prios
+                .iter_mut()
+                .flat_map(|q| q.drain(..))
+                .filter_map(|job| job.upgrade())
+                .collect(),
+        };
+        self.nonempty.remove(fname);
+        let mut removed = Vec::with_capacity(dropped.len());
+        for job in dropped {
+            // A job requeued while it was still queued shows up twice
+            if self.is_stored(&job) {
+                self.remove_job(&job.unique);
+                removed.push(job);
+            }
+        }
+        removed
+    }
+
+    /// Whether a job for any function `worker` can do is waiting in this shard's queues
+    pub fn has_job_for(&self, worker: &Worker) -> bool {
+        self.nonempty
+            .iter()
+            .filter(|fname| worker.can(fname))
+            .any(|fname| match self.queues.get(fname) {
+                None => false,
+                Some(prios) => prios
+                    .iter()
+                    .any(|q| q.iter().any(|job| job.upgrade().is_some())),
+            })
+    }
+
+    /// Finds a stored job by handle
+    pub fn job_by_handle(&self, handle: &JobHandle) -> Option<Arc<Job>> {
+        self.uniques_by_handle
+            .get(handle)
+            .and_then(|unique| self.jobs.get(unique))
+            .cloned()
+    }
+
+    /// Puts a stored job on the queue for its function
+    fn queue_job(&mut self, job: &Arc<Job>, priority: JobQueuePriority) {
+        let func_queues = self.queues.entry(job.fname.clone()).or_insert_with(|| {
+            [VecDeque::new(), VecDeque::new(), VecDeque::new()]
+        });
+        func_queues[priority].push_back(Arc::downgrade(job));
+        self.nonempty.insert(job.fname.clone());
+    }
+
+    /// Takes the next live job queued for `fname`, recording how long it waited
+    fn take_job(&mut self, fname: &Bytes) -> Option<Arc<Job>> {
+        if !self.nonempty.contains(fname) {
+            return None;
+        }
+        debug!("func = {:?}", fname);
+        let mut job: Option<Arc<Job>> = None;
+        if let Some(prios) = self.queues.get_mut(fname) {
+            if let Some(threshold) = self.aging_threshold {
+                promote_aged(prios, threshold);
+            }
+            for q in prios.iter_mut() {
+                while let Some(a_job) = q.pop_front() {
+                    match a_job.upgrade() {
+                        None => trace!("Deleted job encountered."),
+                        Some(a_job) => {
+                            job = Some(a_job);
+                            break;
+                        }
+                    }
+                }
+                if job.is_some() {
+                    break;
+                }
+            }
+        }
+        match job {
+            Some(job) => {
+                let wait = job.created_at.elapsed();
+                debug!("{:?} waited {:?} for a worker", job, wait);
+                self.last_waits.insert(job.fname.clone(), wait);
+                Some(job)
+            }
+            None => {
+                // Every queue for fname was drained without finding a live job
+                self.nonempty.remove(fname);
+                None
+            }
+        }
+    }
+
+    /// Records `job` in the storage without queueing it
+    fn store_job(&mut self, job: &Arc<Job>, priority: JobQueuePriority, remote: Option<usize>) {
+        let mut index = self.index.lock().unwrap();
+        if let Some(replaced) = self.jobs.insert(job.unique.clone(), job.clone()) {
+            self.uniques_by_handle.remove(&replaced.handle);
+            index.remove(&replaced, self.shard);
+        }
+        self.uniques_by_handle.insert(job.handle.clone(), job.unique.clone());
+        index.insert(job, self.shard);
+        drop(index);
+        self.replicate(ReplicationEvent::Submitted {
+            job: job.clone(),
+            priority: priority,
+        });
+        let mut remotes_by_unique = HashSet::with_capacity(INIT_JOB_REMOTES_CAPACITY);
+        let mut remotes_by_handle = Vec::with_capacity(INIT_JOB_REMOTES_CAPACITY);
+        match remote {
+            None => {}
+            Some(remote) => {
+                remotes_by_unique.insert(remote);
+                remotes_by_handle.push(remote);
+            }
+        }
+        self.remotes_by_unique
+            .insert(job.unique.clone(), remotes_by_unique);
+        self.remotes_by_handle
+            .insert(job.handle.clone(), remotes_by_handle);
+    }
+
+    fn check_data_size(&self, job: &Job) -> Result<(), EnqueueError> {
+        if let Some(limit) = self.max_data_size(&job.fname) {
+            if job.data.len() > limit {
+                return Err(EnqueueError::DataTooLarge {
+                    size: job.data.len(),
+                    limit: limit,
+                });
+            }
+        }
+        Ok(())
+    }
+
     pub fn remove_job(&mut self, unique: &Bytes) {
         match self.jobs.get(unique) {
             None => {}
             Some(job) => {
                 self.remotes_by_handle.remove(&job.handle);
+                self.uniques_by_handle.remove(&job.handle);
+                self.index.lock().unwrap().remove(job, self.shard);
             }
         }
         self.jobs.remove(unique);
@@ -61,18 +508,205 @@ impl JobStorage {
         self.remotes_by_unique.get(unique)
     }
 
-    pub fn remotes_by_handle(&self, handle: &Bytes) -> Option<&Vec<usize>> {
+    pub fn remotes_by_handle(&self, handle: &JobHandle) -> Option<&Vec<usize>> {
         self.remotes_by_handle.get(handle)
     }
 }
 
+impl ShardedJobStorage {
+    fn new(shards: usize) -> ShardedJobStorage {
+        let index = Arc::new(Mutex::new(ShardIndex::default()));
+        ShardedJobStorage {
+            shards: (0..shards)
+                .map(|shard| Mutex::new(JobStorage::new(shard, index.clone())))
+                .collect(),
+            index: index,
+            dependencies: Mutex::new(Dependencies::default()),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// The shard `fname`'s jobs and queues are kept in
+    pub fn shard_of(&self, fname: &Bytes) -> usize {
+        let mut hasher = DefaultHasher::new();
+        fname.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    /// Locks the shard `fname`'s jobs and queues are kept in
+    pub fn shard(&self, fname: &Bytes) -> MutexGuard<'_, JobStorage> {
+        self.shards[self.shard_of(fname)].lock().unwrap()
+    }
+
+    /// Locks the shard holding the job with `handle`, if one does
+    pub fn shard_for_handle(&self, handle: &JobHandle) -> Option<MutexGuard<'_, JobStorage>> {
+        let shard = *self.index.lock().unwrap().by_handle.get(handle)?;
+        Some(self.shards[shard].lock().unwrap())
+    }
+
+    /// Locks every shard, in order, for views across all functions
+    pub fn lock_all(&self) -> Vec<MutexGuard<'_, JobStorage>> {
+        self.shards.iter().map(|shard| shard.lock().unwrap()).collect()
+    }
+
+    /// Finds a stored job by handle
+    pub fn job_by_handle(&self, handle: &JobHandle) -> Option<Arc<Job>> {
+        self.shard_for_handle(handle)?.job_by_handle(handle)
+    }
+
+    /// Removes the job with `handle` whether queued or running, and returns it
+    pub fn remove_by_handle(&self, handle: &JobHandle) -> Option<Arc<Job>> {
+        let mut storage = self.shard_for_handle(handle)?;
+        let job = storage.job_by_handle(handle)?;
+        storage.remove_job(&job.unique);
+        Some(job)
+    }
+
+    /// See [JobStorage::set_max_data_size]
+    pub fn set_max_data_size(&self, fname: Bytes, limit: Option<usize>) {
+        self.shard(&fname).set_max_data_size(fname, limit);
+    }
+
+    /// See [JobStorage::set_max_retries]
+    pub fn set_max_retries(&self, fname: Bytes, retries: usize) {
+        self.shard(&fname).set_max_retries(fname, retries);
+    }
+
+    /// See [JobStorage::set_replicator]
+    pub fn set_replicator(&self, replicator: Arc<dyn Replicator>) {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().set_replicator(replicator.clone());
+        }
+    }
+
+    /// See [JobStorage::set_aging_threshold]
+    pub fn set_aging_threshold(&self, threshold: Option<Duration>) {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().set_aging_threshold(threshold);
+        }
+    }
+
+    /// While draining, new submissions are refused but queued jobs are still handed out
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::SeqCst);
+    }
+
+    pub fn draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Number of jobs waiting in all queues
+    pub fn queued_count(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().queued_count())
+            .sum()
+    }
+
+    /// Whether a job for any function `worker` can do is waiting in a queue
+    pub fn has_job_for(&self, worker: &Worker) -> bool {
+        let shards: HashSet<usize> = worker.abilities().map(|fname| self.shard_of(fname)).collect();
+        shards
+            .into_iter()
+            .any(|shard| self.shards[shard].lock().unwrap().has_job_for(worker))
+    }
+
+    /// Every stored job, oldest first, at most `limit` of them
+    pub fn snapshot(&self, limit: usize) -> Vec<JobSnapshot> {
+        let now = Instant::now();
+        let dependencies = self.dependencies.lock().unwrap();
+        let blocked: HashMap<Bytes, JobQueuePriority> = dependencies
+            .blocked
+            .values()
+            .flatten()
+            .map(|(job, priority)| (job.unique.clone(), *priority))
+            .collect();
+        let mut jobs = Vec::new();
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().snapshot_into(&blocked, now, &mut jobs);
+        }
+        jobs.sort_by(|a, b| b.age.cmp(&a.age));
+        jobs.truncate(limit);
+        jobs
+    }
+
+    /// Queues jobs that were waiting on `unique`, returning their functions
+    pub fn release_dependents(&self, unique: &Bytes) -> Vec<Bytes> {
+        let mut dependencies = self.dependencies.lock().unwrap();
+        let mut released = Vec::new();
+        for (job, priority) in dependencies.blocked.remove(unique).unwrap_or_default() {
+            dependencies.waiting_on.remove(&job.unique);
+            let mut storage = self.shard(&job.fname);
+            // Skip jobs that were removed while they waited
+            if !storage.is_stored(&job) {
+                continue;
+            }
+            storage.queue_job(&job, priority);
+            released.push(job.fname.clone());
+        }
+        released
+    }
+
+    /// Removes every job waiting, directly or not, on `unique` and returns them
+    pub fn fail_dependents(&self, unique: &Bytes) -> Vec<Arc<Job>> {
+        let mut dependencies = self.dependencies.lock().unwrap();
+        let mut failed = Vec::new();
+        let mut pending = vec![unique.clone()];
+        while let Some(unique) = pending.pop() {
+            for (job, _) in dependencies.blocked.remove(&unique).unwrap_or_default() {
+                dependencies.waiting_on.remove(&job.unique);
+                self.shard(&job.fname).remove_job(&job.unique);
+                pending.push(job.unique.clone());
+                failed.push(job);
+            }
+        }
+        failed
+    }
+
+    /// Whether a job with `unique` is stored in any shard
+    fn has_unique(&self, unique: &Bytes) -> bool {
+        self.index.lock().unwrap().by_unique.contains_key(unique)
+    }
+}
+
+/// Moves jobs older than `threshold` from the front of the normal and low queues up one level
+///
+/// Promoted jobs keep their place by age in the queue they join.
+fn promote_aged(prios: &mut [JobQueue; 3], threshold: Duration) {
+    for from in PRIORITY_NORMAL..=PRIORITY_LOW {
+        while let Some(front) = prios[from].front() {
+            let job = match front.upgrade() {
+                None => {
+                    prios[from].pop_front();
+                    continue;
+                }
+                Some(job) => job,
+            };
+            if job.created_at.elapsed() <= threshold {
+                break;
+            }
+            let aged = prios[from].pop_front().unwrap();
+            let to = &mut prios[from - 1];
+            let at = to
+                .iter()
+                .position(|queued| match queued.upgrade() {
+                    Some(queued) => queued.created_at > job.created_at,
+                    None => false,
+                })
+                .unwrap_or(to.len());
+            to.insert(at, aged);
+        }
+    }
+}
+
 impl HandleJobStorage for SharedJobStorage {
     fn new_job_storage() -> SharedJobStorage {
-        Arc::new(Mutex::new(JobStorage::new()))
+        Arc::new(ShardedJobStorage::new(JOB_STORAGE_SHARDS))
     }
 
-    fn coalesce_unique(&mut self, unique: &Bytes, remote: Option<usize>) -> Option<Bytes> {
-        let mut storage = self.lock().unwrap();
+    fn coalesce_unique(&mut self, unique: &Bytes, remote: Option<usize>) -> Option<JobHandle> {
+        let shard = *self.index.lock().unwrap().by_unique.get(unique)?;
+        let mut storage = self.shards[shard].lock().unwrap();
         let handle = match storage.jobs.get(unique) {
             None => return None,
             Some(job) => job.handle.clone(),
@@ -106,7 +740,12 @@ impl HandleJobStorage for SharedJobStorage {
         Some(handle)
     }
 
-    fn add_job(&mut self, job: Arc<Job>, priority: JobQueuePriority, remote: Option<usize>) {
+    fn add_job(
+        &mut self,
+        job: Arc<Job>,
+        priority: JobQueuePriority,
+        remote: Option<usize>,
+    ) -> Result<(), EnqueueError> {
         let job = job.clone();
         trace!(
             "job {:?} weak = {} strong = {}",
@@ -114,78 +753,86 @@ impl HandleJobStorage for SharedJobStorage {
             Arc::weak_count(&job),
             Arc::strong_count(&job)
         );
-        let mut storage = self.lock().unwrap();
-        {
-            let func_queues = storage.queues.entry(job.fname.clone()).or_insert_with(|| {
-                let high_queue = VecDeque::new();
-                let norm_queue = VecDeque::new();
-                let low_queue = VecDeque::new();
-                [high_queue, norm_queue, low_queue]
-            });
-            func_queues[priority].push_back(Arc::downgrade(&job.clone()));
-        }
-        storage.jobs.insert(job.unique.clone(), job.clone());
+        let mut storage = self.shard(&job.fname);
+        storage.check_data_size(&job)?;
+        storage.queue_job(&job, priority);
+        storage.store_job(&job, priority, remote);
         trace!(
             "job {:?} weak = {} strong = {}",
             &job,
             Arc::weak_count(&job),
             Arc::strong_count(&job)
         );
-        let mut remotes_by_unique = HashSet::with_capacity(INIT_JOB_REMOTES_CAPACITY);
-        let mut remotes_by_handle = Vec::with_capacity(INIT_JOB_REMOTES_CAPACITY);
-        match remote {
-            None => {}
-            Some(remote) => {
-                remotes_by_unique.insert(remote);
-                remotes_by_handle.push(remote);
+        Ok(())
+    }
+
+    /// Stores `job` but holds it off the queue until the job with unique
+    /// `dependency` completes. Unknown dependencies are treated as done.
+    fn add_dependent_job(
+        &mut self,
+        job: Arc<Job>,
+        priority: JobQueuePriority,
+        remote: Option<usize>,
+        dependency: Bytes,
+    ) -> Result<(), EnqueueError> {
+        {
+            // Held throughout, so the dependency can't complete before the job is blocked on it
+            let mut dependencies = self.dependencies.lock().unwrap();
+            if self.has_unique(&dependency) {
+                self.shard(&job.fname).check_data_size(&job)?;
+                // Follow the chain from the dependency, it must not lead back to this job
+                let mut next = Some(&dependency);
+                while let Some(unique) = next {
+                    if unique == &job.unique {
+                        return Err(EnqueueError::DependencyCycle);
+                    }
+                    next = dependencies.waiting_on.get(unique);
+                }
+                self.shard(&job.fname).store_job(&job, priority, remote);
+                dependencies
+                    .waiting_on
+                    .insert(job.unique.clone(), dependency.clone());
+                dependencies
+                    .blocked
+                    .entry(dependency)
+                    .or_insert_with(Vec::new)
+                    .push((job, priority));
+                return Ok(());
             }
         }
-        storage
-            .remotes_by_unique
-            .insert(job.unique.clone(), remotes_by_unique);
-        storage
-            .remotes_by_handle
-            .insert(job.handle.clone(), remotes_by_handle);
-        trace!(
-            "job {:?} weak = {} strong = {}",
-            &job,
-            Arc::weak_count(&job),
-            Arc::strong_count(&job)
-        );
+        self.add_job(job, priority, remote)
     }
 
+    /// Snapshot of the functions that have at least one job waiting
+    fn functions_with_queued(&self) -> Vec<Vec<u8>> {
+        let mut functions = Vec::new();
+        for storage in self.shards.iter() {
+            let storage = storage.lock().unwrap();
+            functions.extend(
+                storage
+                    .queues
+                    .iter()
+                    .filter(|(_, prios)| {
+                        prios
+                            .iter()
+                            .any(|q| q.iter().any(|job| job.upgrade().is_some()))
+                    })
+                    .map(|(fname, _)| fname.to_vec()),
+            );
+        }
+        functions
+    }
+
+    /// Only the shards of the worker's functions are locked, one at a time
     fn get_job(&mut self, worker: &mut Worker) -> Option<Arc<Job>> {
-        let mut storage = self.lock().unwrap();
         let mut job: Option<Arc<Job>> = None;
         debug!("{:?}", &worker);
+        // The worker's functions rotate, so each grab starts after the
+        // function the last one came from and no backlog starves the rest
         for func in worker.iter() {
-            debug!("func = {:?}", &func);
-            match storage.queues.get_mut(&func) {
-                None => {}
-                Some(prios) => {
-                    debug!("found func with {} priority queues", prios.len());
-                    let mut i = 0;
-                    for q in prios {
-                        debug!("searching priority {}", i);
-                        i = i + 1;
-                        loop {
-                            debug!("Queue has items! {:?}", q.len());
-                            match q.pop_front() {
-                                None => break,
-                                Some(a_job) => match a_job.upgrade() {
-                                    None => trace!("Deleted job encountered."),
-                                    Some(a_job) => {
-                                        job = Some(a_job);
-                                        break;
-                                    }
-                                },
-                            }
-                        }
-                        if job.is_some() {
-                            break;
-                        }
-                    }
-                }
+            job = self.shard(&func).take_job(&func);
+            if job.is_some() {
+                break;
             }
         }
         match job {
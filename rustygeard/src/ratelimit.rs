@@ -0,0 +1,32 @@
+use std::time::Instant;
+
+/// Allows `rate` events per second, with bursts of up to `rate`
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u32,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32) -> TokenBucket {
+        TokenBucket {
+            rate: rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes one token, returning false if none are left
+    pub fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
@@ -0,0 +1,39 @@
+use std::fmt;
+use std::sync::Arc;
+
+use rustygear::job::Job;
+
+use crate::queues::JobQueuePriority;
+
+/// A change to the stored jobs, as seen by a [Replicator]
+#[derive(Clone, Debug)]
+pub enum ReplicationEvent {
+    /// A job was stored, queued at `priority` or held for a dependency
+    Submitted {
+        job: Arc<Job>,
+        priority: JobQueuePriority,
+    },
+    /// A job was finished by WORK_COMPLETE and removed
+    Completed(Arc<Job>),
+    /// A job failed for good, after any retries, and was removed
+    Failed(Arc<Job>),
+}
+
+/// Told about every job submitted, completed or failed, e.g. to forward
+/// them to a standby server
+///
+/// Set one with [ServerConfig::replicator](crate::config::ServerConfig::replicator).
+/// It is called with the job's storage shard locked, so events for a job
+/// arrive in the order they happened, and it should hand them off rather
+/// than block.
+pub trait Replicator: fmt::Debug + Send + Sync {
+    fn replicate(&self, event: ReplicationEvent);
+}
+
+/// Replicates nothing, the default
+#[derive(Debug, Default)]
+pub struct NoReplication;
+
+impl Replicator for NoReplication {
+    fn replicate(&self, _event: ReplicationEvent) {}
+}
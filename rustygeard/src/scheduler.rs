@@ -0,0 +1,51 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Decides which sleeping worker is woken for a newly queued job
+///
+/// Set one with [ServerConfig::scheduler](crate::config::ServerConfig::scheduler).
+pub trait Scheduler: fmt::Debug + Send + Sync {
+    /// Picks one of `candidates`, the sleeping workers that can do `function`
+    ///
+    /// Candidates come least recently assigned a job first, ties by conn id.
+    /// Returning `None` wakes nobody.
+    fn pick_worker(&self, function: &[u8], candidates: &[usize]) -> Option<usize>;
+}
+
+/// Wakes the worker that has waited longest for a job, so idle workers take turns
+#[derive(Debug, Default)]
+pub struct RoundRobin;
+
+impl Scheduler for RoundRobin {
+    fn pick_worker(&self, _function: &[u8], candidates: &[usize]) -> Option<usize> {
+        candidates.first().cloned()
+    }
+}
+
+/// Wakes workers in a fixed order, for tests that need to know who gets a job
+///
+/// Each pick takes the next conn id from the script, waking nobody if that
+/// worker isn't a candidate or the script has run out.
+#[derive(Debug)]
+pub struct Scripted {
+    script: Mutex<VecDeque<usize>>,
+}
+
+impl Scripted {
+    pub fn new(script: Vec<usize>) -> Scripted {
+        Scripted {
+            script: Mutex::new(VecDeque::from(script)),
+        }
+    }
+}
+
+impl Scheduler for Scripted {
+    fn pick_worker(&self, _function: &[u8], candidates: &[usize]) -> Option<usize> {
+        let next = self.script.lock().unwrap().pop_front()?;
+        match candidates.contains(&next) {
+            true => Some(next),
+            false => None,
+        }
+    }
+}
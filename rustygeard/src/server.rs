@@ -1,112 +1,573 @@
 use std::collections::{HashMap, BTreeMap};
 use std::convert::TryInto;
+use std::io;
 use std::net::SocketAddr;
-use std::sync::atomic::AtomicUsize;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::os::unix::io::AsRawFd;
+use std::thread;
+use std::time::Duration;
 
-use futures::stream::StreamExt;
+use bytes::Bytes;
+use futures::stream::{self, StreamExt};
 use futures::SinkExt;
-use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{duplex, DuplexStream};
+use tokio::net::{TcpListener, TcpSocket};
 use tokio::runtime;
-use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::{channel, unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::{oneshot, watch};
+use tokio::time::{sleep, timeout};
 use tokio_util::codec::Decoder;
 use tower_service::Service;
 
 use rustygear::codec::{Packet, PacketCodec};
+use rustygear::constants::*;
+use rustygear::util::new_res;
 
+use crate::config::ServerConfig;
+use crate::log_context::LogContext;
 use crate::queues::{HandleJobStorage, SharedJobStorage};
-use crate::service::GearmanService;
+use crate::ratelimit::TokenBucket;
+use crate::service::{
+    check_outbound_depth, ConnectionGuard, GearmanService, JobWaiters, SendersByConnId,
+    WorkersByConnId,
+};
 use crate::worker::{SharedWorkers, Wake};
 
-pub struct GearmanServer;
+/// How long shutdown waits for connections to hang up once told to
+const CLOSE_GRACE: Duration = Duration::from_secs(1);
 
-const MAX_UNHANDLED_OUT_FRAMES: usize = 1024;
+/// Bytes each direction of an in-memory connection buffers before writes wait
+const IN_MEMORY_BUFFER: usize = 64 * 1024;
+
+/// Next connection id for [GearmanServer::connect_in_memory]
+static NEXT_IN_MEMORY_CONN_ID: AtomicUsize = AtomicUsize::new(usize::MAX / 2);
+
+/// How the admin `shutdown` command asked the server to stop
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownMode {
+    Immediate,
+    /// Stop accepting connections and submissions, wait for queued and
+    /// assigned jobs to finish, then close the open connections
+    Graceful,
+}
+
+/// A server running on its own thread, see [GearmanServer::start]
+pub struct ServerHandle {
+    shutdown_tx: UnboundedSender<ShutdownMode>,
+    progress_rx: watch::Receiver<Option<usize>>,
+    thread: thread::JoinHandle<()>,
+    local_addr: SocketAddr,
+}
+
+impl ServerHandle {
+    /// The address the server is listening on, with the port the OS picked
+    /// if the config asked for port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting connections and waits for the server thread to exit
+    pub fn shutdown(self) {
+        self.stop(ShutdownMode::Immediate)
+    }
+
+    /// Like [ServerHandle::shutdown], but first waits for jobs to finish, see [ShutdownMode::Graceful]
+    pub fn shutdown_graceful(self) {
+        self.stop(ShutdownMode::Graceful)
+    }
+
+    /// Jobs a graceful shutdown is still waiting on, `None` until one starts
+    pub fn shutdown_progress(&self) -> watch::Receiver<Option<usize>> {
+        self.progress_rx.clone()
+    }
+
+    /// Blocks until the server stops on its own, e.g. by an admin `shutdown`
+    pub fn join(self) {
+        if self.thread.join().is_err() {
+            error!("Server thread panicked");
+        }
+    }
+
+    fn stop(self, mode: ShutdownMode) {
+        // Fails only if the server already stopped, which is what was asked for
+        let _ = self.shutdown_tx.send(mode);
+        self.join()
+    }
+}
+
+/// The shutdown a reply asks for once it has been written, if any
+fn shutdown_mode(packet: &Packet) -> Option<ShutdownMode> {
+    match packet.ptype {
+        ADMIN_SHUTDOWN => Some(ShutdownMode::Immediate),
+        ADMIN_SHUTDOWN_GRACEFUL => Some(ShutdownMode::Graceful),
+        _ => None,
+    }
+}
+
+/// Counts one open connection until dropped
+struct ConnectionSlot {
+    connections: Arc<AtomicUsize>,
+}
+
+impl ConnectionSlot {
+    /// Takes a slot unless `max` connections are already open
+    fn acquire(connections: &Arc<AtomicUsize>, max: Option<usize>) -> Option<ConnectionSlot> {
+        let max = max.unwrap_or(usize::MAX);
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                if open < max {
+                    Some(open + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()?;
+        Some(ConnectionSlot {
+            connections: connections.clone(),
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Holds the state shared by every connection to one server
+pub struct GearmanServer {
+    config: ServerConfig,
+    shutdown_tx: UnboundedSender<ShutdownMode>,
+    shutdown_rx: Option<UnboundedReceiver<ShutdownMode>>,
+    progress_tx: watch::Sender<Option<usize>>,
+    progress_rx: watch::Receiver<Option<usize>>,
+    queues: SharedJobStorage,
+    workers: SharedWorkers,
+    job_count: Arc<AtomicUsize>,
+    senders_by_conn_id: SendersByConnId,
+    workers_by_conn_id: WorkersByConnId,
+    job_waiters: JobWaiters,
+    connections: Arc<AtomicUsize>,
+}
 
 impl GearmanServer {
-    pub fn run(addr: SocketAddr) {
+    pub fn with_config(config: ServerConfig) -> GearmanServer {
+        let (shutdown_tx, shutdown_rx) = unbounded_channel();
+        let (progress_tx, progress_rx) = watch::channel(None);
         let queues = SharedJobStorage::new_job_storage();
-        let workers = SharedWorkers::new_workers();
-        let job_count = Arc::new(AtomicUsize::new(0));
-        let senders_by_conn_id = Arc::new(Mutex::new(HashMap::new()));
-        let workers_by_conn_id = Arc::new(Mutex::new(BTreeMap::new()));
-        let job_waiters = Arc::new(Mutex::new(HashMap::new()));
-        let mut rt = runtime::Runtime::new().unwrap();
+        queues.set_aging_threshold(config.aging_threshold);
+        for (fname, limit) in config.max_data_sizes.iter() {
+            queues.set_max_data_size(fname.clone(), Some(*limit));
+        }
+        if let Some(ref replicator) = config.replicator {
+            queues.set_replicator(replicator.clone());
+        }
+        let mut workers = SharedWorkers::new_workers();
+        if let Some(ref scheduler) = config.scheduler {
+            workers.set_scheduler(scheduler.clone());
+        }
+        GearmanServer {
+            config: config,
+            shutdown_tx: shutdown_tx,
+            shutdown_rx: Some(shutdown_rx),
+            progress_tx: progress_tx,
+            progress_rx: progress_rx,
+            queues: queues,
+            workers: workers,
+            job_count: Arc::new(AtomicUsize::new(0)),
+            senders_by_conn_id: Arc::new(Mutex::new(HashMap::new())),
+            workers_by_conn_id: Arc::new(Mutex::new(BTreeMap::new())),
+            job_waiters: Arc::new(Mutex::new(HashMap::new())),
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Runs a server on `addr` with the default config until an admin `shutdown`
+    ///
+    /// Use [GearmanServer::start] for a server that can be stopped from code.
+    pub fn run(addr: SocketAddr) {
+        let config = ServerConfig {
+            listen: addr,
+            ..ServerConfig::default()
+        };
+        match GearmanServer::with_config(config).start() {
+            Ok(handle) => handle.join(),
+            Err(e) => error!("Could not listen on {}: {}", addr, e),
+        }
+    }
+
+    /// Runs a server on the standard gearman port on every interface, see [GearmanServer::run]
+    pub fn run_default() {
+        GearmanServer::run(ServerConfig::default().listen)
+    }
+
+    /// Serves on a new thread, returning a handle to stop it
+    ///
+    /// Returns once the listener is bound, or with the error binding it.
+    pub fn start(self) -> Result<ServerHandle, io::Error> {
+        let shutdown_tx = self.shutdown_tx.clone();
+        let progress_rx = self.progress_rx.clone();
+        let (bound_tx, bound_rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            // Never fired, the handle stops the server through shutdown_tx instead
+            let (_stop_tx, stop_rx) = oneshot::channel();
+            self.serve_reporting(stop_rx, Some(bound_tx));
+        });
+        let bound = bound_rx.recv().unwrap_or_else(|_| {
+            Err(io::Error::new(io::ErrorKind::Other, "Server thread exited before binding"))
+        });
+        match bound {
+            Ok(local_addr) => Ok(ServerHandle {
+                shutdown_tx: shutdown_tx,
+                progress_rx: progress_rx,
+                thread: thread,
+                local_addr: local_addr,
+            }),
+            Err(e) => {
+                let _ = thread.join();
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs a server on `addr` with the default config until `stop_rx` fires
+    pub fn run_with_stop(addr: SocketAddr, stop_rx: oneshot::Receiver<()>) {
+        let config = ServerConfig {
+            listen: addr,
+            ..ServerConfig::default()
+        };
+        GearmanServer::with_config(config).serve(stop_rx);
+    }
+
+    /// Listens and serves connections until `stop_rx` fires or its sender is
+    /// dropped, or an admin `shutdown` command is received
+    pub fn serve(self, stop_rx: oneshot::Receiver<()>) {
+        self.serve_reporting(stop_rx, None)
+    }
+
+    /// Like [GearmanServer::serve], sending the bound address, or the error
+    /// binding, on `bound_tx` before accepting connections
+    fn serve_reporting(
+        mut self,
+        mut stop_rx: oneshot::Receiver<()>,
+        bound_tx: Option<mpsc::Sender<Result<SocketAddr, io::Error>>>,
+    ) {
+        let mut builder = runtime::Builder::new_multi_thread();
+        if let Some(threads) = self.config.threads {
+            builder.worker_threads(threads);
+        }
+        let rt = builder.enable_all().build().unwrap();
+        let mut shutdown_rx = self.shutdown_rx.take().unwrap();
         rt.block_on(async move {
-            let mut listener = TcpListener::bind(&addr).await.unwrap();
-            let mut incoming = listener.incoming();
-            while let Some(socket_res) = incoming.next().await {
-                match socket_res {
-                    Ok(sock) => {
-                        let conn_id: usize = sock.as_raw_fd().try_into().unwrap();
-                        let peer_addr = sock.peer_addr().unwrap_or("0.0.0.0:0".parse().unwrap());
-                        let pc = PacketCodec {};
-                        let (mut sink, mut stream) = pc.framed(sock).split();
-                        let (tx, mut rx) = channel::<Packet>(MAX_UNHANDLED_OUT_FRAMES);
-                        {
-                            let senders_by_conn_id = senders_by_conn_id.clone();
-                            let mut senders_by_conn_id = senders_by_conn_id.lock().unwrap();
-                            senders_by_conn_id.insert(conn_id, tx.clone());
-                        }
-                        // Read stuff, write if needed
-                        let senders_by_conn_id = senders_by_conn_id.clone();
-                        let workers_by_conn_id = workers_by_conn_id.clone();
-                        let senders_by_conn_id_w = senders_by_conn_id.clone();
-                        let workers_by_conn_id_w = workers_by_conn_id.clone();
-                        let queues = queues.clone();
-                        let workers = workers.clone();
-                        let job_count = job_count.clone();
-                        let job_waiters = job_waiters.clone();
-                        let reader = async move {
-                            let mut service = GearmanService::new(
-                                conn_id,
-                                queues,
-                                workers,
-                                job_count,
-                                senders_by_conn_id,
-                                workers_by_conn_id.clone(),
-                                job_waiters,
-                                peer_addr,
-                            );
-                            {
-                                let mut workers_by_conn_id = workers_by_conn_id.lock().unwrap();
-                                workers_by_conn_id.insert(conn_id, service.worker.clone());
-                            }
-                            let mut tx = tx.clone();
-                            while let Some(frame) = stream.next().await {
-                                let response = service.call(frame.unwrap()).await;
-                                if let Ok(response) = response {
-                                    if let Err(_) = tx.send(response).await {
-                                        error!("receiver dropped!")
-                                    }
-                                }
+            let bound = self.bind().and_then(|listener| {
+                let addr = listener.local_addr()?;
+                Ok((listener, addr))
+            });
+            let (listener, addr) = match (bound, bound_tx) {
+                (Ok((listener, addr)), Some(bound_tx)) => {
+                    let _ = bound_tx.send(Ok(addr));
+                    (listener, addr)
+                }
+                (Ok(bound), None) => bound,
+                (Err(e), Some(bound_tx)) => {
+                    let _ = bound_tx.send(Err(e));
+                    return;
+                }
+                (Err(e), None) => panic!("Could not listen on {}: {}", self.config.listen, e),
+            };
+            let mode = loop {
+                tokio::select! {
+                    _ = &mut stop_rx => {
+                        info!("Stopping server on {}", addr);
+                        break ShutdownMode::Immediate;
+                    }
+                    Some(mode) = shutdown_rx.recv() => {
+                        info!("Admin shutdown ({:?}) of server on {}", mode, addr);
+                        break mode;
+                    }
+                    socket_res = listener.accept() => match socket_res {
+                        Ok((sock, peer_addr)) => {
+                            if let Err(e) = sock.set_nodelay(self.config.nodelay) {
+                                warn!("Could not set TCP_NODELAY for {}: {}", peer_addr, e);
                             }
-                        };
-
-                        let writer = async move {
-                            while let Some(packet) = rx.next().await {
-                                trace!("Sending {:?}", &packet);
-                                if let Err(_) = sink.send(packet).await {
-                                    {
-                                        let mut workers_by_conn_id = workers_by_conn_id_w.lock().unwrap();
-                                        workers_by_conn_id.remove(&conn_id);
-                                    }
-                                    {
-                                        let mut senders_by_conn_id = senders_by_conn_id_w.lock().unwrap();
-                                        senders_by_conn_id.remove(&conn_id);
-                                    }
-                                    error!("Connection ({}) dropped", conn_id);
-                                }
+                            let conn_id: usize = sock.as_raw_fd().try_into().unwrap();
+                            self.handle_connection(sock, conn_id, peer_addr);
+                        }
+                        Err(e) => {
+                            error!("{}", e);
+                        }
+                    }
+                }
+            };
+            // No connection is accepted from here on, but open ones are served
+            drop(listener);
+            if mode == ShutdownMode::Graceful {
+                self.set_draining(true);
+                self.wait_for_jobs().await;
+                self.close_connections().await;
+            }
+        })
+    }
+
+    /// Binds the listener with the socket options from the config
+    fn bind(&self) -> Result<TcpListener, io::Error> {
+        let addr = self.config.listen;
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.set_reuseaddr(self.config.reuseaddr)?;
+        socket.bind(addr)?;
+        socket.listen(self.config.backlog)
+    }
+
+    /// Refuses new submissions while still handing queued jobs to workers
+    pub fn set_draining(&self, draining: bool) {
+        self.queues.set_draining(draining);
+    }
+
+    /// Jobs assigned to a worker, plus queued ones a connected worker can do
+    fn remaining_jobs(&self) -> usize {
+        let assigned: usize = {
+            let workers_by_conn_id = self.workers_by_conn_id.lock().unwrap();
+            workers_by_conn_id
+                .values()
+                .map(|worker| worker.lock().unwrap().assigned_count())
+                .sum()
+        };
+        let functions = self.workers.functions();
+        let queued: usize = functions
+            .into_iter()
+            .map(Bytes::from)
+            .map(|fname| self.queues.shard(&fname).queued_for(&fname))
+            .sum();
+        assigned + queued
+    }
+
+    /// Waits until [GearmanServer::remaining_jobs] is 0, reporting it on the
+    /// channel from [ServerHandle::shutdown_progress] as it goes
+    async fn wait_for_jobs(&self) {
+        loop {
+            let remaining = self.remaining_jobs();
+            let _ = self.progress_tx.send(Some(remaining));
+            if remaining == 0 {
+                return;
+            }
+            debug!("Waiting for {} jobs before shutdown", remaining);
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Hangs up every connection, waiting a moment for them to go
+    async fn close_connections(&self) {
+        for worker in self.workers_by_conn_id.lock().unwrap().values() {
+            worker.lock().unwrap().close();
+        }
+        let closed = async {
+            while !self.senders_by_conn_id.lock().unwrap().is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        };
+        if timeout(CLOSE_GRACE, closed).await.is_err() {
+            warn!("Connections still open at shutdown");
+        }
+    }
+
+    /// Spawns a server on the current runtime without a listener
+    ///
+    /// Returns a (client, worker) pair of connected streams. They are
+    /// in-process pipes, no socket of any kind is opened, which makes this
+    /// suitable for driving the protocol from tests. Must be called from
+    /// within a tokio runtime.
+    pub fn spawn_in_memory() -> (DuplexStream, DuplexStream) {
+        let server = GearmanServer::with_config(ServerConfig::default());
+        (server.connect_in_memory(), server.connect_in_memory())
+    }
+
+    /// Connects a new in-memory stream to this server, see [GearmanServer::spawn_in_memory]
+    pub fn connect_in_memory(&self) -> DuplexStream {
+        let (local, remote) = duplex(IN_MEMORY_BUFFER);
+        // Without a file descriptor to use, ids are counted from well above any fd
+        let conn_id = NEXT_IN_MEMORY_CONN_ID.fetch_add(1, Ordering::Relaxed);
+        self.handle_connection(remote, conn_id, "0.0.0.0:0".parse().unwrap());
+        local
+    }
+
+    /// Spawns the reader and writer for one connection onto the current runtime
+    fn handle_connection<S>(&self, sock: S, conn_id: usize, peer_addr: SocketAddr)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let pc = PacketCodec::new()
+            .with_max_admin_line(self.config.max_admin_line)
+            .with_max_packet_size(self.config.max_packet_size);
+        let slot = match ConnectionSlot::acquire(&self.connections, self.config.max_connections) {
+            Some(slot) => slot,
+            None => {
+                warn!(
+                    "{} Refusing connection from {}, too many open",
+                    LogContext::conn(conn_id),
+                    peer_addr
+                );
+                let mut framed = pc.framed(sock);
+                let refusal = new_res(
+                    ERROR,
+                    Bytes::from_static(b"too_many_connections\0Server is at its connection limit"),
+                );
+                runtime::Handle::current().spawn(async move {
+                    let _ = framed.send(refusal).await;
+                });
+                return;
+            }
+        };
+        let (mut sink, mut stream) = pc.framed(sock).split();
+        let (tx, mut rx) = channel::<Packet>(self.config.max_unhandled_out_frames);
+        {
+            let mut senders_by_conn_id = self.senders_by_conn_id.lock().unwrap();
+            senders_by_conn_id.insert(conn_id, tx.clone());
+        }
+        // Read stuff, write if needed
+        let senders_by_conn_id = self.senders_by_conn_id.clone();
+        let workers_by_conn_id = self.workers_by_conn_id.clone();
+        let senders_by_conn_id_r = senders_by_conn_id.clone();
+        let senders_by_conn_id_w = senders_by_conn_id.clone();
+        let workers_by_conn_id_w = workers_by_conn_id.clone();
+        let queues = self.queues.clone();
+        let workers = self.workers.clone();
+        let job_count = self.job_count.clone();
+        let job_waiters = self.job_waiters.clone();
+        let idle_timeout = self.config.idle_timeout;
+        let allow_remote_shutdown = self.config.allow_remote_shutdown;
+        let shutdown_tx = self.shutdown_tx.clone();
+        let max_submits_per_sec = self.config.max_submits_per_sec;
+        let handles = self.config.handles.clone();
+        let slow_packet_threshold = self.config.slow_packet_threshold;
+        let max_admin_rows = self.config.max_admin_rows;
+        let reject_unknown_functions = self.config.reject_unknown_functions;
+        let outbound_high_water = self.config.outbound_high_water;
+        let authenticator = self.config.authenticator.clone();
+        let reader = async move {
+            // Declared before the service so it is dropped after it
+            let _slot = slot;
+            let _guard = ConnectionGuard::new(
+                conn_id,
+                senders_by_conn_id_r,
+                workers_by_conn_id.clone(),
+            );
+            let mut service = GearmanService::new(
+                conn_id,
+                queues,
+                workers,
+                job_count,
+                senders_by_conn_id,
+                workers_by_conn_id.clone(),
+                job_waiters,
+                peer_addr,
+            );
+            if allow_remote_shutdown {
+                service.allow_shutdown = true;
+            }
+            service.submit_limit = max_submits_per_sec.map(TokenBucket::new);
+            service.slow_packet_threshold = slow_packet_threshold;
+            service.max_admin_rows = max_admin_rows;
+            service.reject_unknown_functions = reject_unknown_functions;
+            service.outbound_high_water = outbound_high_water;
+            service.authenticator = authenticator;
+            if let Some(handles) = handles {
+                service.handles = handles;
+            }
+            {
+                let mut workers_by_conn_id = workers_by_conn_id.lock().unwrap();
+                workers_by_conn_id.insert(conn_id, service.worker.clone());
+            }
+            let tx = tx.clone();
+            let closer = service.worker.lock().unwrap().closer.clone();
+            loop {
+                let next = async {
+                    match idle_timeout {
+                        None => Ok(stream.next().await),
+                        Some(idle_timeout) => timeout(idle_timeout, stream.next()).await,
+                    }
+                };
+                let frame = tokio::select! {
+                    _ = closer.notified() => {
+                        info!("{} Closing on admin request", LogContext::conn(conn_id));
+                        break;
+                    }
+                    next = next => match next {
+                        Ok(frame) => frame,
+                        Err(_) => {
+                            if service.worker.lock().unwrap().sleeping {
+                                continue;
                             }
-                        };
-                        runtime::Handle::current().spawn(reader);
-                        runtime::Handle::current().spawn(writer);
+                            info!("{} Closing idle connection", LogContext::conn(conn_id));
+                            break;
+                        }
+                    },
+                };
+                let frame = match frame {
+                    None => break,
+                    Some(Ok(frame)) => frame,
+                    Some(Err(e)) => {
+                        warn!("{} Closing after bad input: {}", LogContext::conn(conn_id), e);
+                        let error = format!("bad_request\0{}", e);
+                        let _ = tx.send(new_res(ERROR, Bytes::from(error))).await;
+                        break;
                     }
-                    Err(e) => {
-                        error!("{}", e);
+                };
+                let response = service.call(frame).await;
+                if let Ok(response) = response {
+                    if let Err(_) = tx.send(response).await {
+                        error!("{} receiver dropped!", LogContext::conn(conn_id))
+                    } else {
+                        check_outbound_depth(conn_id, &tx, outbound_high_water);
                     }
                 }
             }
-        })
+            // Dropping the guard removes our senders, which ends the writer
+            // and closes the connection
+        };
+
+        let writer = async move {
+            while let Some(packet) = rx.recv().await {
+                // Everything already queued goes out with a single flush, but
+                // nothing after a shutdown reply
+                let mut shutdown = shutdown_mode(&packet);
+                let mut batch = vec![packet];
+                while shutdown.is_none() {
+                    match rx.try_recv() {
+                        Ok(packet) => {
+                            shutdown = shutdown_mode(&packet);
+                            batch.push(packet);
+                        }
+                        Err(_) => break,
+                    }
+                }
+                for packet in batch.iter() {
+                    trace!("{} Sending {:?}", LogContext::conn(conn_id), packet);
+                }
+                let mut packets = stream::iter(batch.into_iter().map(Ok));
+                if let Err(_) = sink.send_all(&mut packets).await {
+                    {
+                        let mut workers_by_conn_id = workers_by_conn_id_w.lock().unwrap();
+                        workers_by_conn_id.remove(&conn_id);
+                    }
+                    {
+                        let mut senders_by_conn_id = senders_by_conn_id_w.lock().unwrap();
+                        senders_by_conn_id.remove(&conn_id);
+                    }
+                    error!("{} Connection dropped", LogContext::conn(conn_id));
+                    // Only this connection is lost, stop writing to it
+                    break;
+                } else if let Some(mode) = shutdown {
+                    let _ = shutdown_tx.send(mode);
+                    break;
+                }
+            }
+        };
+        runtime::Handle::current().spawn(reader);
+        runtime::Handle::current().spawn(writer);
     }
 }
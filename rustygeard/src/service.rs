@@ -2,42 +2,152 @@ use std::collections::{HashMap, BTreeMap};
 use std::io;
 use std::ops::Drop;
 use std::pin::Pin;
+use std::process;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::net::SocketAddr;
+use std::str;
+use std::time::{Duration, Instant};
 
 use core::task::{Context, Poll};
 
 use futures::Future;
 use tokio::runtime;
 use tokio::sync::mpsc::Sender;
+use tokio::time::sleep;
 use tower_service::Service;
 
 use bytes::{BufMut, Bytes, BytesMut};
 
 use rustygear::codec::{Packet, PacketMagic};
 use rustygear::constants::*;
-use rustygear::job::Job;
-use rustygear::util::{new_res, next_field, no_response};
+use rustygear::job::{Job, JobHandle};
+use rustygear::util::{decode_metadata, encode_metadata, new_res, next_field, no_response, psize};
 
 use crate::admin;
+use crate::auth::Authenticator;
+use crate::handles::{CounterHandles, HandleGenerator};
+use crate::log_context::LogContext;
 use crate::queues::{HandleJobStorage, JobQueuePriority, SharedJobStorage};
-use crate::worker::{SharedWorkers, Wake, Worker};
+use crate::ratelimit::TokenBucket;
+use crate::replication::ReplicationEvent;
+use crate::worker::{ConnectionInfo, SharedWorkers, Wake, Worker};
+
+/// Prefix of the OPTION_REQ carrying a connection's token, see [Authenticator]
+const AUTH: &[u8] = b"auth=";
 
 fn new_noop() -> Packet {
     new_res(NOOP, Bytes::new())
 }
 
-type JobWaiters = Arc<Mutex<HashMap<Bytes, Vec<usize>>>>;
-type SendersByConnId = Arc<Mutex<HashMap<usize, Sender<Packet>>>>;
+fn new_error(code: &str, text: &str) -> Packet {
+    let mut data = BytesMut::with_capacity(code.len() + 1 + text.len());
+    data.extend(code.as_bytes());
+    data.put_u8(b'\0');
+    data.extend(text.as_bytes());
+    new_res(ERROR, data.freeze())
+}
+
+/// The data JOB_ASSIGN carries for `job`, with its metadata if `worker` asked for it
+fn assigned_data(worker: &Worker, job: &Job) -> Bytes {
+    match worker.metadata {
+        true => encode_metadata(&job.metadata, &job.data),
+        false => job.data.clone(),
+    }
+}
+
+pub type JobWaiters = Arc<Mutex<HashMap<JobHandle, Vec<usize>>>>;
+pub type SendersByConnId = Arc<Mutex<HashMap<usize, Sender<Packet>>>>;
 pub type WorkersByConnId = Arc<Mutex<BTreeMap<usize, Arc<Mutex<Worker>>>>>;
 
+/// Packets queued for a connection that its writer hasn't sent yet
+pub fn outbound_depth(tx: &Sender<Packet>) -> usize {
+    tx.max_capacity() - tx.capacity()
+}
+
+/// Warns if the packets queued for connection `conn_id` just reached `high_water`
+///
+/// Meant to be called after each send, so a peer that stopped reading is
+/// reported once as its queue climbs rather than for every packet after.
+/// Returns whether it warned.
+pub fn check_outbound_depth(conn_id: usize, tx: &Sender<Packet>, high_water: Option<usize>) -> bool {
+    let depth = outbound_depth(tx);
+    if high_water != Some(depth) {
+        return false;
+    }
+    warn!(
+        "{} {} packets queued to send, of {}, is the peer reading?",
+        LogContext::conn(conn_id),
+        depth,
+        tx.max_capacity()
+    );
+    true
+}
+
+/// Unregisters a connection from the shared maps when dropped
+///
+/// Held by the connection's reader so cleanup happens on every exit path,
+/// including panics. Unregistering from [SharedWorkers] and requeueing
+/// assigned jobs is done by [GearmanService]'s own drop.
+pub struct ConnectionGuard {
+    conn_id: usize,
+    senders_by_conn_id: SendersByConnId,
+    workers_by_conn_id: WorkersByConnId,
+}
+
+impl ConnectionGuard {
+    pub fn new(
+        conn_id: usize,
+        senders_by_conn_id: SendersByConnId,
+        workers_by_conn_id: WorkersByConnId,
+    ) -> ConnectionGuard {
+        ConnectionGuard {
+            conn_id: conn_id,
+            senders_by_conn_id: senders_by_conn_id,
+            workers_by_conn_id: workers_by_conn_id,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.workers_by_conn_id.lock().unwrap().remove(&self.conn_id);
+        self.senders_by_conn_id.lock().unwrap().remove(&self.conn_id);
+        debug!("{} Unregistered", LogContext::conn(self.conn_id));
+    }
+}
+
 pub struct GearmanService {
     pub conn_id: usize,
     pub queues: SharedJobStorage,
     pub workers: SharedWorkers,
     pub worker: Arc<Mutex<Worker>>,
     pub job_count: Arc<AtomicUsize>,
+    /// Makes handles for new jobs, counting with `job_count` unless replaced
+    pub handles: Arc<dyn HandleGenerator>,
+    /// Whether the admin `shutdown` command is honoured, loopback peers only by default
+    pub allow_shutdown: bool,
+    /// Limits how fast this connection may submit jobs, unlimited if `None`
+    pub submit_limit: Option<TokenBucket>,
+    /// Most jobs the admin `showjobs` and `showunique` commands list
+    pub max_admin_rows: usize,
+    /// Packets taking longer than this to process are logged, not timed if `None`
+    pub slow_packet_threshold: Option<Duration>,
+    /// Refuse submissions for functions no connected worker can do
+    pub reject_unknown_functions: bool,
+    /// Warn when this many packets are queued for a connection, see [check_outbound_depth]
+    pub outbound_high_water: Option<usize>,
+    /// Checks the `auth=<token>` option, every connection is trusted if `None`
+    pub authenticator: Option<Arc<dyn Authenticator>>,
+    /// Set once the `auth=<token>` option was accepted
+    authenticated: bool,
+    /// Set by the `depends_on=<unique>` option, used up by the next submission
+    ///
+    /// Only a submission that creates a job applies it. One that is
+    /// refused, or coalesced into an existing job, clears it unused.
+    pending_dependency: Option<Bytes>,
+    /// Set by the `ttl=<seconds>` option, used up by the next submission like `pending_dependency`
+    pending_ttl: Option<Duration>,
     senders_by_conn_id: SendersByConnId,
     workers_by_conn_id: WorkersByConnId,
     job_waiters: JobWaiters,
@@ -45,9 +155,19 @@ pub struct GearmanService {
 
 impl Drop for GearmanService {
     fn drop(&mut self) {
-        trace!("Dropping conn_id = {}", self.conn_id);
+        trace!("{} Dropping", LogContext::conn(self.conn_id));
         self.workers.shutdown(self.conn_id);
-        debug!("Dropped conn_id = {}", self.conn_id);
+        let abandoned = self.worker.lock().unwrap().drain_jobs();
+        for job in abandoned {
+            warn!(
+                "{} Dropped while assigned {:?}",
+                LogContext::handle(self.conn_id, job.handle.as_bytes()),
+                job
+            );
+            self.retry_or_fail(job);
+        }
+        self.cancel_abandoned_waits();
+        debug!("{} Dropped", LogContext::conn(self.conn_id));
     }
 }
 
@@ -56,13 +176,13 @@ impl GearmanService {
     fn response_from_packet(&self, packet: &Packet) -> Result<Packet, io::Error> {
         match packet.ptype {
             ADMIN_VERSION => {
-                let resp_str = b"OK some-rustygear-version\n";
+                let resp_str = format!("OK rustygeard {}\n", env!("CARGO_PKG_VERSION"));
                 let mut resp_body = BytesMut::with_capacity(resp_str.len());
-                resp_body.put(&resp_str[..]);
+                resp_body.put(resp_str.as_bytes());
                 Ok(Packet {
                     magic: PacketMagic::TEXT,
                     ptype: packet.ptype,
-                    psize: resp_str.len() as u32,
+                    psize: psize(resp_str.len()),
                     data: resp_body.freeze(),
                 })
             }
@@ -73,6 +193,66 @@ impl GearmanService {
             ADMIN_WORKERS => Ok(admin::admin_command_workers(
                 self.workers_by_conn_id.clone())
             ),
+            ADMIN_SHUTDOWN | ADMIN_SHUTDOWN_GRACEFUL => {
+                if !self.allow_shutdown {
+                    warn!("{} Refusing remote shutdown", LogContext::conn(self.conn_id));
+                    return Ok(Packet::new_text_res(Bytes::from_static(
+                        b"ERR shutdown_not_permitted shutdown+is+only+allowed+from+localhost\n",
+                    )));
+                }
+                // The ptype is kept so the connection writer can stop the
+                // server once OK has actually been flushed.
+                let resp_str = b"OK\n";
+                Ok(Packet {
+                    magic: PacketMagic::TEXT,
+                    ptype: packet.ptype,
+                    psize: psize(resp_str.len()),
+                    data: Bytes::from_static(resp_str),
+                })
+            }
+            // Liveness only, so this must not take any locks
+            ADMIN_PING => Ok(Packet::new_text_res(Bytes::from_static(b"PONG\n"))),
+            ADMIN_DRAIN => {
+                let draining = &packet.data[..] != b"off";
+                info!("{} Draining set to {}", LogContext::conn(self.conn_id), draining);
+                self.queues.set_draining(draining);
+                // Repeating the command reports how many jobs are left
+                let resp_str = format!("OK {}\n", self.queues.queued_count());
+                Ok(Packet::new_text_res(Bytes::from(resp_str)))
+            }
+            ADMIN_CANCEL_JOB => Ok(self.admin_cancel_job(&JobHandle::from(packet.data.clone()))),
+            ADMIN_DROP_FUNCTION => Ok(self.admin_drop_function(&packet.data)),
+            ADMIN_FUNCTION_STATS => Ok(admin::admin_command_function_stats(self.queues.clone())),
+            ADMIN_SHOW_JOBS => Ok(admin::admin_command_show_jobs(
+                self.queues.clone(),
+                &packet.data,
+                self.max_admin_rows,
+            )),
+            ADMIN_KILL_IDLE => match str::from_utf8(&packet.data).ok().and_then(|s| s.parse().ok()) {
+                Some(secs) => Ok(admin::admin_command_kill_idle(
+                    self.workers_by_conn_id.clone(),
+                    Duration::from_secs(secs),
+                )),
+                None => Ok(Packet::new_text_res(Bytes::from_static(
+                    b"ERR invalid_seconds killidle+takes+a+whole+number+of+seconds\n",
+                ))),
+            },
+            ADMIN_SHOW_UNIQUE => Ok(admin::admin_command_show_unique(
+                self.queues.clone(),
+                self.max_admin_rows,
+            )),
+            ADMIN_GETPID => {
+                let resp_str = format!("OK {}\n", process::id());
+                Ok(Packet::new_text_res(Bytes::from(resp_str)))
+            }
+            // Lets servers behind a proxy be told apart, "-" if the handles don't name one
+            ADMIN_ID => {
+                let resp_str = format!("OK {}\n", self.handles.server_id().unwrap_or("-"));
+                Ok(Packet::new_text_res(Bytes::from(resp_str)))
+            }
+            ADMIN_UNKNOWN => Ok(Packet::new_text_res(Bytes::from_static(
+                b"ERR unknown_command Unknown+server+command\n",
+            ))),
             _ => panic!(
                 "response_from_packet called with invalid ptype: {}",
                 packet.ptype
@@ -80,23 +260,261 @@ impl GearmanService {
         }
     }
 
+    /// Removes a job whether queued or running and fails it for its waiters
+    ///
+    /// A worker still running the job is not told, whatever it sends back is discarded.
+    fn admin_cancel_job(&self, handle: &JobHandle) -> Packet {
+        let job = match self.queues.remove_by_handle(handle) {
+            None => {
+                return Packet::new_text_res(Bytes::from_static(
+                    b"ERR not_found Job+not+found\n",
+                ))
+            }
+            Some(job) => job,
+        };
+        let dependents = self.queues.fail_dependents(&job.unique);
+        info!("{} Cancelled by admin", LogContext::handle(self.conn_id, handle.as_bytes()));
+        // So a worker going away later doesn't requeue it
+        for worker in self.workers_by_conn_id.lock().unwrap().values() {
+            let mut worker = worker.lock().unwrap();
+            if worker.get_assigned_job(handle).is_some() {
+                worker.unassign_job(handle);
+            }
+        }
+        self.send_work_fail(handle);
+        for job in dependents {
+            self.send_work_fail(&job.handle);
+        }
+        Packet::new_text_res(Bytes::from_static(b"OK\n"))
+    }
+
+    /// Purges `fname`'s queue, failing the dropped jobs and anything waiting on them
+    fn admin_drop_function(&self, fname: &Bytes) -> Packet {
+        let dropped = self.queues.shard(fname).drop_function(fname);
+        let dependents: Vec<Arc<Job>> = dropped
+            .iter()
+            .flat_map(|job| self.queues.fail_dependents(&job.unique))
+            .collect();
+        info!(
+            "{} Dropped {} queued jobs for {:?}",
+            LogContext::conn(self.conn_id),
+            dropped.len(),
+            String::from_utf8_lossy(fname)
+        );
+        for job in dropped.iter().chain(dependents.iter()) {
+            self.send_work_fail(&job.handle);
+        }
+        Packet::new_text_res(Bytes::from(format!("OK {}\n", dropped.len())))
+    }
+
     fn send_to_conn_id(&self, conn_id: usize, packet: Packet) {
         let senders_by_conn_id = self.senders_by_conn_id.lock().unwrap();
         match senders_by_conn_id.get(&conn_id) {
-            None => {
-                panic!("No connection found for conn_id = {}", &conn_id); // XXX You can do better
-            }
+            // A waiter that disconnected has nothing left to tell
+            None => debug!("{} Gone, not sending {:?}", LogContext::conn(conn_id), packet),
             Some(tx) => {
-                let mut tx = tx.clone();
+                let tx = tx.clone();
+                let high_water = self.outbound_high_water;
                 runtime::Handle::current().spawn(async move {
                     if let Err(e) = tx.send(packet).await {
                         error!("Send Error! {:?}", e);
+                    } else {
+                        check_outbound_depth(conn_id, &tx, high_water);
                     }
                 });
             }
         }
     }
 
+    /// Snapshots the state of connection `conn_id`, `None` if it isn't connected
+    pub fn connection_info(&self, conn_id: usize) -> Option<ConnectionInfo> {
+        let mut info = self.with_conn(conn_id, |conn| conn.info(conn_id))?;
+        if let Some(tx) = self.senders_by_conn_id.lock().unwrap().get(&conn_id) {
+            info.outbound_depth = outbound_depth(tx);
+        }
+        Some(info)
+    }
+
+    /// Runs `f` on the state of connection `conn_id`, unless it has gone away
+    ///
+    /// Must not be called while holding this connection's own worker lock.
+    fn with_conn<T>(&self, conn_id: usize, f: impl FnOnce(&mut Worker) -> T) -> Option<T> {
+        let conn = self.workers_by_conn_id.lock().unwrap().get(&conn_id).cloned()?;
+        let mut conn = conn.lock().unwrap();
+        Some(f(&mut conn))
+    }
+
+    /// Sends NOOP to sleeping workers that can do `fname`
+    fn wake_workers(&self, fname: &Bytes) {
+        for wake in self.workers.clone().queue_wake(fname) {
+            let senders_by_conn_id = self.senders_by_conn_id.lock().unwrap();
+            match senders_by_conn_id.get(&wake) {
+                None => {
+                    debug!("No connection found to wake up for conn_id = {}", wake);
+                }
+                Some(tx) => {
+                    let tx = tx.clone();
+                    let high_water = self.outbound_high_water;
+                    runtime::Handle::current().spawn(async move {
+                        if let Err(_) = tx.send(new_noop()).await {
+                            error!("worker receiver dropped");
+                        } else {
+                            check_outbound_depth(wake, &tx, high_water);
+                        };
+                    });
+                }
+            }
+        }
+    }
+
+    /// Removes foreground jobs that nobody but this connection was waiting on
+    ///
+    /// Queued jobs are dropped before a worker sees them. Running jobs are
+    /// forgotten, so whatever the worker sends back is discarded.
+    fn cancel_abandoned_waits(&self) {
+        let mut abandoned = Vec::new();
+        {
+            let conn_id = self.conn_id;
+            let mut job_waiters = self.job_waiters.lock().unwrap();
+            job_waiters.retain(|handle, waiters| {
+                let was_waiting = waiters.contains(&conn_id);
+                waiters.retain(|waiter| *waiter != conn_id);
+                if was_waiting && waiters.is_empty() {
+                    abandoned.push(handle.clone());
+                    return false;
+                }
+                true
+            });
+        }
+        if abandoned.is_empty() {
+            return;
+        }
+        let running: Vec<JobHandle> = {
+            let workers_by_conn_id = self.workers_by_conn_id.lock().unwrap();
+            abandoned
+                .iter()
+                .filter(|handle| {
+                    workers_by_conn_id
+                        .values()
+                        .any(|worker| worker.lock().unwrap().get_assigned_job(handle).is_some())
+                })
+                .cloned()
+                .collect()
+        };
+        let mut dependents = Vec::new();
+        for handle in abandoned {
+            if let Some(job) = self.queues.remove_by_handle(&handle) {
+                if running.contains(&handle) {
+                    info!(
+                        "{} Abandoned while running, its result will be discarded",
+                        LogContext::handle(self.conn_id, handle.as_bytes())
+                    );
+                } else {
+                    info!("{} Cancelling abandoned job", LogContext::handle(self.conn_id, handle.as_bytes()));
+                }
+                dependents.extend(self.queues.fail_dependents(&job.unique));
+            }
+        }
+        for job in dependents {
+            self.send_work_fail(&job.handle);
+        }
+    }
+
+    /// Sends WORK_FAIL to everyone waiting on `handle`
+    fn send_work_fail(&self, handle: &JobHandle) {
+        info!("{} Job failed", LogContext::handle(self.conn_id, handle.as_bytes()));
+        let waiters = self.job_waiters.lock().unwrap().remove(handle);
+        for conn_id in waiters.unwrap_or_default() {
+            self.with_conn(conn_id, |waiter| waiter.take_aggregated(handle));
+            self.send_to_conn_id(conn_id, new_res(WORK_FAIL, handle.to_bytes()));
+        }
+    }
+
+    /// Drops `job` once `ttl` has passed if no worker took it, sending WORK_FAIL to its waiters
+    fn expire_after(&self, ttl: Duration, job: &Arc<Job>) {
+        let conn_id = self.conn_id;
+        let job = Arc::downgrade(job);
+        let queues = self.queues.clone();
+        let job_waiters = self.job_waiters.clone();
+        let senders_by_conn_id = self.senders_by_conn_id.clone();
+        let workers_by_conn_id = self.workers_by_conn_id.clone();
+        runtime::Handle::current().spawn(async move {
+            sleep(ttl).await;
+            let job = match job.upgrade() {
+                Some(job) => job,
+                None => return,
+            };
+            if !queues.shard(&job.fname).expire_job(&job) {
+                return;
+            }
+            let mut expired = queues.fail_dependents(&job.unique);
+            expired.insert(0, job.clone());
+            info!(
+                "{} Expired after {:?} in the queue",
+                LogContext::handle(conn_id, job.handle.as_bytes()),
+                ttl
+            );
+            for job in expired {
+                let waiters = job_waiters.lock().unwrap().remove(&job.handle);
+                for waiter in waiters.unwrap_or_default() {
+                    let conn = workers_by_conn_id.lock().unwrap().get(&waiter).cloned();
+                    if let Some(conn) = conn {
+                        conn.lock().unwrap().take_aggregated(&job.handle);
+                    }
+                    let tx = senders_by_conn_id.lock().unwrap().get(&waiter).cloned();
+                    if let Some(tx) = tx {
+                        if let Err(e) = tx.send(new_res(WORK_FAIL, job.handle.to_bytes())).await {
+                            error!("Send Error! {:?}", e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Requeues a failed job if it has retries left, otherwise reports WORK_FAIL to its waiters
+    fn retry_or_fail(&self, job: Arc<Job>) {
+        let attempts = job.attempts.fetch_add(1, Ordering::Relaxed) + 1;
+        let retry = {
+            let mut queues = self.queues.shard(&job.fname);
+            // Nobody is waiting for a job that was cancelled while it ran
+            if !queues.is_stored(&job) {
+                info!(
+                    "{} Not retrying cancelled job",
+                    LogContext::handle(self.conn_id, job.handle.as_bytes())
+                );
+                return;
+            }
+            let max_retries = queues.max_retries(&job.fname);
+            if attempts <= max_retries {
+                info!(
+                    "{} Retrying ({} of {} retries)",
+                    LogContext::handle(self.conn_id, job.handle.as_bytes()),
+                    attempts,
+                    max_retries
+                );
+                // It already waited its turn once, so put it at the front
+                queues.requeue_job(&job, PRIORITY_HIGH);
+                true
+            } else {
+                queues.remove_job(&job.unique);
+                queues.record_failure(&job.fname);
+                queues.replicate(ReplicationEvent::Failed(job.clone()));
+                false
+            }
+        };
+        if retry {
+            self.wake_workers(&job.fname);
+            return;
+        }
+        self.send_work_fail(&job.handle);
+        // Jobs that depended on this one can never run now
+        let dependents = self.queues.fail_dependents(&job.unique);
+        for dependent in dependents {
+            self.send_work_fail(&dependent.handle);
+        }
+    }
+
     pub fn new(
         conn_id: usize,
         queues: SharedJobStorage,
@@ -112,7 +530,18 @@ impl GearmanService {
             queues: queues,
             worker: Arc::new(Mutex::new(Worker::new(peer_addr, Bytes::from("-")))),
             workers: workers,
+            handles: Arc::new(CounterHandles::with_counter(job_count.clone())),
             job_count: job_count,
+            allow_shutdown: peer_addr.ip().is_loopback(),
+            submit_limit: None,
+            max_admin_rows: 1000,
+            slow_packet_threshold: None,
+            reject_unknown_functions: false,
+            outbound_high_water: None,
+            authenticator: None,
+            authenticated: false,
+            pending_dependency: None,
+            pending_ttl: None,
             senders_by_conn_id: senders_by_conn_id,
             workers_by_conn_id: workers_by_conn_id,
             job_waiters: job_waiters,
@@ -123,40 +552,55 @@ impl GearmanService {
         let worker = self.worker.clone();
         let workers = self.workers.clone();
         let conn_id = self.conn_id;
-        debug!("CAN_DO fname = {:?}", packet.data);
+        debug!("{} CAN_DO fname = {:?}", LogContext::conn(conn_id), packet.data);
         let mut worker = worker.lock().unwrap();
         worker.can_do(packet.data.clone());
         workers.clone().wakeup(&mut worker, conn_id);
         Ok(no_response())
     }
 
+    /// Stops assigning and waking for the function, jobs of it already assigned still run to completion
     fn handle_cant_do(&self, packet: &Packet) -> Result<Packet, io::Error> {
         let worker = self.worker.clone();
-        debug!("CANT_DO fname = {:?}", packet.data);
+        debug!("{} CANT_DO fname = {:?}", LogContext::conn(self.conn_id), packet.data);
         let mut worker = worker.lock().unwrap();
         worker.cant_do(&packet.data);
+        self.workers.clone().forget(&packet.data, self.conn_id);
         Ok(no_response())
     }
 
+    /// Answers with JOB_ASSIGN_ALL, whose fields are handle, function,
+    /// unique, reducer and then the data, or NO_JOB
+    ///
+    /// The job is assigned to this worker by `get_job`, which is how a later
+    /// WORK_COMPLETE from this connection is matched to it.
     fn handle_grab_job_all(&self) -> Result<Packet, io::Error> {
         let mut queues = self.queues.clone();
         let worker = self.worker.clone();
         let mut worker = worker.lock().unwrap();
         let ref mut worker = worker;
+        self.stop_sleeping(worker);
         match queues.get_job(worker) {
             Some(ref j) => {
+                self.workers.clone().assigned(self.conn_id);
+                let reducer = j.reducer.clone().unwrap_or_default();
+                let job_data = assigned_data(worker, j);
                 let mut data = BytesMut::with_capacity(
-                    4 + j.handle.len() + j.fname.len() + j.unique.len() + j.data.len(),
+                    4 + j.handle.as_bytes().len()
+                        + j.fname.len()
+                        + j.unique.len()
+                        + reducer.len()
+                        + job_data.len(),
                 );
-                data.extend(&j.handle);
+                data.extend(j.handle.as_bytes());
                 data.put_u8(b'\0');
                 data.extend(&j.fname);
                 data.put_u8(b'\0');
                 data.extend(&j.unique);
                 data.put_u8(b'\0');
-                // reducer not implemented
+                data.extend(&reducer);
                 data.put_u8(b'\0');
-                data.extend(&j.data);
+                data.extend(&job_data);
                 return Ok(new_res(JOB_ASSIGN_ALL, data.freeze()));
             }
             None => {}
@@ -169,18 +613,21 @@ impl GearmanService {
         let worker = self.worker.clone();
         let mut worker = worker.lock().unwrap();
         let ref mut worker = worker;
+        self.stop_sleeping(worker);
         match queues.get_job(worker) {
             Some(ref j) => {
+                self.workers.clone().assigned(self.conn_id);
+                let job_data = assigned_data(worker, j);
                 let mut data = BytesMut::with_capacity(
-                    3 + j.handle.len() + j.fname.len() + j.unique.len() + j.data.len(),
+                    3 + j.handle.as_bytes().len() + j.fname.len() + j.unique.len() + job_data.len(),
                 );
-                data.extend(&j.handle);
+                data.extend(j.handle.as_bytes());
                 data.put_u8(b'\0');
                 data.extend(&j.fname);
                 data.put_u8(b'\0');
                 data.extend(&j.unique);
                 data.put_u8(b'\0');
-                data.extend(&j.data);
+                data.extend(&job_data);
                 Ok(new_res(JOB_ASSIGN_UNIQ, data.freeze()))
             }
             None => Ok(new_res(NO_JOB, Bytes::new())),
@@ -192,15 +639,18 @@ impl GearmanService {
         let worker = self.worker.clone();
         let mut worker = worker.lock().unwrap();
         let ref mut worker = worker;
+        self.stop_sleeping(worker);
         match queues.get_job(worker) {
             Some(ref j) => {
+                self.workers.clone().assigned(self.conn_id);
+                let job_data = assigned_data(worker, j);
                 let mut data =
-                    BytesMut::with_capacity(2 + j.handle.len() + j.fname.len() + j.data.len());
-                data.extend(&j.handle);
+                    BytesMut::with_capacity(2 + j.handle.as_bytes().len() + j.fname.len() + job_data.len());
+                data.extend(j.handle.as_bytes());
                 data.put_u8(b'\0');
                 data.extend(&j.fname);
                 data.put_u8(b'\0');
-                data.extend(&j.data);
+                data.extend(&job_data);
                 return Ok(new_res(JOB_ASSIGN, data.freeze()));
             }
             None => {}
@@ -208,77 +658,143 @@ impl GearmanService {
         Ok(new_res(NO_JOB, Bytes::new()))
     }
 
+    /// Takes a worker that sent GRAB_JOB out of the sleepers, so it isn't sent NOOP while it works
+    fn stop_sleeping(&self, worker: &mut Worker) {
+        if worker.sleeping {
+            worker.sleeping = false;
+            self.workers.clone().wakeup(worker, self.conn_id);
+        }
+    }
+
+    /// Puts the worker to sleep until a job it can do is queued
+    ///
+    /// A worker that got NO_JOB after losing a race for a job may only get
+    /// here after the next job was queued, when it wasn't yet a sleeper to
+    /// wake. It is answered with NOOP straight away if that happened.
     fn handle_pre_sleep(&self) -> Result<Packet, io::Error> {
         let worker = self.worker.clone();
         let ref mut w = worker.lock().unwrap();
+        w.sleeping = true;
         self.workers.clone().sleep(w, self.conn_id);
+        if self.queues.has_job_for(w) {
+            debug!("{} Jobs queued while going to sleep", LogContext::conn(self.conn_id));
+            self.workers.clone().wakeup(w, self.conn_id);
+            return Ok(new_noop());
+        }
         Ok(no_response())
     }
 
+    /// Rate limits a submission, then queues it with the priority and waiting its type implies
+    fn handle_submit(&mut self, packet: Packet) -> Result<Packet, io::Error> {
+        if !self.take_submit_token() {
+            warn!("{} Submission rate limited", LogContext::conn(self.conn_id));
+            return Ok(new_error("rate_limited", "Too many job submissions"));
+        }
+        let (priority, wait) = match packet.ptype {
+            SUBMIT_JOB | SUBMIT_REDUCE_JOB => (PRIORITY_NORMAL, true),
+            SUBMIT_JOB_HIGH => (PRIORITY_HIGH, true),
+            SUBMIT_JOB_LOW => (PRIORITY_LOW, true),
+            SUBMIT_JOB_BG | SUBMIT_REDUCE_JOB_BACKGROUND => (PRIORITY_NORMAL, false),
+            SUBMIT_JOB_HIGH_BG => (PRIORITY_HIGH, false),
+            SUBMIT_JOB_LOW_BG => (PRIORITY_LOW, false),
+            _ => unreachable!("handle_submit called with {:?}", packet),
+        };
+        self.handle_submit_job(priority, wait, packet)
+    }
+
+    /// Parses function, unique and any type specific fields, then queues the job
     fn handle_submit_job(
-        &self,
+        &mut self,
         priority: JobQueuePriority,
         wait: bool,
         packet: Packet,
     ) -> Result<Packet, io::Error> {
+        // Taken first so no exit below leaves them for a later submission
+        let dependency = self.pending_dependency.take();
+        let ttl = self.pending_ttl.take();
+        if self.queues.draining() {
+            return Ok(new_error("draining", "Server is not accepting new jobs"));
+        }
+        if self.reject_unknown_functions {
+            let fname = next_field(&mut packet.data.clone());
+            if self.workers.clone().count_workers(&fname) == (0, 0) {
+                info!(
+                    "{} Rejecting job for {:?}, no workers",
+                    LogContext::conn(self.conn_id),
+                    String::from_utf8_lossy(&fname)
+                );
+                return Ok(new_error("no_workers", "No worker can do this function"));
+            }
+        }
         let mut queues = self.queues.clone();
         let conn_id = match wait {
             true => Some(self.conn_id),
             false => None,
         };
-        let mut workers = self.workers.clone();
-        let job_count = self.job_count.clone();
         //let remote = self.remote.clone();
-        let senders_by_conn_id = self.senders_by_conn_id.clone();
         let mut fields = packet.data.clone();
         trace!("fields = {:?}", fields);
         let fname = next_field(&mut fields);
         let unique = next_field(&mut fields);
-        trace!("  --> fname = {:?} unique = {:?}", fname, unique);
-        let mut add = false;
+        let reducer = match packet.ptype {
+            SUBMIT_REDUCE_JOB | SUBMIT_REDUCE_JOB_BACKGROUND => Some(next_field(&mut fields)),
+            _ => None,
+        };
+        let (metadata, fields) = match self.worker.lock().unwrap().metadata {
+            true => decode_metadata(fields),
+            false => (Vec::new(), fields),
+        };
+        trace!("  --> fname = {:?} unique = {:?} reducer = {:?}", fname, unique, reducer);
         let handle = match queues.coalesce_unique(&unique, conn_id) {
-            Some(handle) => handle,
+            Some(handle) => {
+                if dependency.is_some() || ttl.is_some() {
+                    info!(
+                        "{} Coalesced, ignoring depends_on/ttl options for the existing job",
+                        LogContext::handle(self.conn_id, handle.as_bytes())
+                    );
+                }
+                handle
+            }
             None => {
-                {
-                    for wake in workers.queue_wake(&fname) {
-                        let senders_by_conn_id = senders_by_conn_id.lock().unwrap();
-                        match senders_by_conn_id.get(&wake) {
-                            None => {
-                                debug!("No connection found to wake up for conn_id = {}", wake);
-                            }
-                            Some(tx) => {
-                                let mut tx = tx.clone();
-                                runtime::Handle::current().spawn(async move {
-                                    if let Err(_) = tx.send(new_noop()).await {
-                                        error!("worker receiver dropped");
-                                    };
-                                });
-                            }
-                        }
+                let handle = self.handles.next_handle();
+                debug!("new handle = {}", handle);
+                let mut job = Job::new(fname.clone(), unique, fields, handle.clone());
+                job.reducer = reducer;
+                job.metadata = metadata;
+                job.expires_at = ttl.map(|ttl| job.created_at + ttl);
+                let job = Arc::new(job);
+                // Only acknowledge jobs that were actually enqueued
+                let added = match dependency {
+                    None => queues.add_job(job.clone(), priority, conn_id),
+                    Some(dependency) => {
+                        queues.add_dependent_job(job.clone(), priority, conn_id, dependency)
                     }
+                };
+                if let Err(e) = added {
+                    warn!(
+                        "{} Rejected job {:?}: {}",
+                        LogContext::handle(self.conn_id, handle.as_bytes()),
+                        job,
+                        e
+                    );
+                    return Ok(new_error(e.code(), &e.to_string()));
+                }
+                info!("{} Created job {:?}", LogContext::handle(self.conn_id, handle.as_bytes()), job);
+                if let Some(ttl) = ttl {
+                    self.expire_after(ttl, &job);
                 }
-                // H:091234567890
-                let mut handle = BytesMut::with_capacity(12);
-                let job_num = job_count.fetch_add(1, Ordering::Relaxed);
-                debug!("job_num = {}", job_num);
-                handle.extend(format!("H:{:010}", job_num).as_bytes());
-                add = true;
-                handle.freeze()
+                self.wake_workers(&fname);
+                trace!(
+                    "job weak = {} strong = {}",
+                    Arc::weak_count(&job),
+                    Arc::strong_count(&job)
+                );
+                handle
             }
         };
-        if add {
-            let job = Arc::new(Job::new(fname, unique, fields, handle.clone()));
-            info!("Created job {:?}", job);
-            queues.add_job(job.clone(), priority, conn_id);
-            trace!(
-                "job weak = {} strong = {}",
-                Arc::weak_count(&job),
-                Arc::strong_count(&job)
-            );
-        }
         // If we don't store any senders, the sender will be dropped and the rx
         // stream should end thus releasing the waiter immediately.
-        let psize = handle.len() as u32;
+        let handle_psize = psize(handle.as_bytes().len());
         // Fetch our sender
         let mut job_waiters = self.job_waiters.lock().unwrap();
         let waiters = job_waiters.entry(handle.clone()).or_insert(Vec::new());
@@ -288,51 +804,292 @@ impl GearmanService {
         Ok(Packet {
             magic: PacketMagic::RES,
             ptype: JOB_CREATED,
-            psize: psize,
-            data: handle,
+            psize: handle_psize,
+            data: handle.to_bytes(),
         })
     }
 
     fn handle_work_complete(&self, packet: &Packet) -> Result<Packet, io::Error> {
         // Search for handle
         let mut fields = packet.data.clone();
-        let handle = next_field(&mut fields);
+        let handle = JobHandle::from(next_field(&mut fields));
         let worker = self.worker.clone();
         let queues = self.queues.clone();
-        info!("Job is complete {:?}", handle);
+        info!("{} Job is complete", LogContext::handle(self.conn_id, handle.as_bytes()));
         let mut worker = worker.lock().unwrap();
-        match worker.get_assigned_job(&handle) {
-            Some(ref mut j) => {
-                let mut queues = queues.lock().unwrap();
-                queues.remove_job(&j.unique);
+        match worker.get_assigned_job(&handle).cloned() {
+            Some(j) => {
+                let stored = {
+                    let mut storage = queues.shard(&j.fname);
+                    // A job cancelled while it ran may have been replaced under its unique
+                    let stored = storage.is_stored(&j);
+                    if stored {
+                        storage.remove_job(&j.unique);
+                        storage.replicate(ReplicationEvent::Completed(j.clone()));
+                        if let Some(runtime) = worker.assigned_for(&handle) {
+                            storage.record_complete(&j.fname, runtime);
+                        }
+                    }
+                    stored
+                };
+                if !stored {
+                    info!(
+                        "{} Ignoring completion of cancelled job",
+                        LogContext::handle(self.conn_id, handle.as_bytes())
+                    );
+                    worker.unassign_job(&handle);
+                    return Ok(no_response());
+                }
+                let released = match j.reducer {
+                    // A map job isn't done until its reducer is
+                    Some(_) => Vec::new(),
+                    None => queues.release_dependents(&j.unique),
+                };
+                if let Some(ref reducer) = j.reducer {
+                    worker.unassign_job(&handle);
+                    self.queue_reduce(&j, reducer, fields);
+                    return Ok(no_response());
+                }
+                for fname in released {
+                    self.wake_workers(&fname);
+                }
             }
             None => {
-                error!("WORK_COMPLETE received but no active jobs");
+                error!(
+                    "{} WORK_COMPLETE received but no active jobs",
+                    LogContext::handle(self.conn_id, handle.as_bytes())
+                );
             }
         }
         worker.unassign_job(&handle);
-        let mut job_waiters = self.job_waiters.lock().unwrap();
+        drop(worker);
         // If there are waiters, send the packet to them
-        if let Some(waiters) = job_waiters.remove(&handle) {
-            for conn_id in waiters.iter() {
-                self.send_to_conn_id(*conn_id, packet.clone());
+        let waiters = self.job_waiters.lock().unwrap().remove(&handle);
+        for conn_id in waiters.unwrap_or_default() {
+            match self.with_conn(conn_id, |waiter| waiter.take_aggregated(&handle)).flatten() {
+                Some(mut data) => {
+                    data.extend_from_slice(&fields);
+                    let complete = Packet::from_fields(WORK_COMPLETE, &[handle.as_bytes(), &data]);
+                    self.send_to_conn_id(conn_id, complete);
+                }
+                None => self.send_to_conn_id(conn_id, packet.clone()),
             }
         }
         Ok(no_response())
     }
 
+    fn handle_work_fail(&self, packet: &Packet) -> Result<Packet, io::Error> {
+        let mut fields = packet.data.clone();
+        let handle = JobHandle::from(next_field(&mut fields));
+        let job = {
+            let mut worker = self.worker.lock().unwrap();
+            let job = worker.get_assigned_job(&handle).cloned();
+            worker.unassign_job(&handle);
+            job
+        };
+        match job {
+            Some(job) => self.retry_or_fail(job),
+            None => error!(
+                "{} WORK_FAIL received but no active jobs",
+                LogContext::handle(self.conn_id, handle.as_bytes())
+            ),
+        }
+        Ok(no_response())
+    }
+
+    /// Ends the job without retrying it, the worker already decided it can't be done
+    ///
+    /// Waiters that set the `exceptions` option get the WORK_EXCEPTION, the rest WORK_FAIL.
+    fn handle_work_exception(&self, packet: &Packet) -> Result<Packet, io::Error> {
+        let mut fields = packet.data.clone();
+        let handle = JobHandle::from(next_field(&mut fields));
+        let job = {
+            let mut worker = self.worker.lock().unwrap();
+            let job = worker.get_assigned_job(&handle).cloned();
+            worker.unassign_job(&handle);
+            job
+        };
+        let job = match job {
+            Some(job) => job,
+            None => {
+                error!(
+                    "{} WORK_EXCEPTION received but no active jobs",
+                    LogContext::handle(self.conn_id, handle.as_bytes())
+                );
+                return Ok(no_response());
+            }
+        };
+        info!("{} Job raised an exception", LogContext::handle(self.conn_id, handle.as_bytes()));
+        {
+            let mut storage = self.queues.shard(&job.fname);
+            // A job cancelled while it ran may have been replaced under its unique
+            if !storage.is_stored(&job) {
+                info!(
+                    "{} Ignoring exception from cancelled job",
+                    LogContext::handle(self.conn_id, handle.as_bytes())
+                );
+                return Ok(no_response());
+            }
+            storage.remove_job(&job.unique);
+            storage.record_failure(&job.fname);
+            storage.replicate(ReplicationEvent::Failed(job.clone()));
+        }
+        let dependents = self.queues.fail_dependents(&job.unique);
+        let waiters = self.job_waiters.lock().unwrap().remove(&handle);
+        for conn_id in waiters.unwrap_or_default() {
+            let exceptions = self
+                .with_conn(conn_id, |waiter| {
+                    waiter.take_aggregated(&handle);
+                    waiter.exceptions
+                })
+                .unwrap_or(false);
+            if exceptions {
+                self.send_to_conn_id(conn_id, packet.clone());
+            } else {
+                self.send_to_conn_id(conn_id, new_res(WORK_FAIL, handle.to_bytes()));
+            }
+        }
+        for dependent in dependents {
+            self.send_work_fail(&dependent.handle);
+        }
+        Ok(no_response())
+    }
+
+    fn take_submit_token(&mut self) -> bool {
+        match self.submit_limit {
+            None => true,
+            Some(ref mut bucket) => bucket.take(),
+        }
+    }
+
+    /// Queues the reducer for a finished map job under the same handle
+    ///
+    /// The reducer's data is every partial result from the map job, in
+    /// order, separated by NUL bytes.
+    fn queue_reduce(&self, job: &Arc<Job>, reducer: &Bytes, last: Bytes) {
+        let mut partials = job.partials.lock().unwrap();
+        if !last.is_empty() {
+            partials.push(last);
+        }
+        let mut data = BytesMut::new();
+        for (i, partial) in partials.drain(..).enumerate() {
+            if i > 0 {
+                data.put_u8(b'\0');
+            }
+            data.extend(partial);
+        }
+        let reduce_job = Arc::new(Job::new(
+            reducer.clone(),
+            job.unique.clone(),
+            data.freeze(),
+            job.handle.clone(),
+        ));
+        if let Err(e) = self.queues.clone().add_job(reduce_job, PRIORITY_NORMAL, None) {
+            warn!("{} Reduce rejected: {}", LogContext::handle(self.conn_id, job.handle.as_bytes()), e);
+            if let Some(waiters) = self.job_waiters.lock().unwrap().remove(&job.handle) {
+                for conn_id in waiters.iter() {
+                    self.send_to_conn_id(*conn_id, new_res(WORK_FAIL, job.handle.to_bytes()));
+                }
+            }
+            return;
+        }
+        info!("{} Queued reducer {:?}", LogContext::handle(self.conn_id, job.handle.as_bytes()), reducer);
+        self.wake_workers(reducer);
+    }
+
     fn handle_work_update(&self, packet: &Packet) -> Result<Packet, io::Error> {
         let mut fields = packet.data.clone();
-        let handle = next_field(&mut fields);
-        let job_waiters = self.job_waiters.lock().unwrap();
-        if let Some(waiters) = job_waiters.get(&handle) {
-            for conn_id in waiters.iter() {
-                self.send_to_conn_id(*conn_id, packet.clone());
+        let handle = JobHandle::from(next_field(&mut fields));
+        if packet.ptype == WORK_DATA {
+            // Map output is held for the reducer instead of going to the client
+            let worker = self.worker.lock().unwrap();
+            if let Some(job) = worker.get_assigned_job(&handle) {
+                if job.reducer.is_some() {
+                    job.partials.lock().unwrap().push(fields);
+                    return Ok(no_response());
+                }
+            }
+        }
+        let waiters = self.job_waiters.lock().unwrap().get(&handle).cloned();
+        for conn_id in waiters.unwrap_or_default() {
+            if packet.ptype == WORK_DATA {
+                let held = self.with_conn(conn_id, |waiter| {
+                    if waiter.aggregate {
+                        waiter.aggregate_data(&handle, &fields);
+                    }
+                    waiter.aggregate
+                });
+                if held == Some(true) {
+                    continue;
+                }
             }
+            self.send_to_conn_id(conn_id, packet.clone());
         }
         Ok(no_response())
     }
 
+    /// Records a job's progress for GET_STATUS and forwards it to waiters
+    ///
+    /// A numerator past the denominator is clamped to it, so clients never see
+    /// more than 100%. Updates with a denominator of 0, or that aren't numbers,
+    /// are dropped.
+    fn handle_work_status(&self, packet: &Packet) -> Result<Packet, io::Error> {
+        let mut fields = packet.data.clone();
+        let handle = JobHandle::from(next_field(&mut fields));
+        let numerator = next_field(&mut fields);
+        let denominator = next_field(&mut fields);
+        let parse = |field: &Bytes| str::from_utf8(field).ok().and_then(|n| n.parse::<u64>().ok());
+        let (numerator, denominator) = match (parse(&numerator), parse(&denominator)) {
+            (Some(numerator), Some(denominator)) if denominator > 0 => {
+                (numerator.min(denominator), denominator)
+            }
+            _ => {
+                warn!(
+                    "{} Dropping bad WORK_STATUS {:?}/{:?}",
+                    LogContext::handle(self.conn_id, handle.as_bytes()),
+                    numerator,
+                    denominator
+                );
+                return Ok(no_response());
+            }
+        };
+        if let Some(job) = self.worker.lock().unwrap().get_assigned_job(&handle) {
+            *job.status.lock().unwrap() = Some((numerator, denominator));
+        }
+        let mut data = BytesMut::new();
+        data.extend(handle.as_bytes());
+        data.extend(format!("\0{}\0{}", numerator, denominator).into_bytes());
+        let status = Packet {
+            magic: packet.magic,
+            ptype: WORK_STATUS,
+            psize: psize(data.len()),
+            data: data.freeze(),
+        };
+        self.handle_work_update(&status)
+    }
+
+    /// Whether `req` must be refused until the connection authenticates
+    fn needs_auth(&self, req: &Packet) -> bool {
+        if self.authenticator.is_none() || self.authenticated {
+            return false;
+        }
+        !(req.ptype == OPTION_REQ && req.data.starts_with(AUTH))
+    }
+
+    fn auth_required(&self, req: &Packet) -> Packet {
+        info!(
+            "{} Refusing {} before authentication",
+            LogContext::conn(self.conn_id),
+            req.ptype_name()
+        );
+        match req.magic {
+            PacketMagic::TEXT => Packet::new_text_res(Bytes::from_static(
+                b"ERR auth_required Send+the+auth+option+first\n",
+            )),
+            _ => new_error("auth_required", "Send the auth option first"),
+        }
+    }
+
     fn handle_set_client_id(&self, packet: &Packet) -> Result<Packet, io::Error> {
         let d = packet.data.clone();
         let mut worker = self.worker.lock().unwrap();
@@ -340,23 +1097,104 @@ impl GearmanService {
         Ok(no_response())
     }
 
+    /// Acknowledges connection options this server supports
+    ///
+    /// `exceptions` has WORK_EXCEPTION forwarded to this connection instead of WORK_FAIL.
+    ///
+    /// `aggregate` holds back WORK_DATA for jobs this connection waits on and
+    /// sends it all, followed by the result, as one WORK_COMPLETE.
+    ///
+    /// `depends_on=<unique>` holds the next job submitted on this connection
+    /// back until the job with that unique completes.
+    ///
+    /// `auth=<token>` lets the connection in if the configured [Authenticator]
+    /// accepts the token. It is always accepted when there is none.
+    ///
+    /// `ttl=<seconds>` drops the next job submitted on this connection if it
+    /// is still queued that long after, failing it for any waiting clients.
+    ///
+    /// Anything else is refused with an `unknown_option` ERROR. That includes
+    /// `compress=gzip`: data compression is not implemented, since there is no
+    /// deflate implementation among the dependencies, so a peer that asks for
+    /// it is told no and keeps sending raw data sections.
+    fn handle_option_req(&mut self, packet: &Packet) -> Result<Packet, io::Error> {
+        const DEPENDS_ON: &[u8] = b"depends_on=";
+        const TTL: &[u8] = b"ttl=";
+        match &packet.data[..] {
+            option if option.starts_with(AUTH) => {
+                let token = &option[AUTH.len()..];
+                let peer_addr = self.worker.lock().unwrap().peer_addr;
+                let accepted = match self.authenticator {
+                    None => true,
+                    Some(ref authenticator) => authenticator.authenticate(peer_addr, token),
+                };
+                if !accepted {
+                    warn!("{} Authentication failed", LogContext::conn(self.conn_id));
+                    return Ok(new_error("auth_failed", "Authentication failed"));
+                }
+                self.authenticated = true;
+                // Not echoing the token back
+                Ok(new_res(OPTION_RES, Bytes::from_static(b"auth")))
+            }
+            b"exceptions" => {
+                self.worker.lock().unwrap().exceptions = true;
+                Ok(new_res(OPTION_RES, packet.data.clone()))
+            }
+            b"aggregate" => {
+                self.worker.lock().unwrap().aggregate = true;
+                Ok(new_res(OPTION_RES, packet.data.clone()))
+            }
+            b"metadata" => {
+                self.worker.lock().unwrap().metadata = true;
+                Ok(new_res(OPTION_RES, packet.data.clone()))
+            }
+            option if option.starts_with(DEPENDS_ON) => {
+                self.pending_dependency = Some(packet.data.slice(DEPENDS_ON.len()..));
+                Ok(new_res(OPTION_RES, packet.data.clone()))
+            }
+            option if option.starts_with(TTL) => {
+                let secs = str::from_utf8(&option[TTL.len()..])
+                    .ok()
+                    .and_then(|secs| secs.parse().ok());
+                match secs {
+                    Some(secs) => {
+                        self.pending_ttl = Some(Duration::from_secs(secs));
+                        Ok(new_res(OPTION_RES, packet.data.clone()))
+                    }
+                    None => Ok(new_error("bad_ttl", "ttl must be a whole number of seconds")),
+                }
+            }
+            option => {
+                info!(
+                    "{} Refusing option {:?}",
+                    LogContext::conn(self.conn_id),
+                    String::from_utf8_lossy(option)
+                );
+                Ok(new_error("unknown_option", "Server does not support this option"))
+            }
+        }
+    }
+
     fn handle_get_status(&self, packet: &Packet) -> Result<Packet, io::Error> {
         let mut d = packet.data.clone();
-        let handle = next_field(&mut d);
+        let handle = JobHandle::from(next_field(&mut d));
         let (known, _num_waiters) = match self.job_waiters.lock().unwrap().get(&handle) {
             Some(waiters) => (1, waiters.len()),
             None => (0, 0),
         };
-        let worker = self.worker.clone();
-        let running = match worker.lock().unwrap().get_assigned_job(&handle) {
-            Some(_) => 1,
-            None => 0,
+        // Assigned to any worker, not just one on this connection
+        let job = self.queues.job_by_handle(&handle);
+        let running = match job {
+            Some(ref job) if job.assigned.load(Ordering::SeqCst) > 0 => 1,
+            _ => 0,
         };
-        // TODO Need to intercept work updates for these
-        let numerator = 0;
-        let denominator = 0;
-        let mut data = BytesMut::with_capacity(handle.len() + 2 + 2 + 2 + 2); // handle + null+ known + null + running + null + num + null + denom
-        data.extend(&handle);
+        let status = job.and_then(|job| {
+            let status = *job.status.lock().unwrap();
+            status
+        });
+        let (numerator, denominator) = status.unwrap_or((0, 0));
+        let mut data = BytesMut::with_capacity(handle.as_bytes().len() + 2 + 2 + 2 + 2); // handle + null+ known + null + running + null + num + null + denom
+        data.extend(handle.as_bytes());
         data.put_u8(b'\0');
         data.extend(format!("{}", known).into_bytes());
         data.put_u8(b'\0');
@@ -381,15 +1219,30 @@ impl Service<Packet> for GearmanService {
     }
 
     fn call(&mut self, req: Packet) -> Self::Future {
-        debug!("[{}:{:?}] Got a req {:?}", self.conn_id, self.worker.lock().unwrap().client_id, req);
+        {
+            let mut worker = self.worker.lock().unwrap();
+            worker.touch();
+            debug!(
+                "{}[client_id={:?}] Got a req {:?}",
+                LogContext::conn(self.conn_id),
+                worker.client_id,
+                req
+            );
+        }
+        let ptype = req.ptype;
+        let started = Instant::now();
         let res = match req.ptype {
-            ADMIN_VERSION | ADMIN_STATUS | ADMIN_WORKERS => self.response_from_packet(&req),
-            SUBMIT_JOB => self.handle_submit_job(PRIORITY_NORMAL, true, req),
-            SUBMIT_JOB_HIGH => self.handle_submit_job(PRIORITY_HIGH, true, req),
-            SUBMIT_JOB_LOW => self.handle_submit_job(PRIORITY_LOW, true, req),
-            SUBMIT_JOB_BG => self.handle_submit_job(PRIORITY_NORMAL, false, req),
-            SUBMIT_JOB_HIGH_BG => self.handle_submit_job(PRIORITY_HIGH, false, req),
-            SUBMIT_JOB_LOW_BG => self.handle_submit_job(PRIORITY_LOW, false, req),
+            _ if self.needs_auth(&req) => Ok(self.auth_required(&req)),
+            ADMIN_VERSION | ADMIN_STATUS | ADMIN_WORKERS | ADMIN_SHUTDOWN
+            | ADMIN_SHUTDOWN_GRACEFUL | ADMIN_DRAIN | ADMIN_PING | ADMIN_CANCEL_JOB
+            | ADMIN_FUNCTION_STATS | ADMIN_GETPID | ADMIN_ID | ADMIN_SHOW_JOBS
+            | ADMIN_KILL_IDLE | ADMIN_DROP_FUNCTION | ADMIN_SHOW_UNIQUE | ADMIN_UNKNOWN => {
+                self.response_from_packet(&req)
+            }
+            SUBMIT_JOB | SUBMIT_JOB_HIGH | SUBMIT_JOB_LOW | SUBMIT_JOB_BG | SUBMIT_JOB_HIGH_BG
+            | SUBMIT_JOB_LOW_BG | SUBMIT_REDUCE_JOB | SUBMIT_REDUCE_JOB_BACKGROUND => {
+                self.handle_submit(req)
+            }
             GET_STATUS => self.handle_get_status(&req),
             PRE_SLEEP => self.handle_pre_sleep(),
             CAN_DO => self.handle_can_do(&req),
@@ -398,17 +1251,49 @@ impl Service<Packet> for GearmanService {
             GRAB_JOB_UNIQ => self.handle_grab_job_uniq(),
             GRAB_JOB_ALL => self.handle_grab_job_all(),
             WORK_COMPLETE => self.handle_work_complete(&req),
-            WORK_STATUS | WORK_DATA | WORK_WARNING => self.handle_work_update(&req),
+            WORK_FAIL => self.handle_work_fail(&req),
+            WORK_EXCEPTION => self.handle_work_exception(&req),
+            WORK_STATUS => self.handle_work_status(&req),
+            WORK_DATA | WORK_WARNING => self.handle_work_update(&req),
             SET_CLIENT_ID => self.handle_set_client_id(&req),
+            OPTION_REQ => self.handle_option_req(&req),
             ECHO_REQ => Ok(new_res(ECHO_RES, req.data)),
+            // 0 and 5 are reserved by the protocol and never valid
+            0 | 5 => {
+                error!(
+                    "{} Rejecting reserved packet type {}",
+                    LogContext::conn(self.conn_id),
+                    req.ptype
+                );
+                Ok(new_error(
+                    "invalid_packet_type",
+                    &format!("Packet type {} is reserved", req.ptype),
+                ))
+            }
             _ => {
-                error!("Unimplemented: {:?} processing packet", req);
+                error!(
+                    "{} Unimplemented: {:?} processing packet",
+                    LogContext::conn(self.conn_id),
+                    req
+                );
                 Err(io::Error::new(
                     io::ErrorKind::Other,
                     format!("Invalid packet type {}", req.ptype),
                 ))
             }
         };
+        if let Some(threshold) = self.slow_packet_threshold {
+            let took = started.elapsed();
+            if took > threshold {
+                warn!(
+                    "{} Slow packet: {} ({}) took {:?}",
+                    LogContext::conn(self.conn_id),
+                    PTYPES.get(ptype as usize).map_or("ADMIN", |p| p.name),
+                    ptype,
+                    took
+                );
+            }
+        }
         let fut = async { res };
         Box::pin(fut)
     }
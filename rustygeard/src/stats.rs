@@ -0,0 +1,48 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// How many of the most recent runtimes are kept per function
+const RUNTIME_WINDOW: usize = 1024;
+
+/// Rolling completion statistics for one function
+#[derive(Debug, Default)]
+pub struct FunctionStats {
+    pub completed: u64,
+    pub failed: u64,
+    runtimes: VecDeque<Duration>,
+}
+
+impl FunctionStats {
+    /// Counts a completed job that ran for `runtime`, from assignment to WORK_COMPLETE
+    pub fn record_complete(&mut self, runtime: Duration) {
+        self.completed += 1;
+        if self.runtimes.len() == RUNTIME_WINDOW {
+            self.runtimes.pop_front();
+        }
+        self.runtimes.push_back(runtime);
+    }
+
+    /// Counts a job that failed for good, after any retries
+    pub fn record_failure(&mut self) {
+        self.failed += 1;
+    }
+
+    /// Mean runtime of the recent completions
+    pub fn avg_runtime(&self) -> Option<Duration> {
+        if self.runtimes.is_empty() {
+            return None;
+        }
+        Some(self.runtimes.iter().sum::<Duration>() / self.runtimes.len() as u32)
+    }
+
+    /// Runtime that `percent` of the recent completions finished within
+    pub fn percentile_runtime(&self, percent: f64) -> Option<Duration> {
+        if self.runtimes.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = self.runtimes.iter().cloned().collect();
+        sorted.sort();
+        let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+        Some(sorted[rank.max(1).min(sorted.len()) - 1])
+    }
+}
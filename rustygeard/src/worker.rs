@@ -3,12 +3,17 @@ extern crate wrappinghashset;
 use self::wrappinghashset::{Iter, WrappingHashSet};
 
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use tokio::sync::Notify;
 
-use rustygear::job::Job;
+use rustygear::job::{Job, JobHandle};
+
+use crate::scheduler::{RoundRobin, Scheduler};
 
 #[derive(Debug)]
 pub struct WorkerSet {
@@ -28,6 +33,8 @@ impl WorkerSet {
 pub struct Workers {
     allworkers: HashMap<Bytes, WorkerSet>,
     wakeworkers: HashSet<usize>,
+    last_assigned: HashMap<usize, Instant>,
+    scheduler: Arc<dyn Scheduler>,
 }
 
 pub type SharedWorkers = Arc<Mutex<Workers>>;
@@ -39,7 +46,11 @@ pub trait Wake {
     fn sleep(&mut self, worker: &mut Worker, remote: usize);
     fn wakeup(&mut self, worker: &mut Worker, remote: usize);
     fn count_workers(&mut self, fname: &Bytes) -> (usize, usize);
+    fn assigned(&mut self, conn_id: usize);
     fn shutdown(&mut self, conn_id: usize);
+    fn forget(&mut self, fname: &Bytes, conn_id: usize);
+    fn functions(&self) -> Vec<Vec<u8>>;
+    fn set_scheduler(&mut self, scheduler: Arc<dyn Scheduler>);
 }
 
 impl Wake for SharedWorkers {
@@ -47,17 +58,32 @@ impl Wake for SharedWorkers {
         Arc::new(Mutex::new(Workers::new()))
     }
 
+    /// Picks sleeping workers to wake for a new `fname` job
+    ///
+    /// Only workers that sent CAN_DO for `fname` are considered, since
+    /// `allworkers` is indexed by function. Which one is up to the [Scheduler].
     fn queue_wake(&mut self, fname: &Bytes) -> Vec<usize> {
         let mut workers = self.lock().unwrap();
+        let workers = &mut *workers;
         debug!("allworkers({:?}) = {:?}", fname, workers.allworkers);
         match workers.allworkers.get_mut(fname) {
             None => Vec::new(),
             Some(workerset) => {
-                // Copy the contents into active
-                workerset.active.extend(workerset.inactive.iter());
-                info!("Waking up inactive workers: {:?}", &workerset.inactive);
-                // Empty the contents into inserts
-                workerset.inactive.drain().collect()
+                // Only wake one sleeper, so a burst of jobs is spread across idle
+                // workers rather than all going to whichever one grabs fastest.
+                let last_assigned = &workers.last_assigned;
+                let mut candidates: Vec<usize> = workerset.inactive.iter().cloned().collect();
+                candidates.sort_by_key(|conn_id| (last_assigned.get(conn_id), *conn_id));
+                let next = workers.scheduler.pick_worker(fname, &candidates);
+                match next {
+                    None => Vec::new(),
+                    Some(conn_id) => {
+                        info!("Waking up inactive worker: {}", conn_id);
+                        workerset.inactive.remove(&conn_id);
+                        workerset.active.insert(conn_id);
+                        vec![conn_id]
+                    }
+                }
             }
         }
     }
@@ -126,8 +152,14 @@ impl Wake for SharedWorkers {
         }
     }
 
+    fn assigned(&mut self, conn_id: usize) {
+        let mut workers = self.lock().unwrap();
+        workers.last_assigned.insert(conn_id, Instant::now());
+    }
+
     fn shutdown(&mut self, conn_id: usize) {
         let mut workers = self.lock().unwrap();
+        workers.last_assigned.remove(&conn_id);
         for (_, workerset) in workers.allworkers.iter_mut() {
             workerset.inactive.remove(&conn_id);
             workerset.active.remove(&conn_id);
@@ -138,6 +170,31 @@ impl Wake for SharedWorkers {
             workers.allworkers
         );
     }
+
+    /// Stops waking `conn_id` for `fname` jobs, after it sent CANT_DO
+    fn forget(&mut self, fname: &Bytes, conn_id: usize) {
+        let mut workers = self.lock().unwrap();
+        if let Some(workerset) = workers.allworkers.get_mut(fname) {
+            workerset.inactive.remove(&conn_id);
+            workerset.active.remove(&conn_id);
+        }
+    }
+
+    /// Snapshot of the functions at least one connected worker can do
+    fn functions(&self) -> Vec<Vec<u8>> {
+        let workers = self.lock().unwrap();
+        workers
+            .allworkers
+            .iter()
+            .filter(|(_, workerset)| !workerset.active.is_empty() || !workerset.inactive.is_empty())
+            .map(|(fname, _)| fname.to_vec())
+            .collect()
+    }
+
+    /// Replaces the [RoundRobin] scheduler used by [Wake::queue_wake]
+    fn set_scheduler(&mut self, scheduler: Arc<dyn Scheduler>) {
+        self.lock().unwrap().scheduler = scheduler;
+    }
 }
 
 impl Workers {
@@ -145,16 +202,52 @@ impl Workers {
         Workers {
             allworkers: HashMap::new(),
             wakeworkers: HashSet::new(),
+            last_assigned: HashMap::new(),
+            scheduler: Arc::new(RoundRobin),
         }
     }
 }
 
+/// A snapshot of one connection's state, see [Worker::info]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    pub conn_id: usize,
+    pub peer_addr: SocketAddr,
+    pub client_id: Bytes,
+    /// Functions sent with CAN_DO, sorted
+    pub functions: Vec<Bytes>,
+    /// Options accepted with OPTION_REQ, sorted
+    pub options: Vec<&'static str>,
+    pub sleeping: bool,
+    pub assigned: usize,
+    /// Packets queued to send to the connection, see [crate::service::outbound_depth]
+    pub outbound_depth: usize,
+}
+
 #[derive(Debug)]
 pub struct Worker {
     pub peer_addr: SocketAddr,
     pub functions: WrappingHashSet<Bytes>,
+    abilities: HashSet<Bytes>,
     pub client_id: Bytes,
-    jobs: HashMap<Bytes, Arc<Job>>,
+    /// Set by PRE_SLEEP, cleared when the worker grabs again
+    pub sleeping: bool,
+    /// Set by OPTION_REQ `exceptions`, the connection is sent WORK_EXCEPTION
+    /// rather than WORK_FAIL for jobs it waits on
+    pub exceptions: bool,
+    /// Set by OPTION_REQ `aggregate`, WORK_DATA for jobs the connection waits
+    /// on is held back and sent as part of WORK_COMPLETE
+    pub aggregate: bool,
+    /// Set by OPTION_REQ `metadata`, submitted data starts with metadata and
+    /// JOB_ASSIGN data is sent with it, see [rustygear::util::encode_metadata]
+    pub metadata: bool,
+    aggregated: HashMap<JobHandle, BytesMut>,
+    jobs: HashMap<JobHandle, Arc<Job>>,
+    assigned_at: HashMap<JobHandle, Instant>,
+    /// When the connection last sent a packet
+    last_active: Instant,
+    /// Notified to make the connection's reader hang up, see [Worker::close]
+    pub closer: Arc<Notify>,
 }
 
 impl Worker {
@@ -162,45 +255,151 @@ impl Worker {
         Worker {
             peer_addr: peer_addr,
             functions: WrappingHashSet::new(),
+            abilities: HashSet::new(),
             client_id: client_id,
+            sleeping: false,
+            exceptions: false,
+            aggregate: false,
+            metadata: false,
+            aggregated: HashMap::new(),
             jobs: HashMap::new(),
+            assigned_at: HashMap::new(),
+            last_active: Instant::now(),
+            closer: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Records that the connection just sent a packet
+    pub fn touch(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    /// How long this worker has gone without sending anything, `None` if it
+    /// isn't a worker or is busy with a job
+    pub fn idle_for(&self) -> Option<Duration> {
+        if self.abilities.is_empty() || !self.jobs.is_empty() {
+            return None;
         }
+        Some(self.last_active.elapsed())
+    }
+
+    /// Asks the connection's reader to hang up
+    pub fn close(&self) {
+        self.closer.notify_one();
     }
 
     pub fn can_do(&mut self, fname: Bytes) {
+        self.abilities.insert(fname.clone());
         self.functions.insert(fname);
     }
 
     pub fn cant_do<'b>(&mut self, fname: &'b Bytes) {
+        self.abilities.remove(fname);
         self.functions.remove(fname);
     }
 
+    /// Functions this worker sent CAN_DO for, in no particular order
+    pub fn abilities(&self) -> impl Iterator<Item = &Bytes> {
+        self.abilities.iter()
+    }
+
+    /// Whether this worker sent CAN_DO for `fname`
+    pub fn can(&self, fname: &Bytes) -> bool {
+        self.abilities.contains(fname)
+    }
+
     pub fn iter<'i>(&'i mut self) -> Iter<'i, Bytes> {
         self.functions.iter()
     }
 
     pub fn assign_job(&mut self, job: &Arc<Job>) {
-        self.jobs.insert(job.handle.clone(), job.clone());
+        if self.jobs.insert(job.handle.clone(), job.clone()).is_none() {
+            job.assigned.fetch_add(1, Ordering::SeqCst);
+        }
+        self.assigned_at.insert(job.handle.clone(), Instant::now());
     }
 
-    pub fn unassign_job(&mut self, handle: &Bytes) {
+    /// How long the job with `handle` has been assigned to this worker
+    pub fn assigned_for(&self, handle: &JobHandle) -> Option<Duration> {
+        self.assigned_at.get(handle).map(|at| at.elapsed())
+    }
+
+    pub fn unassign_job(&mut self, handle: &JobHandle) {
+        self.assigned_at.remove(handle);
         match self.jobs.remove(handle) {
             None => warn!("Worker was not assigned {:?}", handle),
-            Some(ref j) => match Arc::weak_count(j) {
-                0 => {}
-                a @ _ => {
-                    warn!(
-                        "Unassigning queued {:?} ({}+{} refs)",
-                        j,
-                        Arc::strong_count(j),
-                        a
-                    );
+            Some(ref j) => {
+                j.assigned.fetch_sub(1, Ordering::SeqCst);
+                match Arc::weak_count(j) {
+                    0 => {}
+                    a @ _ => {
+                        warn!(
+                            "Unassigning queued {:?} ({}+{} refs)",
+                            j,
+                            Arc::strong_count(j),
+                            a
+                        );
+                    }
                 }
-            },
+            }
         }
     }
 
-    pub fn get_assigned_job(&self, handle: &Bytes) -> Option<&Arc<Job>> {
+    pub fn get_assigned_job(&self, handle: &JobHandle) -> Option<&Arc<Job>> {
         self.jobs.get(handle)
     }
+
+    /// Snapshots this connection's state, `conn_id` being the one it is stored under
+    pub fn info(&self, conn_id: usize) -> ConnectionInfo {
+        let mut functions: Vec<Bytes> = self.abilities.iter().cloned().collect();
+        functions.sort();
+        let mut options = Vec::new();
+        if self.aggregate {
+            options.push("aggregate");
+        }
+        if self.exceptions {
+            options.push("exceptions");
+        }
+        if self.metadata {
+            options.push("metadata");
+        }
+        ConnectionInfo {
+            conn_id: conn_id,
+            peer_addr: self.peer_addr,
+            client_id: self.client_id.clone(),
+            functions: functions,
+            options: options,
+            sleeping: self.sleeping,
+            assigned: self.jobs.len(),
+            outbound_depth: 0,
+        }
+    }
+
+    /// Holds back WORK_DATA for the job with `handle`, see [Worker::aggregate]
+    pub fn aggregate_data(&mut self, handle: &JobHandle, data: &[u8]) {
+        self.aggregated
+            .entry(handle.clone())
+            .or_insert_with(BytesMut::new)
+            .extend_from_slice(data);
+    }
+
+    /// Takes the WORK_DATA held back for the job with `handle`, if any
+    pub fn take_aggregated(&mut self, handle: &JobHandle) -> Option<BytesMut> {
+        self.aggregated.remove(handle)
+    }
+
+    /// Number of jobs currently assigned to this worker
+    pub fn assigned_count(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// Unassigns and returns all jobs, used when the worker goes away
+    pub fn drain_jobs(&mut self) -> Vec<Arc<Job>> {
+        self.assigned_at.clear();
+        let jobs: Vec<Arc<Job>> = self.jobs.drain().map(|(_, job)| job).collect();
+        for job in jobs.iter() {
+            job.assigned.fetch_sub(1, Ordering::SeqCst);
+        }
+        jobs
+    }
 }
@@ -9,9 +9,11 @@ use std::collections::BTreeMap;
 use bytes::Bytes;
 
 use rustygear::constants::*;
-use rustygear::job::Job;
+use rustygear::job::{Job, JobHandle};
 
-use rustygeard::admin::{admin_command_status, admin_command_workers};
+use rustygeard::admin::{
+    admin_command_show_jobs, admin_command_show_unique, admin_command_status, admin_command_workers,
+};
 use rustygeard::queues::{HandleJobStorage, SharedJobStorage};
 use rustygeard::worker::{SharedWorkers, Wake, Worker};
 use rustygeard::service::WorkersByConnId;
@@ -22,13 +24,13 @@ fn admin_command_status_1job() {
         Bytes::from("f"),
         Bytes::from("u"),
         Bytes::new(),
-        Bytes::from("h"),
+        JobHandle::from("h"),
     );
     let mut w = Worker::new("127.0.0.1:37337".parse().unwrap(), Bytes::from("client1"));
     w.can_do(Bytes::from("f"));
     let mut storage = SharedJobStorage::new_job_storage();
     let mut workers = SharedWorkers::new_workers();
-    storage.add_job(Arc::new(j), PRIORITY_NORMAL, None);
+    storage.add_job(Arc::new(j), PRIORITY_NORMAL, None).unwrap();
     workers.sleep(&mut w, 1);
     let packet = admin_command_status(storage, workers);
     assert_eq!(b"f\t1\t0\t1\n.\n", &packet.data[..])
@@ -68,3 +70,42 @@ fn admin_command_workers_with2() {
     let expected = String::from("10 127.0.0.1:37337 hacker1 : hack\n11 127.0.0.1:33333 - :\n.\n");
     assert_eq!(expected, response);
 }
+
+#[test]
+fn admin_command_show_jobs_lists_queue() {
+    let mut storage = SharedJobStorage::new_job_storage();
+    let jobs = [
+        ("u1", "H:1", PRIORITY_NORMAL),
+        ("u2", "H:2", PRIORITY_HIGH),
+        ("u3", "H:3", PRIORITY_NORMAL),
+    ];
+    for (unique, handle, priority) in jobs.iter() {
+        let j = Job::new(Bytes::from("f"), Bytes::from(*unique), Bytes::new(), JobHandle::from(*handle));
+        storage.add_job(Arc::new(j), *priority, None).unwrap();
+    }
+    let other = Job::new(Bytes::from("g"), Bytes::from("u4"), Bytes::new(), JobHandle::from("H:4"));
+    storage.add_job(Arc::new(other), PRIORITY_NORMAL, None).unwrap();
+    let packet = admin_command_show_jobs(storage.clone(), &Bytes::from("f"), 10);
+    let text = String::from_utf8(packet.data.to_vec()).unwrap();
+    let rows: Vec<Vec<&str>> = text.lines().map(|line| line.split('\t').collect()).collect();
+    assert_eq!(4, rows.len(), "{}", text);
+    let handles: Vec<(&str, &str, &str)> = rows[..3].iter().map(|row| (row[0], row[1], row[3])).collect();
+    assert_eq!(
+        vec![("H:2", "u2", "high"), ("H:1", "u1", "normal"), ("H:3", "u3", "normal")],
+        handles
+    );
+    assert_eq!(vec!["."], rows[3]);
+    let limited = admin_command_show_jobs(storage, &Bytes::from("f"), 1);
+    assert_eq!(2, limited.data.split(|b| *b == b'\n').filter(|l| !l.is_empty()).count());
+}
+
+#[test]
+fn admin_command_show_unique_counts_coalesced_waiters() {
+    let mut storage = SharedJobStorage::new_job_storage();
+    let j = Job::new(Bytes::from("f"), Bytes::from("u1"), Bytes::new(), JobHandle::from("H:1"));
+    storage.add_job(Arc::new(j), PRIORITY_NORMAL, Some(1)).unwrap();
+    assert_eq!(Some(JobHandle::from("H:1")), storage.coalesce_unique(&Bytes::from("u1"), Some(2)));
+    let packet = admin_command_show_unique(storage, 10);
+    let text = String::from_utf8(packet.data.to_vec()).unwrap();
+    assert_eq!("u1\tH:1\tqueued\t2\n.\n", text);
+}
@@ -0,0 +1,24 @@
+extern crate rustygeard;
+
+use std::collections::HashSet;
+
+use rustygeard::handles::{CounterHandles, HandleGenerator, UuidHandles};
+
+#[test]
+fn generators_make_distinct_well_formed_handles() {
+    let counter = CounterHandles::with_host("gear1");
+    let uuids = UuidHandles;
+    let mut seen = HashSet::new();
+    for i in 0..3 {
+        let handle = counter.next_handle();
+        assert_eq!(format!("H:gear1:{:010}", i), handle.to_string());
+        assert!(seen.insert(handle.to_bytes()));
+    }
+    for _ in 0..3 {
+        let handle = uuids.next_handle().to_string();
+        let parts: Vec<&str> = handle.splitn(2, ':').collect();
+        assert_eq!("H", parts[0]);
+        assert_eq!(36, parts[1].len(), "{}", handle);
+        assert!(seen.insert(handle.into()));
+    }
+}
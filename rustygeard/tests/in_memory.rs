@@ -0,0 +1,47 @@
+extern crate bytes;
+extern crate futures;
+extern crate rustygear;
+extern crate rustygeard;
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio_util::codec::Decoder;
+
+use rustygear::codec::PacketCodec;
+use rustygear::constants::*;
+use rustygear::util::new_req;
+
+use rustygeard::server::GearmanServer;
+
+#[tokio::test]
+async fn submit_grab_complete_in_memory() {
+    let (client, worker) = GearmanServer::spawn_in_memory();
+    let mut client = PacketCodec::new().framed(client);
+    let mut worker = PacketCodec::new().framed(worker);
+
+    worker.send(new_req(CAN_DO, Bytes::from("reverse"))).await.unwrap();
+    client
+        .send(new_req(SUBMIT_JOB, Bytes::from("reverse\0u1\0abc")))
+        .await
+        .unwrap();
+    let created = client.next().await.unwrap().unwrap();
+    assert_eq!(JOB_CREATED, created.ptype);
+    let handle = created.data;
+
+    worker.send(new_req(GRAB_JOB, Bytes::new())).await.unwrap();
+    let assigned = worker.next().await.unwrap().unwrap();
+    assert_eq!(JOB_ASSIGN, assigned.ptype);
+    let mut expected = handle.to_vec();
+    expected.extend(b"\0reverse\0abc");
+    assert_eq!(&expected[..], &assigned.data[..]);
+
+    let mut complete = handle.to_vec();
+    complete.extend(b"\0cba");
+    worker
+        .send(new_req(WORK_COMPLETE, Bytes::from(complete.clone())))
+        .await
+        .unwrap();
+    let response = client.next().await.unwrap().unwrap();
+    assert_eq!(WORK_COMPLETE, response.ptype);
+    assert_eq!(&complete[..], &response.data[..]);
+}
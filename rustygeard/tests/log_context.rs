@@ -0,0 +1,12 @@
+extern crate rustygeard;
+
+use rustygeard::log_context::LogContext;
+
+#[test]
+fn log_context_prefixes() {
+    assert_eq!("[conn=7]", format!("{}", LogContext::conn(7)));
+    assert_eq!(
+        "[conn=7][handle=H:0000000001]",
+        format!("{}", LogContext::handle(7, b"H:0000000001"))
+    );
+}
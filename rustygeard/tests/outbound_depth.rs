@@ -0,0 +1,71 @@
+extern crate log;
+extern crate rustygeard;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use tokio::sync::mpsc::channel;
+
+use rustygear::codec::Packet;
+use rustygear::constants::*;
+use rustygear::util::new_res;
+
+use rustygeard::queues::{HandleJobStorage, SharedJobStorage};
+use rustygeard::service::{check_outbound_depth, GearmanService, SendersByConnId, WorkersByConnId};
+use rustygeard::worker::{SharedWorkers, Wake};
+
+/// Keeps every warning logged by this test binary
+struct Captured;
+
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+static LOGGER: Captured = Captured;
+
+impl Log for Captured {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            WARNINGS.lock().unwrap().push(format!("{}", record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[tokio::test]
+async fn backed_up_connection_is_measured_and_reported() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Warn);
+    let senders_by_conn_id: SendersByConnId = Arc::new(Mutex::new(HashMap::new()));
+    let workers_by_conn_id: WorkersByConnId = Arc::new(Mutex::new(BTreeMap::new()));
+    // Nothing ever reads from this end, like a peer that stopped reading
+    let (tx, _rx) = channel::<Packet>(8);
+    senders_by_conn_id.lock().unwrap().insert(3, tx.clone());
+    let service = GearmanService::new(
+        3,
+        SharedJobStorage::new_job_storage(),
+        SharedWorkers::new_workers(),
+        Arc::new(AtomicUsize::new(0)),
+        senders_by_conn_id.clone(),
+        workers_by_conn_id.clone(),
+        Arc::new(Mutex::new(HashMap::new())),
+        "127.0.0.1:37337".parse().unwrap(),
+    );
+    workers_by_conn_id.lock().unwrap().insert(3, service.worker.clone());
+    let mut warned = Vec::new();
+    for _ in 0..6 {
+        tx.try_send(new_res(NOOP, Bytes::new())).unwrap();
+        warned.push(check_outbound_depth(3, &tx, Some(4)));
+    }
+    // Only reaching the mark warns, not every packet past it
+    assert_eq!(vec![false, false, false, true, false, false], warned);
+    let warnings = WARNINGS.lock().unwrap().clone();
+    assert_eq!(1, warnings.len(), "{:?}", warnings);
+    assert!(warnings[0].starts_with("[conn=3] 4 packets queued to send, of 8"), "{}", warnings[0]);
+    assert_eq!(6, service.connection_info(3).unwrap().outbound_depth);
+}
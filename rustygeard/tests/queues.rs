@@ -0,0 +1,251 @@
+extern crate bytes;
+extern crate rustygear;
+extern crate rustygeard;
+
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use rustygear::constants::*;
+use rustygear::job::{Job, JobHandle};
+
+use rustygeard::queues::{EnqueueError, HandleJobStorage, JobState, SharedJobStorage};
+use rustygeard::worker::Worker;
+
+#[test]
+fn job_wait_is_recorded_when_grabbed() {
+    let j = Job::new(
+        Bytes::from("f"),
+        Bytes::from("u"),
+        Bytes::new(),
+        JobHandle::from("h"),
+    );
+    let mut w = Worker::new("127.0.0.1:37337".parse().unwrap(), Bytes::from("client1"));
+    w.can_do(Bytes::from("f"));
+    let mut storage = SharedJobStorage::new_job_storage();
+    storage.add_job(Arc::new(j), PRIORITY_NORMAL, None).unwrap();
+    thread::sleep(Duration::from_millis(20));
+    assert!(storage.shard(&Bytes::from("f")).oldest_queued_age(&Bytes::from("f")).unwrap() >= Duration::from_millis(20));
+    assert!(storage.get_job(&mut w).is_some());
+    let storage = storage.shard(&Bytes::from("f"));
+    assert!(storage.last_wait(&Bytes::from("f")).unwrap() >= Duration::from_millis(20));
+    assert_eq!(None, storage.oldest_queued_age(&Bytes::from("f")));
+}
+
+#[test]
+fn jobs_are_grabbed_in_submission_order() {
+    let mut storage = SharedJobStorage::new_job_storage();
+    for i in 0..100 {
+        let j = Job::new(
+            Bytes::from("f"),
+            Bytes::from(format!("u{}", i)),
+            Bytes::new(),
+            JobHandle::from(Bytes::from(format!("h{}", i))),
+        );
+        storage.add_job(Arc::new(j), PRIORITY_NORMAL, None).unwrap();
+    }
+    let g = Job::new(Bytes::from("g"), Bytes::from("ug"), Bytes::new(), JobHandle::from("hg"));
+    storage.add_job(Arc::new(g), PRIORITY_HIGH, None).unwrap();
+    let mut w = Worker::new("127.0.0.1:37337".parse().unwrap(), Bytes::from("client1"));
+    w.can_do(Bytes::from("f"));
+    w.can_do(Bytes::from("g"));
+    let mut grabbed = Vec::new();
+    while let Some(job) = storage.get_job(&mut w) {
+        grabbed.push(job.handle.clone());
+    }
+    // Every job comes out exactly once, and those for f in the order submitted
+    assert_eq!(101, grabbed.len());
+    let expected: Vec<JobHandle> = (0..100).map(|i| JobHandle::from(Bytes::from(format!("h{}", i)))).collect();
+    grabbed.retain(|handle| handle != &JobHandle::from("hg"));
+    assert_eq!(expected, grabbed);
+}
+
+#[test]
+fn functions_with_queued_lists_waiting_jobs() {
+    let mut storage = SharedJobStorage::new_job_storage();
+    assert!(storage.functions_with_queued().is_empty());
+    let j = Job::new(Bytes::from("f"), Bytes::from("u"), Bytes::new(), JobHandle::from("h"));
+    storage.add_job(Arc::new(j), PRIORITY_LOW, None).unwrap();
+    assert_eq!(vec![b"f".to_vec()], storage.functions_with_queued());
+    let mut w = Worker::new("127.0.0.1:37337".parse().unwrap(), Bytes::from("client1"));
+    w.can_do(Bytes::from("f"));
+    storage.get_job(&mut w).unwrap();
+    assert!(storage.functions_with_queued().is_empty());
+}
+
+#[test]
+fn dependency_cycle_is_refused() {
+    let mut storage = SharedJobStorage::new_job_storage();
+    let a = Arc::new(Job::new(Bytes::from("f"), Bytes::from("a"), Bytes::new(), JobHandle::from("ha")));
+    let b = Arc::new(Job::new(Bytes::from("f"), Bytes::from("b"), Bytes::new(), JobHandle::from("hb")));
+    storage.add_job(a.clone(), PRIORITY_NORMAL, None).unwrap();
+    storage
+        .add_dependent_job(b, PRIORITY_NORMAL, None, Bytes::from("a"))
+        .unwrap();
+    match storage.add_dependent_job(a, PRIORITY_NORMAL, None, Bytes::from("b")) {
+        Err(EnqueueError::DependencyCycle) => {}
+        other => panic!("expected a cycle, got {:?}", other),
+    }
+}
+
+#[test]
+fn dispatch_with_many_idle_functions() {
+    let mut storage = SharedJobStorage::new_job_storage();
+    let mut w = Worker::new("127.0.0.1:37337".parse().unwrap(), Bytes::from("client1"));
+    for i in 0..5000 {
+        w.can_do(Bytes::from(format!("f{}", i)));
+    }
+    assert!(storage.get_job(&mut w).is_none());
+    let j = Job::new(Bytes::from("f4999"), Bytes::from("u"), Bytes::new(), JobHandle::from("h"));
+    storage.add_job(Arc::new(j), PRIORITY_NORMAL, None).unwrap();
+    w.cant_do(&Bytes::from("f4999"));
+    assert!(storage.get_job(&mut w).is_none());
+    w.can_do(Bytes::from("f4999"));
+    assert_eq!(JobHandle::from("h"), storage.get_job(&mut w).unwrap().handle);
+}
+
+#[test]
+fn grabs_rotate_across_backlogged_functions() {
+    let mut storage = SharedJobStorage::new_job_storage();
+    let mut w = Worker::new("127.0.0.1:37337".parse().unwrap(), Bytes::from("client1"));
+    for fname in ["f", "g", "h"].iter() {
+        w.can_do(Bytes::from(*fname));
+        for i in 0..3 {
+            let unique = format!("{}{}", fname, i);
+            let j = Job::new(Bytes::from(*fname), Bytes::from(unique.clone()), Bytes::new(), JobHandle::from(Bytes::from(unique)));
+            storage.add_job(Arc::new(j), PRIORITY_NORMAL, None).unwrap();
+        }
+    }
+    let grabbed: Vec<Bytes> = (0..9).map(|_| storage.get_job(&mut w).unwrap().fname.clone()).collect();
+    // Each round of three grabs takes one job from every function
+    for round in grabbed.chunks(3) {
+        let mut round = round.to_vec();
+        round.sort();
+        assert_eq!(vec![Bytes::from("f"), Bytes::from("g"), Bytes::from("h")], round, "{:?}", grabbed);
+    }
+}
+
+#[test]
+fn aged_low_job_overtakes_newer_normal_jobs() {
+    let mut storage = SharedJobStorage::new_job_storage();
+    storage
+        .set_aging_threshold(Some(Duration::from_millis(20)));
+    let new_job = |i: usize| {
+        Arc::new(Job::new(
+            Bytes::from("f"),
+            Bytes::from(format!("u{}", i)),
+            Bytes::new(),
+            JobHandle::from(Bytes::from(format!("h{}", i))),
+        ))
+    };
+    storage.add_job(new_job(0), PRIORITY_LOW, None).unwrap();
+    thread::sleep(Duration::from_millis(30));
+    storage.add_job(new_job(1), PRIORITY_NORMAL, None).unwrap();
+    storage.add_job(new_job(2), PRIORITY_NORMAL, None).unwrap();
+    let mut w = Worker::new("127.0.0.1:37337".parse().unwrap(), Bytes::from("client1"));
+    w.can_do(Bytes::from("f"));
+    let grabbed: Vec<JobHandle> = (0..3).map(|_| storage.get_job(&mut w).unwrap().handle.clone()).collect();
+    assert_eq!(vec![JobHandle::from("h0"), JobHandle::from("h1"), JobHandle::from("h2")], grabbed);
+}
+
+#[test]
+fn handle_round_trips_through_storage() {
+    let mut storage = SharedJobStorage::new_job_storage();
+    let j = Job::new(Bytes::from("f"), Bytes::from("u"), Bytes::new(), JobHandle::from("H:1"));
+    storage.add_job(Arc::new(j), PRIORITY_NORMAL, Some(7)).unwrap();
+    // A handle parsed back off the wire finds the same job
+    let handle = JobHandle::from(Bytes::from(&b"H:1"[..]));
+    let storage = storage.shard_for_handle(&handle).unwrap();
+    assert_eq!(Bytes::from("u"), storage.job_by_handle(&handle).unwrap().unique);
+    assert_eq!(Some(&vec![7]), storage.remotes_by_handle(&handle));
+    assert!(storage.job_by_handle(&JobHandle::from("H:2")).is_none());
+}
+
+#[test]
+fn removed_job_is_not_found_by_handle() {
+    let mut storage = SharedJobStorage::new_job_storage();
+    let j = Job::new(Bytes::from("f"), Bytes::from("u"), Bytes::new(), JobHandle::from("H:1"));
+    storage.add_job(Arc::new(j), PRIORITY_NORMAL, None).unwrap();
+    storage.shard(&Bytes::from("f")).remove_job(&Bytes::from("u"));
+    assert!(storage.job_by_handle(&JobHandle::from("H:1")).is_none());
+    assert!(storage.shard_for_handle(&JobHandle::from("H:1")).is_none());
+}
+
+#[test]
+fn snapshot_lists_queued_and_running_jobs() {
+    let mut storage = SharedJobStorage::new_job_storage();
+    let jobs = [("f", "u1", "h1", PRIORITY_HIGH), ("g", "u2", "h2", PRIORITY_LOW), ("f", "u3", "h3", PRIORITY_NORMAL)];
+    for (fname, unique, handle, priority) in jobs.iter() {
+        let j = Job::new(Bytes::from(*fname), Bytes::from(*unique), Bytes::new(), JobHandle::from(*handle));
+        storage.add_job(Arc::new(j), *priority, None).unwrap();
+        thread::sleep(Duration::from_millis(2));
+    }
+    let mut w = Worker::new("127.0.0.1:37337".parse().unwrap(), Bytes::from("client1"));
+    w.can_do(Bytes::from("f"));
+    let running = storage.get_job(&mut w).unwrap();
+    assert_eq!(JobHandle::from("h1"), running.handle);
+
+    let snapshot = storage.snapshot(10);
+    let summary: Vec<(&[u8], &[u8], Option<usize>, JobState)> = snapshot
+        .iter()
+        .map(|job| (&job.fname[..], job.handle.as_bytes(), job.priority, job.state))
+        .collect();
+    assert_eq!(
+        vec![
+            (&b"f"[..], &b"h1"[..], None, JobState::Running),
+            (b"g", b"h2", Some(PRIORITY_LOW), JobState::Queued),
+            (b"f", b"h3", Some(PRIORITY_NORMAL), JobState::Queued),
+        ],
+        summary
+    );
+    assert!(snapshot[0].age > snapshot[2].age);
+    assert_eq!(1, storage.snapshot(1).len());
+}
+
+/// A function kept in a different shard than `fname`
+fn function_in_other_shard(storage: &SharedJobStorage, fname: &Bytes) -> Bytes {
+    (0..)
+        .map(|i| Bytes::from(format!("g{}", i)))
+        .find(|other| storage.shard_of(other) != storage.shard_of(fname))
+        .unwrap()
+}
+
+#[test]
+fn locked_shard_does_not_hold_up_other_functions() {
+    let storage = SharedJobStorage::new_job_storage();
+    let f = Bytes::from("f");
+    let g = function_in_other_shard(&storage, &f);
+    let _locked = storage.shard(&f);
+    let (done_tx, done_rx) = mpsc::channel();
+    let mut other = storage.clone();
+    thread::spawn(move || {
+        let j = Job::new(g.clone(), Bytes::from("u"), Bytes::new(), JobHandle::from("H:1"));
+        other.add_job(Arc::new(j), PRIORITY_NORMAL, None).unwrap();
+        let mut w = Worker::new("127.0.0.1:37337".parse().unwrap(), Bytes::from("client1"));
+        w.can_do(g);
+        done_tx.send(other.get_job(&mut w).map(|job| job.handle.clone())).unwrap();
+    });
+    let grabbed = done_rx.recv_timeout(Duration::from_secs(5)).expect("waited on another shard");
+    assert_eq!(Some(JobHandle::from("H:1")), grabbed);
+}
+
+#[test]
+fn dependency_in_another_shard_releases_its_dependent() {
+    let mut storage = SharedJobStorage::new_job_storage();
+    let f = Bytes::from("f");
+    let g = function_in_other_shard(&storage, &f);
+    let a = Arc::new(Job::new(f.clone(), Bytes::from("a"), Bytes::new(), JobHandle::from("ha")));
+    let b = Arc::new(Job::new(g.clone(), Bytes::from("b"), Bytes::new(), JobHandle::from("hb")));
+    storage.add_job(a.clone(), PRIORITY_NORMAL, None).unwrap();
+    storage.add_dependent_job(b, PRIORITY_NORMAL, None, Bytes::from("a")).unwrap();
+    let mut w = Worker::new("127.0.0.1:37337".parse().unwrap(), Bytes::from("client1"));
+    w.can_do(g.clone());
+    assert!(storage.get_job(&mut w).is_none());
+    // Coalescing finds the blocked job from any shard by its unique
+    assert_eq!(Some(JobHandle::from("hb")), storage.coalesce_unique(&Bytes::from("b"), None));
+    storage.shard(&f).remove_job(&a.unique);
+    assert_eq!(vec![g], storage.release_dependents(&a.unique));
+    assert_eq!(JobHandle::from("hb"), storage.get_job(&mut w).unwrap().handle);
+}
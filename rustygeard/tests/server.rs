@@ -0,0 +1,544 @@
+extern crate rustygeard;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+use tokio_util::codec::Decoder;
+
+use rustygear::client::{Client, WorkUpdate};
+use rustygear::codec::{Packet, PacketCodec};
+use rustygear::constants::*;
+
+use rustygeard::config::ServerConfig;
+use rustygeard::handles::CounterHandles;
+use rustygeard::server::GearmanServer;
+
+#[test]
+fn server_starts_and_stops() {
+    let config = ServerConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        max_unhandled_out_frames: 16,
+        ..ServerConfig::default()
+    };
+    let (stop_tx, stop_rx) = oneshot::channel();
+    let server = thread::spawn(move || GearmanServer::with_config(config).serve(stop_rx));
+    stop_tx.send(()).unwrap();
+    server.join().unwrap();
+}
+
+#[tokio::test]
+async fn idle_connection_is_closed() {
+    let config = ServerConfig {
+        idle_timeout: Some(Duration::from_millis(50)),
+        ..ServerConfig::default()
+    };
+    let server = GearmanServer::with_config(config);
+    let mut conn = server.connect_in_memory();
+    let mut buf = [0u8; 12];
+    let read = timeout(Duration::from_secs(5), conn.read(&mut buf))
+        .await
+        .expect("idle connection was not closed");
+    assert_eq!(0, read.unwrap());
+}
+
+#[tokio::test]
+async fn configured_data_size_limit_refuses_large_submissions() {
+    let mut config = ServerConfig::default();
+    config.max_data_sizes.insert(Bytes::from("thumb"), 4);
+    let server = GearmanServer::with_config(config);
+    let mut conn = PacketCodec::new().framed(server.connect_in_memory());
+    conn.send(Packet::from_fields(SUBMIT_JOB_BG, &[b"thumb", b"u1", b"abcd"])).await.unwrap();
+    assert_eq!(JOB_CREATED, conn.next().await.unwrap().unwrap().ptype);
+    conn.send(Packet::from_fields(SUBMIT_JOB_BG, &[b"thumb", b"u2", b"abcde"])).await.unwrap();
+    let over = conn.next().await.unwrap().unwrap();
+    assert_eq!(ERROR, over.ptype);
+    assert!(over.data.starts_with(b"data_too_large\0"));
+}
+
+#[test]
+fn admin_shutdown_stops_server() {
+    let config = ServerConfig {
+        listen: "127.0.0.1:47318".parse().unwrap(),
+        ..ServerConfig::default()
+    };
+    let (_stop_tx, stop_rx) = oneshot::channel();
+    let server = thread::spawn(move || GearmanServer::with_config(config).serve(stop_rx));
+    let mut conn = loop {
+        match TcpStream::connect("127.0.0.1:47318") {
+            Ok(conn) => break conn,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+    conn.write_all(b"shutdown graceful\n").unwrap();
+    let mut buf = [0u8; 3];
+    conn.read_exact(&mut buf).unwrap();
+    assert_eq!(b"OK\n", &buf);
+    server.join().unwrap();
+}
+
+#[tokio::test]
+async fn admin_shutdown_refused_for_remote_peer() {
+    let server = GearmanServer::with_config(ServerConfig::default());
+    let mut conn = server.connect_in_memory();
+    conn.write_all(b"shutdown\n").await.unwrap();
+    let mut buf = [0u8; 4];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(b"ERR ", &buf);
+}
+
+#[tokio::test]
+async fn admin_version_reports_package_version() {
+    let server = GearmanServer::with_config(ServerConfig::default());
+    let mut conn = server.connect_in_memory();
+    conn.write_all(b"version\n").await.unwrap();
+    let expected = format!("OK rustygeard {}\n", env!("CARGO_PKG_VERSION"));
+    let mut buf = vec![0u8; expected.len()];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(expected.as_bytes(), &buf[..]);
+}
+
+#[tokio::test]
+async fn admin_drain_reports_queued_jobs() {
+    let server = GearmanServer::with_config(ServerConfig::default());
+    let mut conn = server.connect_in_memory();
+    conn.write_all(b"drain\n").await.unwrap();
+    let mut buf = [0u8; 4];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(b"OK 0", &buf);
+}
+
+#[test]
+fn rebind_after_stop_with_reuseaddr() {
+    for _ in 0..2 {
+        let config = ServerConfig {
+            listen: "127.0.0.1:47326".parse().unwrap(),
+            reuseaddr: true,
+            ..ServerConfig::default()
+        };
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let server = thread::spawn(move || GearmanServer::with_config(config).serve(stop_rx));
+        let conn = loop {
+            match TcpStream::connect("127.0.0.1:47326") {
+                Ok(conn) => break conn,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        stop_tx.send(()).unwrap();
+        server.join().unwrap();
+        drop(conn);
+    }
+}
+
+#[tokio::test]
+async fn admin_ping_pongs() {
+    let server = GearmanServer::with_config(ServerConfig::default());
+    let mut conn = server.connect_in_memory();
+    conn.write_all(b"ping\n").await.unwrap();
+    let mut buf = [0u8; 5];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(b"PONG\n", &buf);
+}
+
+#[tokio::test]
+async fn admin_reports_pid_and_server_id() {
+    let config = ServerConfig {
+        handles: Some(Arc::new(CounterHandles::with_host("gear1"))),
+        ..ServerConfig::default()
+    };
+    let server = GearmanServer::with_config(config);
+    let mut conn = BufReader::new(server.connect_in_memory());
+    conn.get_mut().write_all(b"getpid\nid\n").await.unwrap();
+    let mut line = String::new();
+    conn.read_line(&mut line).await.unwrap();
+    assert_eq!(format!("OK {}\n", std::process::id()), line);
+    line.clear();
+    conn.read_line(&mut line).await.unwrap();
+    assert_eq!("OK gear1\n", line);
+}
+
+#[tokio::test]
+async fn admin_killidle_closes_only_idle_workers() {
+    let server = GearmanServer::with_config(ServerConfig::default());
+    let mut idle = PacketCodec::new().framed(server.connect_in_memory());
+    idle.send(Packet::from_fields(CAN_DO, &[b"f"])).await.unwrap();
+    let mut busy = PacketCodec::new().framed(server.connect_in_memory());
+    busy.send(Packet::from_fields(CAN_DO, &[b"f"])).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    busy.send(Packet::from_fields(ECHO_REQ, &[b"hi"])).await.unwrap();
+    assert_eq!(ECHO_RES, busy.next().await.unwrap().unwrap().ptype);
+
+    let mut admin = BufReader::new(server.connect_in_memory());
+    admin.get_mut().write_all(b"killidle 1\n").await.unwrap();
+    let mut line = String::new();
+    admin.read_line(&mut line).await.unwrap();
+    assert_eq!("OK 1\n", line);
+
+    let closed = timeout(Duration::from_secs(5), idle.next())
+        .await
+        .expect("idle worker was not disconnected");
+    assert!(closed.is_none());
+    busy.send(Packet::from_fields(ECHO_REQ, &[b"hi"])).await.unwrap();
+    assert_eq!(ECHO_RES, busy.next().await.unwrap().unwrap().ptype);
+}
+
+#[tokio::test]
+async fn connections_beyond_limit_are_refused() {
+    let config = ServerConfig {
+        max_connections: Some(2),
+        ..ServerConfig::default()
+    };
+    let server = GearmanServer::with_config(config);
+    let _held = [
+        server.connect_in_memory(),
+        server.connect_in_memory(),
+    ];
+    let mut refused = server.connect_in_memory();
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), refused.read_to_end(&mut response))
+        .await
+        .expect("refused connection was not closed")
+        .unwrap();
+    assert_eq!(&b"\0RES"[..], &response[..4]);
+    assert!(response[12..].starts_with(b"too_many_connections\0"));
+}
+
+#[test]
+fn handle_shuts_down_server() {
+    let config = ServerConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        ..ServerConfig::default()
+    };
+    let handle = GearmanServer::with_config(config).start().unwrap();
+    let addr = handle.local_addr();
+    TcpStream::connect(addr).unwrap();
+    handle.shutdown_graceful();
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[tokio::test]
+async fn graceful_shutdown_refuses_connections_but_finishes_jobs() {
+    let config = ServerConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        ..ServerConfig::default()
+    };
+    let handle = GearmanServer::with_config(config).start().unwrap();
+    let addr = handle.local_addr();
+    let connect = || async {
+        PacketCodec::new().framed(tokio::net::TcpStream::connect(addr).await.unwrap())
+    };
+    let mut worker = connect().await;
+    worker.send(Packet::from_fields(CAN_DO, &[b"f"])).await.unwrap();
+    let mut client = connect().await;
+    client.send(Packet::from_fields(SUBMIT_JOB, &[b"f", b"u", b"x"])).await.unwrap();
+    assert_eq!(JOB_CREATED, client.next().await.unwrap().unwrap().ptype);
+    worker.send(Packet::from_fields(GRAB_JOB, &[])).await.unwrap();
+    let assign = worker.next().await.unwrap().unwrap();
+    assert_eq!(JOB_ASSIGN, assign.ptype);
+
+    let mut progress = handle.shutdown_progress();
+    let stopping = tokio::task::spawn_blocking(move || handle.shutdown_graceful());
+    while progress.borrow().is_none() {
+        progress.changed().await.unwrap();
+    }
+    assert_eq!(Some(1), *progress.borrow());
+    assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+
+    let handle_bytes = assign.fields()[0].to_vec();
+    worker
+        .send(Packet::from_fields(WORK_COMPLETE, &[&handle_bytes, b"done"]))
+        .await
+        .unwrap();
+    let complete = client.next().await.unwrap().unwrap();
+    assert_eq!(WORK_COMPLETE, complete.ptype);
+    timeout(Duration::from_secs(5), stopping)
+        .await
+        .expect("shutdown did not finish")
+        .unwrap();
+}
+
+#[test]
+fn start_reports_port_picked_by_os() {
+    let config = ServerConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        ..ServerConfig::default()
+    };
+    let handle = GearmanServer::with_config(config).start().unwrap();
+    let addr = handle.local_addr();
+    assert_ne!(0, addr.port());
+    let mut admin = TcpStream::connect(addr).unwrap();
+    admin.write_all(b"version\n").unwrap();
+    let mut buf = [0u8; 64];
+    let n = admin.read(&mut buf).unwrap();
+    assert!(buf[..n].starts_with(b"OK "), "{:?}", &buf[..n]);
+    handle.shutdown();
+}
+
+#[test]
+fn start_fails_when_address_is_taken() {
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = ServerConfig {
+        listen: taken.local_addr().unwrap(),
+        reuseaddr: false,
+        ..ServerConfig::default()
+    };
+    assert!(GearmanServer::with_config(config).start().is_err());
+}
+
+#[test]
+fn workers_listing_shows_peer_address() {
+    let config = ServerConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        ..ServerConfig::default()
+    };
+    let handle = GearmanServer::with_config(config).start().unwrap();
+    let mut worker = TcpStream::connect(handle.local_addr()).unwrap();
+    let local = worker.local_addr().unwrap();
+    worker.write_all(b"\0REQ\0\0\0\x01\0\0\0\x01f").unwrap();
+    let mut admin = TcpStream::connect(handle.local_addr()).unwrap();
+    let expected = format!(" {} - : f\n", local);
+    let mut listing = String::new();
+    for _ in 0..100 {
+        admin.write_all(b"workers\n").unwrap();
+        let mut buf = [0u8; 1024];
+        let n = admin.read(&mut buf).unwrap();
+        listing = String::from_utf8_lossy(&buf[..n]).to_string();
+        if listing.contains(&expected) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(listing.contains(&expected), "{:?}", listing);
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn long_admin_line_is_refused() {
+    let config = ServerConfig {
+        max_admin_line: 64,
+        ..ServerConfig::default()
+    };
+    let server = GearmanServer::with_config(config);
+    let mut conn = server.connect_in_memory();
+    conn.write_all(&[b'x'; 128]).await.unwrap();
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), conn.read_to_end(&mut response))
+        .await
+        .expect("connection was not closed")
+        .unwrap();
+    assert!(response.ends_with(b"bad_request\0admin line exceeds 64 bytes"));
+}
+
+#[tokio::test]
+async fn threads_share_queues() {
+    let config = ServerConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        threads: Some(4),
+        ..ServerConfig::default()
+    };
+    let handle = GearmanServer::with_config(config).start().unwrap();
+    let addr = handle.local_addr();
+    let connect = || async {
+        PacketCodec::new().framed(tokio::net::TcpStream::connect(addr).await.unwrap())
+    };
+    let mut clients = Vec::new();
+    for i in 0..8 {
+        let mut client = connect().await;
+        let submit = Packet::from_fields(SUBMIT_JOB_BG, &[b"f", format!("u{}", i).as_bytes(), b"x"]);
+        client.send(submit).await.unwrap();
+        assert_eq!(JOB_CREATED, client.next().await.unwrap().unwrap().ptype);
+        clients.push(client);
+    }
+    let mut worker = connect().await;
+    worker.send(Packet::from_fields(CAN_DO, &[b"f"])).await.unwrap();
+    for _ in 0..8 {
+        worker.send(Packet::from_fields(GRAB_JOB, &[])).await.unwrap();
+        assert_eq!(JOB_ASSIGN, worker.next().await.unwrap().unwrap().ptype);
+    }
+    tokio::task::spawn_blocking(move || handle.shutdown()).await.unwrap();
+}
+
+#[tokio::test]
+async fn client_closing_mid_send_leaves_server_up() {
+    let config = ServerConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        ..ServerConfig::default()
+    };
+    let handle = GearmanServer::with_config(config).start().unwrap();
+    let addr = handle.local_addr();
+    let connect = || async {
+        PacketCodec::new().framed(tokio::net::TcpStream::connect(addr).await.unwrap())
+    };
+    let mut client = connect().await;
+    client.send(Packet::from_fields(SUBMIT_JOB, &[b"f", b"u", b"x"])).await.unwrap();
+    let job_handle = client.next().await.unwrap().unwrap().data;
+    let mut worker = connect().await;
+    worker.send(Packet::from_fields(CAN_DO, &[b"f"])).await.unwrap();
+    worker.send(Packet::from_fields(GRAB_JOB, &[])).await.unwrap();
+    assert_eq!(JOB_ASSIGN, worker.next().await.unwrap().unwrap().ptype);
+    // Far more than the socket buffers hold, so the server is still writing
+    // it out when the client goes away without reading
+    let result = vec![b'r'; 16 * 1024 * 1024];
+    worker
+        .send(Packet::from_fields(WORK_COMPLETE, &[&job_handle, &result]))
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(client);
+    worker.send(Packet::from_fields(ECHO_REQ, &[b"still here"])).await.unwrap();
+    let echo = timeout(Duration::from_secs(5), worker.next()).await.unwrap().unwrap().unwrap();
+    assert_eq!(ECHO_RES, echo.ptype);
+    let mut other = connect().await;
+    other.send(Packet::from_fields(ECHO_REQ, &[b"new"])).await.unwrap();
+    assert_eq!(ECHO_RES, other.next().await.unwrap().unwrap().ptype);
+    handle.shutdown();
+}
+
+#[test]
+fn default_config_listens_on_gearman_port() {
+    // What run_default binds to
+    let listen = ServerConfig::default().listen;
+    assert_eq!(4730, DEFAULT_PORT);
+    assert_eq!(DEFAULT_PORT, listen.port());
+    assert!(listen.ip().is_unspecified());
+}
+
+#[tokio::test]
+async fn worker_sleeps_and_wakes_for_each_job() {
+    let config = ServerConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        ..ServerConfig::default()
+    };
+    let handle = GearmanServer::with_config(config).start().unwrap();
+    let addr = handle.local_addr().to_string();
+    let worker = Client::new()
+        .add_server(&addr)
+        .connect()
+        .await
+        .unwrap()
+        .can_do("upper", |job| Ok(job.payload().to_ascii_uppercase()))
+        .await
+        .unwrap();
+    tokio::spawn(worker.work());
+    let mut client = Client::new().add_server(&addr).connect().await.unwrap();
+    for payload in ["first", "second"].iter() {
+        // Long enough for GRAB_JOB, NO_JOB and PRE_SLEEP, so only NOOP wakes it
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut job = client.submit("upper", payload.as_bytes()).await.unwrap();
+        let response = timeout(Duration::from_secs(5), job.response()).await.unwrap().unwrap();
+        match response {
+            WorkUpdate::Complete { payload: result, .. } => {
+                assert_eq!(payload.to_ascii_uppercase().as_bytes(), &result[..])
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn background_batch_handles_come_back_in_order() {
+    let config = ServerConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        ..ServerConfig::default()
+    };
+    let handle = GearmanServer::with_config(config).start().unwrap();
+    let addr = handle.local_addr().to_string();
+    let mut client = Client::new().add_server(&addr).connect().await.unwrap();
+    let payloads: Vec<String> = (0..1000).map(|i| format!("job{}", i)).collect();
+    let jobs: Vec<(&str, &[u8])> = payloads.iter().map(|p| ("batched", p.as_bytes())).collect();
+    let handles = timeout(Duration::from_secs(10), client.submit_background_batch(&jobs))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(1000, handles.len());
+    for (i, handle) in handles.iter().enumerate() {
+        assert_eq!(format!("H:{:010}", i), handle.to_string());
+    }
+    // Single submissions are matched as before once the batch is done
+    let single = client.submit_background("batched", b"after").await.unwrap();
+    assert_eq!("H:0000001000", single.handle().to_string());
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn status_poller_asks_the_server_the_job_went_to() {
+    let start = || {
+        let config = ServerConfig {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            ..ServerConfig::default()
+        };
+        GearmanServer::with_config(config).start().unwrap()
+    };
+    let servers = [start(), start()];
+    let mut client = Client::new();
+    for server in servers.iter() {
+        client = client.add_server(&server.local_addr().to_string());
+    }
+    let mut client = client.connect().await.unwrap();
+    let (handle, poller) = client.submit_background_with_status("f", b"x").await.unwrap();
+
+    let mut assigned = None;
+    for server in servers.iter() {
+        let mut worker =
+            PacketCodec::new().framed(tokio::net::TcpStream::connect(server.local_addr()).await.unwrap());
+        worker.send(Packet::from_fields(CAN_DO, &[b"f"])).await.unwrap();
+        worker.send(Packet::from_fields(GRAB_JOB, &[])).await.unwrap();
+        if worker.next().await.unwrap().unwrap().ptype == JOB_ASSIGN {
+            assigned = Some(worker);
+        }
+    }
+    let mut worker = assigned.expect("no server assigned the job");
+    worker
+        .send(Packet::from_fields(WORK_STATUS, &[handle.as_bytes(), b"1", b"4"]))
+        .await
+        .unwrap();
+    let mut status = poller.status().await.unwrap();
+    for _ in 0..100 {
+        if status.numerator() == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        status = poller.status().await.unwrap();
+    }
+    assert_eq!(&handle, status.handle());
+    assert!(status.known());
+    assert!(status.running());
+    assert_eq!((1, 4), (status.numerator(), status.denominator()));
+    for server in servers {
+        tokio::task::spawn_blocking(move || server.shutdown()).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn forgotten_job_resolves_as_cancelled() {
+    let config = ServerConfig {
+        listen: "127.0.0.1:0".parse().unwrap(),
+        ..ServerConfig::default()
+    };
+    let handle = GearmanServer::with_config(config).start().unwrap();
+    let addr = handle.local_addr().to_string();
+    let mut client = Client::new().add_server(&addr).connect().await.unwrap();
+    // Nobody can do these, so they stay queued
+    let mut kept = client.submit("nobody", b"kept").await.unwrap();
+    let mut dropped = client.submit("nobody", b"dropped").await.unwrap();
+    assert!(!client.forget(kept.handle(), false).await.unwrap());
+    assert!(client.forget(dropped.handle(), true).await.unwrap());
+    for job in [&mut kept, &mut dropped].iter_mut() {
+        match timeout(Duration::from_secs(5), job.response()).await.unwrap().unwrap() {
+            WorkUpdate::Cancelled(cancelled) => assert_eq!(job.handle(), &cancelled),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    // Only the job forgotten with cancel left the server
+    assert!(!client.forget(dropped.handle(), true).await.unwrap());
+    assert!(client.forget(kept.handle(), true).await.unwrap());
+    handle.shutdown();
+}
@@ -0,0 +1,883 @@
+extern crate bytes;
+extern crate rustygear;
+extern crate rustygeard;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::AtomicUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::time::timeout;
+use tower_service::Service;
+
+use rustygear::codec::Packet;
+use rustygear::constants::*;
+use rustygear::job::JobHandle;
+use rustygear::util::{decode_metadata, encode_metadata, new_req};
+
+use rustygeard::admin::{admin_command_show_jobs, admin_command_workers};
+use rustygeard::auth::StaticTokens;
+use rustygeard::handles::CounterHandles;
+use rustygeard::queues::{HandleJobStorage, SharedJobStorage};
+use rustygeard::ratelimit::TokenBucket;
+use rustygeard::replication::{ReplicationEvent, Replicator};
+use rustygeard::scheduler::Scripted;
+use rustygeard::service::{
+    ConnectionGuard, GearmanService, JobWaiters, SendersByConnId, WorkersByConnId,
+};
+use rustygeard::worker::{SharedWorkers, Wake};
+
+/// State shared by all connections, as the server would hold it
+struct Shared {
+    queues: SharedJobStorage,
+    workers: SharedWorkers,
+    job_count: Arc<AtomicUsize>,
+    senders_by_conn_id: SendersByConnId,
+    workers_by_conn_id: WorkersByConnId,
+    job_waiters: JobWaiters,
+}
+
+impl Shared {
+    fn new() -> Shared {
+        Shared {
+            queues: SharedJobStorage::new_job_storage(),
+            workers: SharedWorkers::new_workers(),
+            job_count: Arc::new(AtomicUsize::new(0)),
+            senders_by_conn_id: Arc::new(Mutex::new(HashMap::new())),
+            workers_by_conn_id: Arc::new(Mutex::new(BTreeMap::new())),
+            job_waiters: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns a service for `conn_id` and the receiver for packets sent to it by other connections
+    fn connect(&self, conn_id: usize) -> (GearmanService, Receiver<Packet>) {
+        let (tx, rx) = channel(16);
+        self.senders_by_conn_id.lock().unwrap().insert(conn_id, tx);
+        let service = GearmanService::new(
+            conn_id,
+            self.queues.clone(),
+            self.workers.clone(),
+            self.job_count.clone(),
+            self.senders_by_conn_id.clone(),
+            self.workers_by_conn_id.clone(),
+            self.job_waiters.clone(),
+            "127.0.0.1:37337".parse().unwrap(),
+        );
+        (service, rx)
+    }
+}
+
+async fn send(service: &mut GearmanService, ptype: u32, data: &[u8]) -> Packet {
+    service
+        .call(new_req(ptype, Bytes::copy_from_slice(data)))
+        .await
+        .unwrap()
+}
+
+async fn send_fields(service: &mut GearmanService, ptype: u32, fields: &[&[u8]]) -> Packet {
+    service.call(Packet::from_fields(ptype, fields)).await.unwrap()
+}
+
+#[tokio::test]
+async fn submit_respects_max_data_size() {
+    let shared = Shared::new();
+    shared
+        .queues
+        .set_max_data_size(Bytes::from("thumb"), Some(4));
+    let (mut service, _rx) = shared.connect(1);
+    let under = send(&mut service, SUBMIT_JOB_BG, b"thumb\0u1\0abcd").await;
+    assert_eq!(JOB_CREATED, under.ptype);
+    let over = send(&mut service, SUBMIT_JOB_BG, b"thumb\0u2\0abcde").await;
+    assert_eq!(ERROR, over.ptype);
+    assert!(over.data.starts_with(b"data_too_large\0"));
+    let unlimited = send(&mut service, SUBMIT_JOB_BG, b"other\0u3\0abcde").await;
+    assert_eq!(JOB_CREATED, unlimited.ptype);
+    let storage = shared.queues.shard(&Bytes::from("thumb"));
+    assert_eq!(1, storage.queues()[&Bytes::from("thumb")][PRIORITY_NORMAL].len());
+}
+
+#[tokio::test]
+async fn submit_to_function_without_workers_is_rejected_when_configured() {
+    let shared = Shared::new();
+    let (mut client, _client_rx) = shared.connect(1);
+    client.reject_unknown_functions = true;
+    let rejected = send(&mut client, SUBMIT_JOB_BG, b"f\0u\0x").await;
+    assert_eq!(ERROR, rejected.ptype);
+    assert!(rejected.data.starts_with(b"no_workers\0"));
+    assert!(shared.queues.functions_with_queued().is_empty());
+    let (mut worker, _worker_rx) = shared.connect(2);
+    send(&mut worker, CAN_DO, b"f").await;
+    assert_eq!(JOB_CREATED, send(&mut client, SUBMIT_JOB_BG, b"f\0u\0x").await.ptype);
+}
+
+#[tokio::test]
+async fn unauthenticated_connection_is_refused_until_auth() {
+    let shared = Shared::new();
+    let (mut client, _client_rx) = shared.connect(1);
+    client.authenticator = Some(Arc::new(StaticTokens::new(vec!["secret"])));
+    let refused = send(&mut client, SUBMIT_JOB_BG, b"f\0u\0x").await;
+    assert_eq!(ERROR, refused.ptype);
+    assert!(refused.data.starts_with(b"auth_required\0"));
+    let wrong = send(&mut client, OPTION_REQ, b"auth=guess").await;
+    assert!(wrong.data.starts_with(b"auth_failed\0"));
+    let accepted = send(&mut client, OPTION_REQ, b"auth=secret").await;
+    assert_eq!(OPTION_RES, accepted.ptype);
+    assert_eq!(&b"auth"[..], &accepted.data[..]);
+    assert_eq!(JOB_CREATED, send(&mut client, SUBMIT_JOB_BG, b"f\0u\0x").await.ptype);
+}
+
+#[tokio::test]
+async fn work_fail_retries_until_max() {
+    let shared = Shared::new();
+    shared
+        .queues
+        .set_max_retries(Bytes::from("f"), 2);
+    let (mut client, mut client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    send(&mut worker, CAN_DO, b"f").await;
+    let created = send(&mut client, SUBMIT_JOB, b"f\0u\0x").await;
+    let handle = created.data;
+    for _ in 0..3 {
+        let assigned = send(&mut worker, GRAB_JOB, b"").await;
+        assert_eq!(JOB_ASSIGN, assigned.ptype);
+        send(&mut worker, WORK_FAIL, &handle).await;
+    }
+    assert_eq!(NO_JOB, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    let failed = client_rx.recv().await.unwrap();
+    assert_eq!(WORK_FAIL, failed.ptype);
+    assert_eq!(handle, failed.data);
+}
+
+#[tokio::test]
+async fn rejected_submit_is_not_acknowledged() {
+    let shared = Shared::new();
+    shared
+        .queues
+        .set_max_data_size(Bytes::from("f"), Some(0));
+    let (mut client, _rx) = shared.connect(1);
+    let rejected = send(&mut client, SUBMIT_JOB, b"f\0u\0x").await;
+    assert_eq!(ERROR, rejected.ptype);
+    assert!(shared.job_waiters.lock().unwrap().is_empty());
+    assert!(!shared.queues.shard(&Bytes::from("f")).queues().contains_key(&Bytes::from("f")));
+}
+
+#[tokio::test]
+async fn reduce_job_concatenates_map_output() {
+    let shared = Shared::new();
+    let (mut client, mut client_rx) = shared.connect(1);
+    let (mut mapper, _mapper_rx) = shared.connect(2);
+    let (mut reducer, _reducer_rx) = shared.connect(3);
+    send(&mut mapper, CAN_DO, b"map").await;
+    send(&mut reducer, CAN_DO, b"concat").await;
+    let created = send(&mut client, SUBMIT_REDUCE_JOB, b"map\0u\0concat\0input").await;
+    assert_eq!(JOB_CREATED, created.ptype);
+    let handle = created.data;
+
+    assert_eq!(JOB_ASSIGN, send(&mut mapper, GRAB_JOB, b"").await.ptype);
+    send_fields(&mut mapper, WORK_DATA, &[&handle, b"ab"]).await;
+    send_fields(&mut mapper, WORK_COMPLETE, &[&handle, b"cd"]).await;
+
+    let assigned = send(&mut reducer, GRAB_JOB, b"").await;
+    assert_eq!(JOB_ASSIGN, assigned.ptype);
+    let expected = [&handle[..], b"\0concat\0ab\0cd"].concat();
+    assert_eq!(&expected[..], &assigned.data[..]);
+    let parts: Vec<&[u8]> = assigned.data.split(|b| *b == b'\0').skip(2).collect();
+    send_fields(&mut reducer, WORK_COMPLETE, &[&handle, &parts.concat()]).await;
+
+    let complete = client_rx.recv().await.unwrap();
+    assert_eq!(WORK_COMPLETE, complete.ptype);
+    assert_eq!(&[&handle[..], b"\0abcd"].concat()[..], &complete.data[..]);
+}
+
+#[tokio::test]
+async fn submit_rate_limited() {
+    let shared = Shared::new();
+    let (mut client, _rx) = shared.connect(1);
+    client.submit_limit = Some(TokenBucket::new(3));
+    for unique in &[b"u1", b"u2", b"u3"] {
+        let created = send_fields(&mut client, SUBMIT_JOB_BG, &[b"f", *unique, b"x"]).await;
+        assert_eq!(JOB_CREATED, created.ptype);
+    }
+    let limited = send(&mut client, SUBMIT_JOB_BG, b"f\0u4\0x").await;
+    assert_eq!(ERROR, limited.ptype);
+    assert!(limited.data.starts_with(b"rate_limited\0"));
+}
+
+#[tokio::test]
+async fn submit_uses_configured_handle_generator() {
+    let shared = Shared::new();
+    let (mut client, _rx) = shared.connect(1);
+    let created = send(&mut client, SUBMIT_JOB_BG, b"f\0u1\0x").await;
+    assert_eq!(&b"H:0000000000"[..], &created.data[..]);
+    client.handles = Arc::new(CounterHandles::with_host("gear1"));
+    let created = send(&mut client, SUBMIT_JOB_BG, b"f\0u2\0x").await;
+    assert_eq!(&b"H:gear1:0000000000"[..], &created.data[..]);
+}
+
+#[tokio::test]
+async fn connection_cleaned_up_after_panic() {
+    let shared = Shared::new();
+    shared
+        .queues
+        .set_max_retries(Bytes::from("f"), 1);
+    let (mut client, _client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    shared
+        .workers_by_conn_id
+        .lock()
+        .unwrap()
+        .insert(2, worker.worker.clone());
+    send(&mut worker, CAN_DO, b"f").await;
+    send(&mut client, SUBMIT_JOB, b"f\0u\0x").await;
+    assert_eq!(JOB_ASSIGN, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    let guard = ConnectionGuard::new(
+        2,
+        shared.senders_by_conn_id.clone(),
+        shared.workers_by_conn_id.clone(),
+    );
+    let conn = tokio::spawn(async move {
+        let _guard = guard;
+        let _worker = worker;
+        panic!("connection blew up");
+    });
+    assert!(conn.await.is_err());
+    assert!(!shared.senders_by_conn_id.lock().unwrap().contains_key(&2));
+    assert!(shared.workers_by_conn_id.lock().unwrap().is_empty());
+    assert_eq!((0, 0), shared.workers.clone().count_workers(&Bytes::from("f")));
+    let (mut other, _other_rx) = shared.connect(3);
+    send(&mut other, CAN_DO, b"f").await;
+    assert_eq!(JOB_ASSIGN, send(&mut other, GRAB_JOB, b"").await.ptype);
+}
+
+#[tokio::test]
+async fn drain_refuses_submits_but_serves_workers() {
+    let shared = Shared::new();
+    let (mut client, _client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    send(&mut worker, CAN_DO, b"f").await;
+    send(&mut client, SUBMIT_JOB_BG, b"f\0u1\0x").await;
+    send(&mut client, SUBMIT_JOB_BG, b"f\0u2\0x").await;
+    shared.queues.set_draining(true);
+    let refused = send(&mut client, SUBMIT_JOB_BG, b"f\0u3\0x").await;
+    assert_eq!(ERROR, refused.ptype);
+    assert!(refused.data.starts_with(b"draining\0"));
+    assert_eq!(2, shared.queues.queued_count());
+    assert_eq!(JOB_ASSIGN, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    assert_eq!(JOB_ASSIGN, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    assert_eq!(NO_JOB, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    assert_eq!(0, shared.queues.queued_count());
+}
+
+#[tokio::test]
+async fn reserved_ptype_is_rejected() {
+    let shared = Shared::new();
+    let (mut client, _rx) = shared.connect(1);
+    for ptype in &[0, 5] {
+        let rejected = send(&mut client, *ptype, b"").await;
+        assert_eq!(ERROR, rejected.ptype);
+        assert!(rejected.data.starts_with(b"invalid_packet_type\0"));
+    }
+}
+
+#[tokio::test]
+async fn unsupported_compression_falls_back_to_raw() {
+    let shared = Shared::new();
+    let (mut client, _rx) = shared.connect(1);
+    let refused = send(&mut client, OPTION_REQ, b"compress=gzip").await;
+    assert_eq!(ERROR, refused.ptype);
+    assert!(refused.data.starts_with(b"unknown_option\0"));
+    let accepted = send(&mut client, OPTION_REQ, b"exceptions").await;
+    assert_eq!(OPTION_RES, accepted.ptype);
+    let payload = vec![b'a'; 4096];
+    let created = send_fields(&mut client, SUBMIT_JOB_BG, &[b"f", b"u", &payload]).await;
+    assert_eq!(JOB_CREATED, created.ptype);
+    let storage = shared.queues.shard(&Bytes::from("f"));
+    let job = storage.queues()[&Bytes::from("f")][PRIORITY_NORMAL][0].upgrade().unwrap();
+    assert_eq!(&payload[..], &job.data[..]);
+}
+
+#[tokio::test]
+async fn binary_function_names_match() {
+    let shared = Shared::new();
+    let (mut client, _client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    send(&mut worker, SET_CLIENT_ID, b"\xffid").await;
+    send(&mut worker, CAN_DO, b"\xfff").await;
+    send(&mut client, SUBMIT_JOB_BG, b"f\0u1\0x").await;
+    send(&mut client, SUBMIT_JOB_BG, b"\xfff\0u2\0y").await;
+    let assigned = send(&mut worker, GRAB_JOB, b"").await;
+    assert_eq!(JOB_ASSIGN, assigned.ptype);
+    assert!(assigned.data.ends_with(b"\0\xfff\0y"));
+    assert_eq!(NO_JOB, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    shared
+        .workers_by_conn_id
+        .lock()
+        .unwrap()
+        .insert(2, worker.worker.clone());
+    let workers = admin_command_workers(shared.workers_by_conn_id.clone());
+    assert!(workers.data.ends_with(b" \xffid : \xfff\n.\n"));
+}
+
+#[tokio::test]
+async fn disconnect_cancels_job_only_it_waited_on() {
+    let shared = Shared::new();
+    let (mut alone, _alone_rx) = shared.connect(1);
+    let (mut first, _first_rx) = shared.connect(2);
+    let (mut second, _second_rx) = shared.connect(3);
+    send(&mut alone, SUBMIT_JOB, b"f\0u1\0x").await;
+    send(&mut first, SUBMIT_JOB, b"f\0u2\0x").await;
+    let shared_handle = JobHandle::from(send(&mut second, SUBMIT_JOB, b"f\0u2\0x").await.data);
+    assert_eq!(2, shared.queues.queued_count());
+    drop(alone);
+    drop(first);
+    assert_eq!(1, shared.queues.queued_count());
+    let job_waiters = shared.job_waiters.lock().unwrap();
+    assert_eq!(1, job_waiters.len());
+    assert_eq!(vec![3], job_waiters[&shared_handle]);
+}
+
+#[tokio::test]
+async fn dependent_job_waits_for_its_dependency() {
+    let shared = Shared::new();
+    let (mut client, mut client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    send(&mut worker, CAN_DO, b"f").await;
+    let a = send(&mut client, SUBMIT_JOB, b"f\0a\0first").await.data;
+    let option = send(&mut client, OPTION_REQ, b"depends_on=a").await;
+    assert_eq!(OPTION_RES, option.ptype);
+    let b = send(&mut client, SUBMIT_JOB, b"f\0b\0second").await.data;
+    let assigned = send(&mut worker, GRAB_JOB, b"").await;
+    assert!(assigned.data.starts_with(&a));
+    assert_eq!(NO_JOB, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    send_fields(&mut worker, WORK_COMPLETE, &[&a, b"done"]).await;
+    let assigned = send(&mut worker, GRAB_JOB, b"").await;
+    assert_eq!(JOB_ASSIGN, assigned.ptype);
+    assert!(assigned.data.starts_with(&b));
+    assert_eq!(WORK_COMPLETE, client_rx.recv().await.unwrap().ptype);
+}
+
+#[tokio::test]
+async fn dependent_job_fails_with_its_dependency() {
+    let shared = Shared::new();
+    let (mut client, mut client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    send(&mut worker, CAN_DO, b"f").await;
+    let a = send(&mut client, SUBMIT_JOB, b"f\0a\0first").await.data;
+    send(&mut client, OPTION_REQ, b"depends_on=a").await;
+    let b = send(&mut client, SUBMIT_JOB, b"f\0b\0second").await.data;
+    send(&mut worker, GRAB_JOB, b"").await;
+    send(&mut worker, WORK_FAIL, &a).await;
+    let mut failed = vec![
+        client_rx.recv().await.unwrap().data,
+        client_rx.recv().await.unwrap().data,
+    ];
+    failed.sort();
+    assert_eq!(vec![a, b], failed);
+    assert_eq!(NO_JOB, send(&mut worker, GRAB_JOB, b"").await.ptype);
+}
+
+#[tokio::test]
+async fn grab_variants_assign_or_say_no_job() {
+    let shared = Shared::new();
+    let (mut client, _client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    send(&mut worker, CAN_DO, b"f").await;
+    for grab in &[GRAB_JOB, GRAB_JOB_UNIQ, GRAB_JOB_ALL] {
+        assert_eq!(NO_JOB, send(&mut worker, *grab, b"").await.ptype);
+    }
+    let handle = send(&mut client, SUBMIT_REDUCE_JOB_BACKGROUND, b"f\0u\0r\0x").await.data;
+    let assigned = send(&mut worker, GRAB_JOB_ALL, b"").await;
+    assert_eq!(JOB_ASSIGN_ALL, assigned.ptype);
+    assert_eq!(&[&handle[..], b"\0f\0u\0r\0x"].concat()[..], &assigned.data[..]);
+}
+
+#[tokio::test]
+async fn grab_all_worker_completes_job() {
+    let shared = Shared::new();
+    let (mut client, mut client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    send(&mut worker, CAN_DO, b"f").await;
+    let handle = send(&mut client, SUBMIT_JOB, b"f\0u\0x").await.data;
+    let assigned = send(&mut worker, GRAB_JOB_ALL, b"").await;
+    assert_eq!(JOB_ASSIGN_ALL, assigned.ptype);
+    let fields: Vec<&[u8]> = assigned.data.splitn(5, |b| *b == b'\0').collect();
+    assert_eq!(vec![&handle[..], b"f", b"u", b"", b"x"], fields);
+    send_fields(&mut worker, WORK_COMPLETE, &[&handle, b"done"]).await;
+    let complete = client_rx.recv().await.unwrap();
+    assert_eq!(WORK_COMPLETE, complete.ptype);
+    assert_eq!(&[&handle[..], b"\0done"].concat()[..], &complete.data[..]);
+}
+
+#[tokio::test]
+async fn work_status_is_clamped_and_zero_denominator_dropped() {
+    let shared = Shared::new();
+    let (mut client, mut client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    send(&mut worker, CAN_DO, b"f").await;
+    let handle = send(&mut client, SUBMIT_JOB, b"f\0u\0x").await.data;
+    assert_eq!(JOB_ASSIGN, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    send_fields(&mut worker, WORK_STATUS, &[&handle, b"7", b"5"]).await;
+    let status = client_rx.recv().await.unwrap();
+    assert_eq!(WORK_STATUS, status.ptype);
+    assert_eq!(vec![&handle[..], b"5", b"5"], status.fields());
+    send_fields(&mut worker, WORK_STATUS, &[&handle, b"3", b"0"]).await;
+    assert!(timeout(Duration::from_millis(50), client_rx.recv()).await.is_err());
+    let res = send(&mut client, GET_STATUS, &handle).await;
+    assert_eq!(STATUS_RES, res.ptype);
+    // Running on the worker's connection, not the one asking
+    assert_eq!(vec![&handle[..], b"1", b"1", b"5", b"5"], res.fields());
+}
+
+#[tokio::test]
+async fn admin_cancel_job() {
+    let shared = Shared::new();
+    let (mut client, mut client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    let (mut admin, _admin_rx) = shared.connect(3);
+    send(&mut worker, CAN_DO, b"f").await;
+    let handle = send(&mut client, SUBMIT_JOB, b"f\0u\0x").await.data;
+    let cancelled = send(&mut admin, ADMIN_CANCEL_JOB, &handle).await;
+    assert_eq!(&b"OK\n"[..], &cancelled.data[..]);
+    let failed = client_rx.recv().await.unwrap();
+    assert_eq!(WORK_FAIL, failed.ptype);
+    assert_eq!(handle, failed.data);
+    assert_eq!(NO_JOB, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    let missing = send(&mut admin, ADMIN_CANCEL_JOB, &handle).await;
+    assert!(missing.data.starts_with(b"ERR not_found "));
+}
+
+#[tokio::test]
+async fn admin_drop_function_leaves_other_functions() {
+    let shared = Shared::new();
+    let (mut client, mut client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    let (mut admin, _admin_rx) = shared.connect(3);
+    let handle = send(&mut client, SUBMIT_JOB, b"foo\0u1\0x").await.data;
+    send(&mut client, SUBMIT_JOB_BG, b"foo\0u2\0x").await;
+    let kept = send(&mut client, SUBMIT_JOB_BG, b"bar\0u3\0x").await.data;
+    let dropped = send(&mut admin, ADMIN_DROP_FUNCTION, b"foo").await;
+    assert_eq!(&b"OK 2\n"[..], &dropped.data[..]);
+    let failed = client_rx.recv().await.unwrap();
+    assert_eq!(WORK_FAIL, failed.ptype);
+    assert_eq!(handle, failed.data);
+    assert_eq!(vec![b"bar".to_vec()], shared.queues.functions_with_queued());
+    send(&mut worker, CAN_DO, b"foo").await;
+    send(&mut worker, CAN_DO, b"bar").await;
+    let assigned = send(&mut worker, GRAB_JOB, b"").await;
+    assert_eq!(JOB_ASSIGN, assigned.ptype);
+    assert_eq!(&kept[..], assigned.fields()[0]);
+    assert_eq!(NO_JOB, send(&mut worker, GRAB_JOB, b"").await.ptype);
+}
+
+#[tokio::test]
+async fn completions_update_function_stats() {
+    let shared = Shared::new();
+    let (mut client, _client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    send(&mut worker, CAN_DO, b"f").await;
+    for unique in &["u1", "u2"] {
+        let handle = send_fields(&mut client, SUBMIT_JOB_BG, &[b"f", unique.as_bytes(), b"x"])
+            .await
+            .data;
+        assert_eq!(JOB_ASSIGN, send(&mut worker, GRAB_JOB, b"").await.ptype);
+        send_fields(&mut worker, WORK_COMPLETE, &[&handle, b"done"]).await;
+    }
+    let storage = shared.queues.shard(&Bytes::from("f"));
+    let stats = &storage.function_stats()[&Bytes::from("f")];
+    assert_eq!(2, stats.completed);
+    assert_eq!(0, stats.failed);
+    assert!(stats.avg_runtime().is_some());
+}
+
+#[tokio::test]
+async fn background_priority_variants_queue_without_waiters() {
+    let shared = Shared::new();
+    let (mut client, _client_rx) = shared.connect(1);
+    let low = send(&mut client, SUBMIT_JOB_LOW_BG, b"f\0u1\0x").await;
+    assert_eq!(JOB_CREATED, low.ptype);
+    let high = send(&mut client, SUBMIT_JOB_HIGH_BG, b"f\0u2\0x").await;
+    assert_eq!(JOB_CREATED, high.ptype);
+    let storage = shared.queues.shard(&Bytes::from("f"));
+    let queues = &storage.queues()[&Bytes::from("f")];
+    assert_eq!(low.data, queues[PRIORITY_LOW][0].upgrade().unwrap().handle);
+    assert_eq!(high.data, queues[PRIORITY_HIGH][0].upgrade().unwrap().handle);
+    let job_waiters = shared.job_waiters.lock().unwrap();
+    assert!(job_waiters.values().all(|waiters| waiters.is_empty()));
+}
+
+#[tokio::test]
+async fn foreground_priority_variants_queue_with_waiters() {
+    let shared = Shared::new();
+    let (mut client, _client_rx) = shared.connect(1);
+    let variants = [
+        (SUBMIT_JOB_HIGH, PRIORITY_HIGH),
+        (SUBMIT_JOB, PRIORITY_NORMAL),
+        (SUBMIT_JOB_LOW, PRIORITY_LOW),
+    ];
+    for (i, (ptype, priority)) in variants.iter().enumerate() {
+        let unique = format!("u{}", i);
+        let created = send_fields(&mut client, *ptype, &[b"f", unique.as_bytes(), b"x"]).await;
+        assert_eq!(JOB_CREATED, created.ptype);
+        let storage = shared.queues.shard(&Bytes::from("f"));
+        let queued = storage.queues()[&Bytes::from("f")][*priority][0].upgrade().unwrap();
+        assert_eq!(created.data, queued.handle);
+        assert_eq!(vec![1], shared.job_waiters.lock().unwrap()[&JobHandle::from(created.data.clone())]);
+    }
+}
+
+#[tokio::test]
+async fn cant_do_lets_active_job_finish() {
+    let shared = Shared::new();
+    let (mut client, mut client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    send(&mut worker, CAN_DO, b"f").await;
+    let handle = send(&mut client, SUBMIT_JOB, b"f\0u1\0x").await.data;
+    assert_eq!(JOB_ASSIGN, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    send(&mut worker, CANT_DO, b"f").await;
+    assert_eq!((0, 0), shared.workers.clone().count_workers(&Bytes::from("f")));
+    send_fields(&mut worker, WORK_COMPLETE, &[&handle, b"done"]).await;
+    let complete = client_rx.recv().await.unwrap();
+    assert_eq!(WORK_COMPLETE, complete.ptype);
+    assert_eq!(&b"done"[..], complete.fields()[1]);
+    send(&mut client, SUBMIT_JOB_BG, b"f\0u2\0x").await;
+    assert_eq!(NO_JOB, send(&mut worker, GRAB_JOB, b"").await.ptype);
+}
+
+#[tokio::test]
+async fn coalesced_waiters_all_get_updates() {
+    let shared = Shared::new();
+    let mut clients = Vec::new();
+    for conn_id in 1..=3 {
+        let (mut client, rx) = shared.connect(conn_id);
+        let created = send(&mut client, SUBMIT_JOB, b"f\0u\0x").await;
+        clients.push((client, rx, created.data));
+    }
+    // Connection 4 goes away without its service noticing yet
+    let (mut gone, _gone_rx) = shared.connect(4);
+    send(&mut gone, SUBMIT_JOB, b"f\0u\0x").await;
+    shared.senders_by_conn_id.lock().unwrap().remove(&4);
+    let (mut worker, _worker_rx) = shared.connect(5);
+    send(&mut worker, CAN_DO, b"f").await;
+    let assigned = send(&mut worker, GRAB_JOB, b"").await;
+    let handle = assigned.fields()[0].to_vec();
+    send_fields(&mut worker, WORK_DATA, &[&handle, b"partial"]).await;
+    send_fields(&mut worker, WORK_COMPLETE, &[&handle, b"done"]).await;
+    for (_client, rx, created) in clients.iter_mut() {
+        assert_eq!(&handle[..], &created[..]);
+        let data = rx.recv().await.unwrap();
+        assert_eq!(WORK_DATA, data.ptype);
+        assert_eq!(&b"partial"[..], data.fields()[1]);
+        let complete = rx.recv().await.unwrap();
+        assert_eq!(WORK_COMPLETE, complete.ptype);
+        assert_eq!(&b"done"[..], complete.fields()[1]);
+    }
+}
+
+#[tokio::test]
+async fn work_exception_reaches_only_clients_asking_for_it() {
+    let shared = Shared::new();
+    let (mut plain, mut plain_rx) = shared.connect(1);
+    let (mut asking, mut asking_rx) = shared.connect(2);
+    shared
+        .workers_by_conn_id
+        .lock()
+        .unwrap()
+        .insert(2, asking.worker.clone());
+    assert_eq!(OPTION_RES, send(&mut asking, OPTION_REQ, b"exceptions").await.ptype);
+    let handle = send(&mut plain, SUBMIT_JOB, b"f\0u\0x").await.data;
+    send(&mut asking, SUBMIT_JOB, b"f\0u\0x").await;
+    let (mut worker, _worker_rx) = shared.connect(3);
+    send(&mut worker, CAN_DO, b"f").await;
+    assert_eq!(JOB_ASSIGN, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    send_fields(&mut worker, WORK_EXCEPTION, &[&handle, b"boom"]).await;
+    let exception = asking_rx.recv().await.unwrap();
+    assert_eq!(WORK_EXCEPTION, exception.ptype);
+    assert_eq!(vec![&handle[..], b"boom"], exception.fields());
+    let failed = plain_rx.recv().await.unwrap();
+    assert_eq!(WORK_FAIL, failed.ptype);
+    assert_eq!(handle, failed.data);
+    assert_eq!(0, shared.queues.queued_count());
+    assert_eq!(NO_JOB, send(&mut worker, GRAB_JOB, b"").await.ptype);
+}
+
+#[tokio::test]
+async fn queued_job_expires_after_ttl() {
+    let shared = Shared::new();
+    let (mut client, mut client_rx) = shared.connect(1);
+    let bad = send(&mut client, OPTION_REQ, b"ttl=soon").await;
+    assert_eq!(ERROR, bad.ptype);
+    let ok = send(&mut client, OPTION_REQ, b"ttl=1").await;
+    assert_eq!(OPTION_RES, ok.ptype);
+    let created = send(&mut client, SUBMIT_JOB, b"f\0u1\0x").await;
+    assert_eq!(JOB_CREATED, created.ptype);
+    // Only the next submission gets the ttl
+    send(&mut client, SUBMIT_JOB, b"f\0u2\0x").await;
+    let fail = timeout(Duration::from_secs(5), client_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(WORK_FAIL, fail.ptype);
+    assert_eq!(created.data, fail.data);
+    assert_eq!(1, shared.queues.queued_count());
+}
+
+#[tokio::test]
+async fn refused_or_coalesced_submission_uses_up_pending_options() {
+    let shared = Shared::new();
+    let (mut client, _client_rx) = shared.connect(1);
+    let first = send(&mut client, SUBMIT_JOB_BG, b"f\0u1\0x").await.data;
+    send(&mut client, OPTION_REQ, b"ttl=1").await;
+    send(&mut client, OPTION_REQ, b"depends_on=a").await;
+    // Coalesced into the job already queued, which is left as it was
+    assert_eq!(first, send(&mut client, SUBMIT_JOB_BG, b"f\0u1\0x").await.data);
+    send(&mut client, OPTION_REQ, b"ttl=1").await;
+    shared.queues.set_draining(true);
+    assert_eq!(ERROR, send(&mut client, SUBMIT_JOB_BG, b"f\0u2\0x").await.ptype);
+    shared.queues.set_draining(false);
+    let later = JobHandle::from(send(&mut client, SUBMIT_JOB_BG, b"f\0u3\0x").await.data);
+    for handle in [JobHandle::from(first), later].iter() {
+        let job = shared.queues.job_by_handle(handle).unwrap();
+        assert!(job.expires_at.is_none());
+    }
+    assert_eq!(2, shared.queues.queued_count());
+}
+
+#[tokio::test]
+async fn aggregate_option_combines_work_data() {
+    let shared = Shared::new();
+    let (mut streaming, mut streaming_rx) = shared.connect(1);
+    let (mut aggregating, mut aggregating_rx) = shared.connect(2);
+    shared
+        .workers_by_conn_id
+        .lock()
+        .unwrap()
+        .insert(2, aggregating.worker.clone());
+    assert_eq!(OPTION_RES, send(&mut aggregating, OPTION_REQ, b"aggregate").await.ptype);
+    let handle = send(&mut streaming, SUBMIT_JOB, b"f\0u\0x").await.data;
+    send(&mut aggregating, SUBMIT_JOB, b"f\0u\0x").await;
+    let (mut worker, _worker_rx) = shared.connect(3);
+    send(&mut worker, CAN_DO, b"f").await;
+    assert_eq!(JOB_ASSIGN, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    send_fields(&mut worker, WORK_DATA, &[&handle, b"one,"]).await;
+    send_fields(&mut worker, WORK_DATA, &[&handle, b"two,"]).await;
+    send_fields(&mut worker, WORK_COMPLETE, &[&handle, b"three"]).await;
+    for (ptype, payload) in [(WORK_DATA, "one,"), (WORK_DATA, "two,"), (WORK_COMPLETE, "three")].iter() {
+        let packet = streaming_rx.recv().await.unwrap();
+        assert_eq!(*ptype, packet.ptype);
+        assert_eq!(payload.as_bytes(), packet.fields()[1]);
+    }
+    let complete = aggregating_rx.recv().await.unwrap();
+    assert_eq!(WORK_COMPLETE, complete.ptype);
+    assert_eq!(vec![&handle[..], b"one,two,three"], complete.fields());
+    assert!(aggregating_rx.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn metadata_reaches_worker_and_showjobs() {
+    let shared = Shared::new();
+    let (mut client, _client_rx) = shared.connect(1);
+    assert_eq!(OPTION_RES, send(&mut client, OPTION_REQ, b"metadata").await.ptype);
+    let metadata = vec![(Bytes::from("trace_id"), Bytes::from("abc123"))];
+    let mut submit = b"f\0u\0".to_vec();
+    submit.extend(&encode_metadata(&metadata, b"payload"));
+    assert_eq!(JOB_CREATED, send(&mut client, SUBMIT_JOB_BG, &submit).await.ptype);
+
+    let shown = admin_command_show_jobs(shared.queues.clone(), &Bytes::from("f"), 10);
+    let shown = String::from_utf8(shown.data.to_vec()).unwrap();
+    assert_eq!(Some("trace_id=abc123"), shown.lines().next().unwrap().split('\t').nth(4));
+
+    let (mut worker, _worker_rx) = shared.connect(2);
+    assert_eq!(OPTION_RES, send(&mut worker, OPTION_REQ, b"metadata").await.ptype);
+    send(&mut worker, CAN_DO, b"f").await;
+    let assign = send(&mut worker, GRAB_JOB, b"").await;
+    assert_eq!(JOB_ASSIGN, assign.ptype);
+    let (received, data) = decode_metadata(Bytes::copy_from_slice(assign.fields()[2]));
+    assert_eq!(metadata, received);
+    assert_eq!(&b"payload"[..], data);
+}
+
+#[tokio::test]
+async fn client_closing_after_submit_drops_its_job() {
+    let shared = Shared::new();
+    shared
+        .queues
+        .set_max_retries(Bytes::from("f"), 1);
+    let (mut queued, _queued_rx) = shared.connect(1);
+    send(&mut queued, SUBMIT_JOB, b"f\0u1\0x").await;
+    drop(queued);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    shared
+        .workers_by_conn_id
+        .lock()
+        .unwrap()
+        .insert(2, worker.worker.clone());
+    send(&mut worker, CAN_DO, b"f").await;
+    assert_eq!(NO_JOB, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    // Once running, the job is left to finish but not retried or reported
+    let (mut running, _running_rx) = shared.connect(3);
+    let handle = send(&mut running, SUBMIT_JOB, b"f\0u2\0x").await.data;
+    assert_eq!(JOB_ASSIGN, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    drop(running);
+    assert!(shared.job_waiters.lock().unwrap().is_empty());
+    let (mut sleeper, mut sleeper_rx) = shared.connect(4);
+    send(&mut sleeper, CAN_DO, b"f").await;
+    send(&mut sleeper, PRE_SLEEP, b"").await;
+    send(&mut worker, WORK_FAIL, &handle).await;
+    tokio::task::yield_now().await;
+    assert!(sleeper_rx.try_recv().is_err(), "woken for a cancelled job");
+    assert_eq!(NO_JOB, send(&mut worker, GRAB_JOB, b"").await.ptype);
+}
+
+#[tokio::test]
+async fn late_completion_of_cancelled_job_leaves_its_replacement() {
+    let shared = Shared::new();
+    let (mut worker, _worker_rx) = shared.connect(1);
+    send(&mut worker, CAN_DO, b"f").await;
+    let (mut first, _first_rx) = shared.connect(2);
+    let old_handle = send(&mut first, SUBMIT_JOB, b"f\0u\0x").await.data;
+    assert_eq!(JOB_ASSIGN, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    // Abandoned while running, then submitted again under the same unique
+    drop(first);
+    let (mut second, _second_rx) = shared.connect(3);
+    let new_handle = send(&mut second, SUBMIT_JOB, b"f\0u\0x").await.data;
+    assert_ne!(old_handle, new_handle);
+    send_fields(&mut worker, WORK_COMPLETE, &[&old_handle, b"stale"]).await;
+    assert_eq!(1, shared.queues.queued_count());
+    let assign = send(&mut worker, GRAB_JOB, b"").await;
+    assert_eq!(JOB_ASSIGN, assign.ptype);
+    assert!(assign.data.starts_with(&new_handle));
+}
+
+#[tokio::test]
+async fn late_exception_from_cancelled_job_leaves_its_replacement() {
+    let shared = Shared::new();
+    let (mut worker, _worker_rx) = shared.connect(1);
+    send(&mut worker, CAN_DO, b"f").await;
+    let (mut first, _first_rx) = shared.connect(2);
+    let old_handle = send(&mut first, SUBMIT_JOB, b"f\0u\0x").await.data;
+    assert_eq!(JOB_ASSIGN, send(&mut worker, GRAB_JOB, b"").await.ptype);
+    drop(first);
+    let (mut second, _second_rx) = shared.connect(3);
+    let new_handle = send(&mut second, SUBMIT_JOB, b"f\0u\0x").await.data;
+    send_fields(&mut worker, WORK_EXCEPTION, &[&old_handle, b"stale"]).await;
+    assert_eq!(1, shared.queues.queued_count());
+    let assign = send(&mut worker, GRAB_JOB, b"").await;
+    assert_eq!(JOB_ASSIGN, assign.ptype);
+    assert!(assign.data.starts_with(&new_handle));
+}
+
+#[tokio::test]
+async fn scripted_scheduler_picks_who_is_woken() {
+    let mut shared = Shared::new();
+    shared.workers.set_scheduler(Arc::new(Scripted::new(vec![3, 2])));
+    let (mut first, mut first_rx) = shared.connect(2);
+    let (mut second, mut second_rx) = shared.connect(3);
+    for worker in [&mut first, &mut second].iter_mut() {
+        send(worker, CAN_DO, b"f").await;
+        send(worker, PRE_SLEEP, b"").await;
+    }
+    let (mut client, _client_rx) = shared.connect(1);
+    send(&mut client, SUBMIT_JOB_BG, b"f\0u1\0x").await;
+    let woken = timeout(Duration::from_secs(5), second_rx.recv()).await.unwrap().unwrap();
+    assert_eq!(NOOP, woken.ptype);
+    tokio::task::yield_now().await;
+    assert!(first_rx.try_recv().is_err(), "both workers woken for one job");
+    assert_eq!(JOB_ASSIGN, send(&mut second, GRAB_JOB, b"").await.ptype);
+    send(&mut client, SUBMIT_JOB_BG, b"f\0u2\0x").await;
+    let woken = timeout(Duration::from_secs(5), first_rx.recv()).await.unwrap().unwrap();
+    assert_eq!(NOOP, woken.ptype);
+}
+
+#[tokio::test]
+async fn worker_losing_grab_race_is_woken_again() {
+    let shared = Shared::new();
+    let (mut loser, mut loser_rx) = shared.connect(2);
+    let (mut winner, mut winner_rx) = shared.connect(3);
+    for worker in [&mut loser, &mut winner].iter_mut() {
+        send(worker, CAN_DO, b"f").await;
+        send(worker, PRE_SLEEP, b"").await;
+    }
+    let (mut client, _client_rx) = shared.connect(1);
+    send(&mut client, SUBMIT_JOB_BG, b"f\0u1\0x").await;
+    let woken = timeout(Duration::from_secs(5), loser_rx.recv()).await.unwrap().unwrap();
+    assert_eq!(NOOP, woken.ptype);
+    // The other worker polls and takes the job before the woken one asks
+    assert_eq!(JOB_ASSIGN, send(&mut winner, GRAB_JOB, b"").await.ptype);
+    assert_eq!(NO_JOB, send(&mut loser, GRAB_JOB, b"").await.ptype);
+    // Queued before the loser's PRE_SLEEP, while neither worker was asleep
+    send(&mut client, SUBMIT_JOB_BG, b"f\0u2\0x").await;
+    tokio::task::yield_now().await;
+    assert!(winner_rx.try_recv().is_err(), "busy worker woken");
+    assert_eq!(NOOP, send(&mut loser, PRE_SLEEP, b"").await.ptype);
+    assert_eq!(JOB_ASSIGN, send(&mut loser, GRAB_JOB, b"").await.ptype);
+}
+
+/// Remembers the events it is given as "<kind> <unique>"
+#[derive(Debug, Default)]
+struct RecordingReplicator {
+    events: Mutex<Vec<String>>,
+}
+
+impl Replicator for RecordingReplicator {
+    fn replicate(&self, event: ReplicationEvent) {
+        let event = match event {
+            ReplicationEvent::Submitted { job, priority } => {
+                format!("submitted {:?} {}", job.unique, priority)
+            }
+            ReplicationEvent::Completed(job) => format!("completed {:?}", job.unique),
+            ReplicationEvent::Failed(job) => format!("failed {:?}", job.unique),
+        };
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+#[tokio::test]
+async fn replicator_sees_submit_complete_and_fail() {
+    let shared = Shared::new();
+    let replicator = Arc::new(RecordingReplicator::default());
+    shared.queues.set_replicator(replicator.clone());
+    let (mut client, _client_rx) = shared.connect(1);
+    let (mut worker, _worker_rx) = shared.connect(2);
+    send(&mut worker, CAN_DO, b"f").await;
+    send(&mut client, SUBMIT_JOB_HIGH_BG, b"f\0u1\0x").await;
+    send(&mut client, SUBMIT_JOB_BG, b"f\0u2\0x").await;
+    let first = send(&mut worker, GRAB_JOB, b"").await;
+    send_fields(&mut worker, WORK_COMPLETE, &[first.fields()[0], b"done"]).await;
+    let second = send(&mut worker, GRAB_JOB, b"").await;
+    send(&mut worker, WORK_FAIL, second.fields()[0]).await;
+    assert_eq!(
+        vec![
+            "submitted b\"u1\" 0",
+            "submitted b\"u2\" 1",
+            "completed b\"u1\"",
+            "failed b\"u2\"",
+        ],
+        *replicator.events.lock().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn connection_info_reflects_negotiation() {
+    let shared = Shared::new();
+    let (mut worker, _worker_rx) = shared.connect(1);
+    shared
+        .workers_by_conn_id
+        .lock()
+        .unwrap()
+        .insert(1, worker.worker.clone());
+    send(&mut worker, CAN_DO, b"resize").await;
+    send(&mut worker, CAN_DO, b"crop").await;
+    send(&mut worker, SET_CLIENT_ID, b"thumbnailer").await;
+    send(&mut worker, OPTION_REQ, b"exceptions").await;
+    send(&mut worker, PRE_SLEEP, b"").await;
+    let info = worker.connection_info(1).unwrap();
+    assert_eq!(1, info.conn_id);
+    assert_eq!(Bytes::from("thumbnailer"), info.client_id);
+    assert_eq!(vec![Bytes::from("crop"), Bytes::from("resize")], info.functions);
+    assert_eq!(vec!["exceptions"], info.options);
+    assert!(info.sleeping);
+    assert_eq!(0, info.assigned);
+    assert!(worker.connection_info(2).is_none());
+}
@@ -0,0 +1,73 @@
+extern crate log;
+extern crate rustygeard;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::AtomicUsize;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use bytes::Bytes;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use tower_service::Service;
+
+use rustygear::constants::*;
+use rustygear::util::new_req;
+
+use rustygeard::queues::{HandleJobStorage, SharedJobStorage};
+use rustygeard::service::GearmanService;
+use rustygeard::worker::{SharedWorkers, Wake};
+
+/// Keeps every warning logged by this test binary
+struct Captured;
+
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+static LOGGER: Captured = Captured;
+
+impl Log for Captured {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            WARNINGS.lock().unwrap().push(format!("{}", record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[tokio::test]
+async fn slow_packet_is_logged() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Warn);
+    let queues = SharedJobStorage::new_job_storage();
+    let mut service = GearmanService::new(
+        7,
+        queues.clone(),
+        SharedWorkers::new_workers(),
+        Arc::new(AtomicUsize::new(0)),
+        Arc::new(Mutex::new(HashMap::new())),
+        Arc::new(Mutex::new(BTreeMap::new())),
+        Arc::new(Mutex::new(HashMap::new())),
+        "127.0.0.1:37337".parse().unwrap(),
+    );
+    service.slow_packet_threshold = Some(Duration::from_millis(50));
+    service.call(new_req(ECHO_REQ, Bytes::from("fast"))).await.unwrap();
+    assert!(WARNINGS.lock().unwrap().is_empty());
+    // Contention on the queues stalls the submission
+    let (locked_tx, locked_rx) = mpsc::channel();
+    let holder = thread::spawn(move || {
+        let _queues = queues.shard(&Bytes::from("f"));
+        locked_tx.send(()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+    });
+    locked_rx.recv().unwrap();
+    let submit = new_req(SUBMIT_JOB_BG, Bytes::from("f\0u\0x"));
+    assert_eq!(JOB_CREATED, service.call(submit).await.unwrap().ptype);
+    holder.join().unwrap();
+    let warnings = WARNINGS.lock().unwrap();
+    assert_eq!(1, warnings.len(), "{:?}", warnings);
+    assert!(warnings[0].starts_with("[conn=7] Slow packet: SUBMIT_JOB_BG"), "{}", warnings[0]);
+}
@@ -0,0 +1,20 @@
+extern crate rustygeard;
+
+use std::time::Duration;
+
+use rustygeard::stats::FunctionStats;
+
+#[test]
+fn runtime_average_and_percentile() {
+    let mut stats = FunctionStats::default();
+    assert_eq!(None, stats.avg_runtime());
+    for ms in 1..=100 {
+        stats.record_complete(Duration::from_millis(ms));
+    }
+    stats.record_failure();
+    assert_eq!(100, stats.completed);
+    assert_eq!(1, stats.failed);
+    assert_eq!(Some(Duration::from_micros(50500)), stats.avg_runtime());
+    assert_eq!(Some(Duration::from_millis(95)), stats.percentile_runtime(95.0));
+    assert_eq!(Some(Duration::from_millis(1)), stats.percentile_runtime(0.0));
+}
@@ -0,0 +1,63 @@
+extern crate bytes;
+extern crate rustygeard;
+
+use std::collections::HashSet;
+
+use bytes::Bytes;
+
+use rustygeard::worker::{SharedWorkers, Wake, Worker};
+
+fn sleeping_worker(workers: &mut SharedWorkers, conn_id: usize, fname: &'static str) {
+    let mut w = Worker::new("127.0.0.1:37337".parse().unwrap(), Bytes::from("-"));
+    w.can_do(Bytes::from(fname));
+    workers.sleep(&mut w, conn_id);
+}
+
+#[test]
+fn queue_wake_spreads_jobs() {
+    let mut workers = SharedWorkers::new_workers();
+    for conn_id in 1..4 {
+        sleeping_worker(&mut workers, conn_id, "f");
+    }
+    let fname = Bytes::from("f");
+    let mut woken = HashSet::new();
+    for _ in 0..3 {
+        let wake = workers.queue_wake(&fname);
+        assert_eq!(1, wake.len());
+        woken.extend(wake);
+    }
+    assert_eq!(3, woken.len());
+    assert!(workers.queue_wake(&fname).is_empty());
+}
+
+#[test]
+fn queue_wake_prefers_least_recently_assigned() {
+    let mut workers = SharedWorkers::new_workers();
+    workers.assigned(1);
+    workers.assigned(2);
+    sleeping_worker(&mut workers, 1, "f");
+    sleeping_worker(&mut workers, 2, "f");
+    assert_eq!(vec![1], workers.queue_wake(&Bytes::from("f")));
+}
+
+#[test]
+fn queue_wake_only_matching_function() {
+    let mut workers = SharedWorkers::new_workers();
+    sleeping_worker(&mut workers, 1, "foo");
+    sleeping_worker(&mut workers, 2, "bar");
+    assert_eq!(vec![1], workers.queue_wake(&Bytes::from("foo")));
+    assert!(workers.queue_wake(&Bytes::from("foo")).is_empty());
+    assert_eq!((0, 1), workers.count_workers(&Bytes::from("bar")));
+}
+
+#[test]
+fn functions_lists_registered_workers() {
+    let mut workers = SharedWorkers::new_workers();
+    sleeping_worker(&mut workers, 1, "f");
+    sleeping_worker(&mut workers, 2, "g");
+    let mut functions = workers.functions();
+    functions.sort();
+    assert_eq!(vec![b"f".to_vec(), b"g".to_vec()], functions);
+    workers.shutdown(2);
+    assert_eq!(vec![b"f".to_vec()], workers.functions());
+}
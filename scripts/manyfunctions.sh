@@ -0,0 +1,34 @@
+#!/usr/bin/env bash
+# Times concurrent submitters that each use their own function, to measure
+# how much the shared job storage lock serializes unrelated work.
+set -eux
+
+functions=${FUNCTIONS:-16}
+count=${JOBS:-2000}
+
+cleanup () {
+    if [[ -n "${outdir:-}" ]] ; then
+        rm -rf $outdir
+    fi
+    jobs -p | xargs -r kill
+}
+trap cleanup EXIT
+outdir=$(mktemp -d -t gearman.mf.XXXXXXXX)
+
+set +x
+for i in $(seq 0 $count) ; do echo "job $i" >> $outdir/jobs.txt ; done
+set -x
+for f in $(seq 1 $functions) ; do
+    gearman -w -c $((count + 1)) -f fn$f cat > /dev/null &
+done
+sleep 1
+start=$(date +%s.%N)
+for f in $(seq 1 $functions) ; do
+    gearman -b -n -f fn$f < $outdir/jobs.txt &
+done
+wait $(jobs -p)
+end=$(date +%s.%N)
+echo "$((functions * (count + 1))) jobs over $functions functions in $(echo "$end - $start" | bc)s"
+trap - EXIT
+cleanup
+echo OK!
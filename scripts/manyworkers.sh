@@ -0,0 +1,45 @@
+#!/usr/bin/env bash
+# Times dispatching a burst of background jobs to many workers of one
+# function. Set SERVER_PID to the running rustygeard to also count the
+# write syscalls it makes, which batching replies is meant to cut down.
+set -eux
+
+workers=${WORKERS:-64}
+count=${JOBS:-5000}
+
+cleanup () {
+    if [[ -n "${outdir:-}" ]] ; then
+        rm -rf $outdir
+    fi
+    jobs -p | xargs -r kill
+}
+trap cleanup EXIT
+outdir=$(mktemp -d -t gearman.mw.XXXXXXXX)
+
+set +x
+for i in $(seq 1 $count) ; do echo "job $i" >> $outdir/jobs.txt ; done
+set -x
+for w in $(seq 1 $workers) ; do
+    gearman -w -f burst cat > /dev/null &
+done
+sleep 1
+if [[ -n "${SERVER_PID:-}" ]] ; then
+    strace -f -c -e trace=write,writev,sendto -o $outdir/strace.txt -p $SERVER_PID &
+    strace_pid=$!
+    sleep 1
+fi
+start=$(date +%s.%N)
+gearman -b -n -f burst < $outdir/jobs.txt
+while [[ "$(echo status | nc -q1 localhost 4730 | awk '$1 == "burst" {print $2}')" != "0" ]] ; do
+    sleep 0.1
+done
+end=$(date +%s.%N)
+echo "$count jobs to $workers workers in $(echo "$end - $start" | bc)s"
+if [[ -n "${SERVER_PID:-}" ]] ; then
+    kill -INT $strace_pid
+    wait $strace_pid || true
+    cat $outdir/strace.txt
+fi
+trap - EXIT
+cleanup
+echo OK!